@@ -13,44 +13,131 @@ pub struct ROIFormat {
     pub img_type: i32,
 }
 
-fn check_error_code(code: i32) {
-    match code {
-        // Success
-        0 => (),
-        // No camera connected or index value out of boundary
-        1 => error!("ASI_ERROR_INVALID_INDEX"),
-        2 => error!("ASI_ERROR_INVALID_ID"),
-        3 => error!("ASI_ERROR_INVALID_CONTROL_TYPE"),
-        // Camera didn't open
-        4 => error!("ASI_ERROR_CAMERA_CLOSED"),
-        // Failed to find the camera, maybe the camera has been removed
-        5 => error!("ASI_ERROR_CAMERA_REMOVED"),
-        // Cannot find the path of the file
-        6 => error!("ASI_ERROR_INVALID_PATH"),
-        7 => error!("ASI_ERROR_INVALID_FILEFORMAT"),
-        // Wrong video format size
-        8 => error!("ASI_ERROR_INVALID_SIZE"),
-        9 => error!("ASI_ERROR_INVALID_IMGTYPE"), //unsupported image formate
-        10 => error!("ASI_ERROR_OUTOF_BOUNDARY"), //the startpos is out of boundary
-        // Communication timeout
-        11 => error!("ASI_ERROR_TIMEOUT"),
-        12 => error!("ASI_ERROR_INVALID_SEQUENCE"), //stop capture first!
-        13 => error!("ASI_ERROR_BUFFER_TOO_SMALL"), //buffer size is not big enough
-        14 => error!("ASI_ERROR_VIDEO_MODE_ACTIVE"),
-        15 => error!("ASI_ERROR_EXPOSURE_IN_PROGRESS"),
-        16 => error!("ASI_ERROR_GENERAL_ERROR"), //general error, eg: value is out of valid range
-        17 => error!("ASI_ERROR_INVALID_MODE"),  //the current mode is wrong
-        18 => error!("ASI_ERROR_END"),
-        e => error!("unknown error {}", e),
+/// Errors returned by the camera SDK, see `ASI_ERROR_CODE`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AsiError {
+    /// No camera connected or index value out of boundary
+    InvalidIndex,
+    InvalidId,
+    InvalidControlType,
+    /// Camera didn't open
+    CameraClosed,
+    /// Failed to find the camera, maybe the camera has been removed
+    CameraRemoved,
+    /// Cannot find the path of the file
+    InvalidPath,
+    InvalidFileFormat,
+    /// Wrong video format size
+    InvalidSize,
+    /// Unsupported image format
+    InvalidImgType,
+    /// The start position is out of boundary
+    OutOfBoundary,
+    /// Communication timeout
+    Timeout,
+    /// Stop capture first
+    InvalidSequence,
+    /// Buffer size is not big enough
+    BufferTooSmall,
+    VideoModeActive,
+    ExposureInProgress,
+    /// General error, eg: value is out of valid range
+    GeneralError,
+    /// The current mode is wrong
+    InvalidMode,
+    Unknown(i32),
+}
+
+impl AsiError {
+    /// Map a code returned by the SDK, `None` means success
+    pub fn from_code(code: i32) -> Option<Self> {
+        let e = match code {
+            0 => return None,
+            1 => AsiError::InvalidIndex,
+            2 => AsiError::InvalidId,
+            3 => AsiError::InvalidControlType,
+            4 => AsiError::CameraClosed,
+            5 => AsiError::CameraRemoved,
+            6 => AsiError::InvalidPath,
+            7 => AsiError::InvalidFileFormat,
+            8 => AsiError::InvalidSize,
+            9 => AsiError::InvalidImgType,
+            10 => AsiError::OutOfBoundary,
+            11 => AsiError::Timeout,
+            12 => AsiError::InvalidSequence,
+            13 => AsiError::BufferTooSmall,
+            14 => AsiError::VideoModeActive,
+            15 => AsiError::ExposureInProgress,
+            16 => AsiError::GeneralError,
+            17 => AsiError::InvalidMode,
+            e => AsiError::Unknown(e),
+        };
+        Some(e)
+    }
+
+    /// Name of the error as defined in the SDK header
+    pub fn name(&self) -> &'static str {
+        match self {
+            AsiError::InvalidIndex => "ASI_ERROR_INVALID_INDEX",
+            AsiError::InvalidId => "ASI_ERROR_INVALID_ID",
+            AsiError::InvalidControlType => "ASI_ERROR_INVALID_CONTROL_TYPE",
+            AsiError::CameraClosed => "ASI_ERROR_CAMERA_CLOSED",
+            AsiError::CameraRemoved => "ASI_ERROR_CAMERA_REMOVED",
+            AsiError::InvalidPath => "ASI_ERROR_INVALID_PATH",
+            AsiError::InvalidFileFormat => "ASI_ERROR_INVALID_FILEFORMAT",
+            AsiError::InvalidSize => "ASI_ERROR_INVALID_SIZE",
+            AsiError::InvalidImgType => "ASI_ERROR_INVALID_IMGTYPE",
+            AsiError::OutOfBoundary => "ASI_ERROR_OUTOF_BOUNDARY",
+            AsiError::Timeout => "ASI_ERROR_TIMEOUT",
+            AsiError::InvalidSequence => "ASI_ERROR_INVALID_SEQUENCE",
+            AsiError::BufferTooSmall => "ASI_ERROR_BUFFER_TOO_SMALL",
+            AsiError::VideoModeActive => "ASI_ERROR_VIDEO_MODE_ACTIVE",
+            AsiError::ExposureInProgress => "ASI_ERROR_EXPOSURE_IN_PROGRESS",
+            AsiError::GeneralError => "ASI_ERROR_GENERAL_ERROR",
+            AsiError::InvalidMode => "ASI_ERROR_INVALID_MODE",
+            AsiError::Unknown(_) => "UNKNOWN_ERROR",
+        }
+    }
+}
+
+impl std::fmt::Display for AsiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AsiError::Unknown(code) => write!(f, "unknown error {}", code),
+            e => f.write_str(e.name()),
+        }
+    }
+}
+
+impl std::error::Error for AsiError {}
+
+/// Turn a code returned by the SDK into a `Result`, errors are logged
+fn to_result(code: i32) -> Result<(), AsiError> {
+    match AsiError::from_code(code) {
+        None => Ok(()),
+        Some(e) => {
+            error!("{}", e);
+            Err(e)
+        }
     }
 }
 
-pub fn start_exposure(camera_id: i32) {
-    check_error_code(unsafe { libasi_sys::camera::ASIStartExposure(camera_id, 0) });
+fn check_error_code(code: i32) {
+    let _ = to_result(code);
+}
+
+pub fn start_exposure(camera_id: i32) -> Result<(), AsiError> {
+    to_result(unsafe { libasi_sys::camera::ASIStartExposure(camera_id, 0) })
+}
+
+/// Start a dark frame, cameras with a mechanical shutter keep it closed for
+/// the whole exposure; the others behave like `start_exposure`.
+pub fn start_dark_exposure(camera_id: i32) -> Result<(), AsiError> {
+    to_result(unsafe { libasi_sys::camera::ASIStartExposure(camera_id, 1) })
 }
 
-pub fn stop_exposure(camera_id: i32) {
-    check_error_code(unsafe { libasi_sys::camera::ASIStopExposure(camera_id) });
+pub fn stop_exposure(camera_id: i32) -> Result<(), AsiError> {
+    to_result(unsafe { libasi_sys::camera::ASIStopExposure(camera_id) })
 }
 
 #[cfg(windows)]
@@ -64,13 +151,53 @@ pub fn exposure_status(camera_id: i32, status: *mut u32) {
 }
 
 #[cfg(windows)]
-pub fn download_exposure(camera_id: i32, buffer: *mut u8, buf_size: i32) {
-    check_error_code(unsafe { libasi_sys::camera::ASIGetDataAfterExp(camera_id, buffer, buf_size) });
+pub fn download_exposure(camera_id: i32, buffer: *mut u8, buf_size: i32) -> Result<(), AsiError> {
+    to_result(unsafe { libasi_sys::camera::ASIGetDataAfterExp(camera_id, buffer, buf_size) })
 }
 
 #[cfg(unix)]
-pub fn download_exposure(camera_id: i32, buffer: *mut u8, buf_size: i64) {
-    check_error_code(unsafe { libasi_sys::camera::ASIGetDataAfterExp(camera_id, buffer, buf_size) });
+pub fn download_exposure(camera_id: i32, buffer: *mut u8, buf_size: i64) -> Result<(), AsiError> {
+    to_result(unsafe { libasi_sys::camera::ASIGetDataAfterExp(camera_id, buffer, buf_size) })
+}
+
+pub fn start_video_capture(camera_id: i32) -> Result<(), AsiError> {
+    to_result(unsafe { libasi_sys::camera::ASIStartVideoCapture(camera_id) })
+}
+
+pub fn stop_video_capture(camera_id: i32) -> Result<(), AsiError> {
+    to_result(unsafe { libasi_sys::camera::ASIStopVideoCapture(camera_id) })
+}
+
+/// Fetch the next frame of an ongoing video capture, waiting at most `wait_ms`
+/// milliseconds. Returns `false` if no frame was available in time.
+#[cfg(windows)]
+pub fn get_video_data(camera_id: i32, buffer: *mut u8, buf_size: i32, wait_ms: i32) -> bool {
+    let code = unsafe { libasi_sys::camera::ASIGetVideoData(camera_id, buffer, buf_size, wait_ms) };
+    // A timeout is expected when the exposure is longer than the wait, don't log it
+    if code != 11 {
+        check_error_code(code);
+    }
+    code == 0
+}
+
+/// Fetch the next frame of an ongoing video capture, waiting at most `wait_ms`
+/// milliseconds. Returns `false` if no frame was available in time.
+#[cfg(unix)]
+pub fn get_video_data(camera_id: i32, buffer: *mut u8, buf_size: i64, wait_ms: i32) -> bool {
+    let code = unsafe { libasi_sys::camera::ASIGetVideoData(camera_id, buffer, buf_size, wait_ms) };
+    // A timeout is expected when the exposure is longer than the wait, don't log it
+    if code != 11 {
+        check_error_code(code);
+    }
+    code == 0
+}
+
+/// Number of frames dropped since the video capture started, because the
+/// buffer was full when they arrived
+pub fn get_dropped_frames(camera_id: i32) -> Result<i32, AsiError> {
+    let mut dropped = 0;
+    to_result(unsafe { libasi_sys::camera::ASIGetDroppedFrames(camera_id, &mut dropped) })?;
+    Ok(dropped)
 }
 
 pub fn get_num_of_connected_cameras() -> i32 {
@@ -81,6 +208,17 @@ pub fn get_cam_id(camera_id: i32, asi_id: *mut AsiID) {
     check_error_code(unsafe { libasi_sys::camera::ASIGetID(camera_id, asi_id) });
 }
 
+/// Serial number of the camera, only USB3 cameras have one
+pub fn get_serial_number(camera_id: i32) -> Result<AsiID, AsiError> {
+    let mut sn = AsiID::new();
+    let code = unsafe { libasi_sys::camera::ASIGetSerialNumber(camera_id, &mut sn) };
+    // Not logged, most of the cameras don't support it
+    match AsiError::from_code(code) {
+        None => Ok(sn),
+        Some(e) => Err(e),
+    }
+}
+
 pub fn set_cam_id(camera_id: i32, asi_id: AsiID) {
     check_error_code(unsafe { libasi_sys::camera::ASISetID(camera_id, asi_id) });
 }
@@ -134,17 +272,17 @@ pub fn get_control_value(
 }
 
 #[cfg(windows)]
-pub fn set_control_value(camera_index: i32, control_type: i32, value: i32, is_auto_set: i32) {
-    check_error_code(unsafe {
+pub fn set_control_value(camera_index: i32, control_type: i32, value: i32, is_auto_set: i32) -> Result<(), AsiError> {
+    to_result(unsafe {
         libasi_sys::camera::ASISetControlValue(camera_index, control_type, value, is_auto_set)
-    });
+    })
 }
 
 #[cfg(unix)]
-pub fn set_control_value(camera_index: i32, control_type: i32, value: ::std::os::raw::c_long, is_auto_set: i32) {
-    check_error_code(unsafe {
+pub fn set_control_value(camera_index: i32, control_type: i32, value: ::std::os::raw::c_long, is_auto_set: i32) -> Result<(), AsiError> {
+    to_result(unsafe {
         libasi_sys::camera::ASISetControlValue(camera_index, control_type, value, is_auto_set)
-    });
+    })
 }
 
 pub fn get_roi_format(
@@ -165,10 +303,10 @@ pub fn set_roi_format(
     height: i32,
     bin: i32,
     img_type: i32,
-) {
-    check_error_code(unsafe {
+) -> Result<(), AsiError> {
+    to_result(unsafe {
         libasi_sys::camera::ASISetROIFormat(camera_id, width, height, bin, img_type)
-    });
+    })
 }
 
 pub fn get_start_position(cam_idx: i32, start_x: &mut i32, start_y: &mut i32) {
@@ -177,6 +315,12 @@ pub fn get_start_position(cam_idx: i32, start_x: &mut i32, start_y: &mut i32) {
     });
 }
 
+pub fn set_start_position(cam_idx: i32, start_x: i32, start_y: i32) -> Result<(), AsiError> {
+    to_result(unsafe {
+        libasi_sys::camera::ASISetStartPos(cam_idx, start_x, start_y)
+    })
+}
+
 pub fn get_camera_mode(cam_idx: i32, camera_mode: &mut i32) {
     check_error_code(unsafe {
         libasi_sys::camera::ASIGetCameraMode(cam_idx, camera_mode)
@@ -4,25 +4,85 @@ use log::error;
 pub type EFWInfo = _EFW_INFO;
 pub type EFWId = _EFW_ID;
 
-fn check_error_code(code: i32) {
-    match code {
-	0 => (),
-	1 => error!("EFW_ERROR_INVALID_INDEX"),
-	2 => error!("EFW_ERROR_INVALID_ID"),
-	3 => error!("EFW_ERROR_INVALID_VALUE"),
-	// Failed to find the filter wheel, maybe the filter wheel has been removed
-	4 => error!("EFW_ERROR_REMOVED"),
-	// Filter wheel is moving
-	5 => error!("EFW_ERROR_MOVING"),
-	6 => error!("EFW_ERROR_ERROR_STATE"),
-	7 => error!("EFW_ERROR_GENERAL_ERROR"),
-	8 => error!("EFW_ERROR_NOT_SUPPORTED"),
-	9 => error!("EFW_ERROR_CLOSED"),
-	-1 => error!("EFW_ERROR_END"),
-	_ => error!("UNKNOWN_ERROR"),
+/// Errors returned by the filter wheel SDK, see `EFW_ERROR_CODE`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EfwError {
+    InvalidIndex,
+    InvalidId,
+    InvalidValue,
+    /// Failed to find the filter wheel, maybe the filter wheel has been removed
+    Removed,
+    /// Filter wheel is moving
+    Moving,
+    ErrorState,
+    GeneralError,
+    NotSupported,
+    Closed,
+    Unknown(i32),
+}
+
+impl EfwError {
+    /// Map a code returned by the SDK, `None` means success
+    pub fn from_code(code: i32) -> Option<Self> {
+        let e = match code {
+            0 => return None,
+            1 => EfwError::InvalidIndex,
+            2 => EfwError::InvalidId,
+            3 => EfwError::InvalidValue,
+            4 => EfwError::Removed,
+            5 => EfwError::Moving,
+            6 => EfwError::ErrorState,
+            7 => EfwError::GeneralError,
+            8 => EfwError::NotSupported,
+            9 => EfwError::Closed,
+            e => EfwError::Unknown(e),
+        };
+        Some(e)
+    }
+
+    /// Name of the error as defined in the SDK header
+    pub fn name(&self) -> &'static str {
+        match self {
+            EfwError::InvalidIndex => "EFW_ERROR_INVALID_INDEX",
+            EfwError::InvalidId => "EFW_ERROR_INVALID_ID",
+            EfwError::InvalidValue => "EFW_ERROR_INVALID_VALUE",
+            EfwError::Removed => "EFW_ERROR_REMOVED",
+            EfwError::Moving => "EFW_ERROR_MOVING",
+            EfwError::ErrorState => "EFW_ERROR_ERROR_STATE",
+            EfwError::GeneralError => "EFW_ERROR_GENERAL_ERROR",
+            EfwError::NotSupported => "EFW_ERROR_NOT_SUPPORTED",
+            EfwError::Closed => "EFW_ERROR_CLOSED",
+            EfwError::Unknown(_) => "UNKNOWN_ERROR",
+        }
+    }
+}
+
+impl std::fmt::Display for EfwError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EfwError::Unknown(code) => write!(f, "unknown error {}", code),
+            e => f.write_str(e.name()),
+        }
+    }
+}
+
+impl std::error::Error for EfwError {}
+
+/// Turn a code returned by the SDK into a `Result`, errors are logged
+fn to_result(code: i32) -> Result<(), EfwError> {
+    match EfwError::from_code(code) {
+        None => Ok(()),
+        Some(e) => {
+            error!("{}", e);
+            Err(e)
+        }
     }
 }
 
+fn check_error_code(code: i32) {
+    let _ = to_result(code);
+}
+
 pub fn get_num_of_connected_devices() -> i32 {
     unsafe { libasi_sys::efw::EFWGetNum() }
 }
@@ -78,19 +138,19 @@ pub fn get_efw_position(id: i32) -> i32 {
     position + 1
 }
 
-pub fn set_efw_position(id: i32, position: i32) {
+pub fn set_efw_position(id: i32, position: i32) -> Result<(), EfwError> {
     // To have users dealing with non 0 indexed values, we simply subtract always 1 to
     // the 0 indexed position wanted by the user
     let indexed_0_position = position -1;
-    check_error_code(
+    to_result(
 	unsafe { libasi_sys::efw::EFWSetPosition(id, indexed_0_position) }
-    );
+    )
 }
 
-pub fn set_unidirection(id: i32, flag: bool) {
-    check_error_code(
+pub fn set_unidirection(id: i32, flag: bool) -> Result<(), EfwError> {
+    to_result(
 	unsafe { EFWSetDirection(id, flag) }
-    );
+    )
 }
 
 pub fn is_unidirectional(id: i32) -> bool {
@@ -101,10 +161,10 @@ pub fn is_unidirectional(id: i32) -> bool {
     unid
 }
 
-pub fn calibrate_wheel(id: i32) {
-    check_error_code(
+pub fn calibrate_wheel(id: i32) -> Result<(), EfwError> {
+    to_result(
 	unsafe { EFWCalibrate(id) }
-    );
+    )
 }
 
 pub fn close_efw(id: i32) {
@@ -150,6 +210,6 @@ pub fn get_serial_number(id: i32) -> EFWId {
 }
 
 /// Writes an 8-byte alias ID to the EFW device flash.
-pub fn set_id(id: i32, alias: EFWId) {
-    check_error_code(unsafe { libasi_sys::efw::EFWSetID(id, alias) });
+pub fn set_id(id: i32, alias: EFWId) -> Result<(), EfwError> {
+    to_result(unsafe { libasi_sys::efw::EFWSetID(id, alias) })
 }
@@ -0,0 +1,806 @@
+use crate::ccd::utils::capturing;
+use crate::ccd::AsiCamera;
+use crate::metadata::{Metadata, MetadataUpdate, METADATA_ACTION};
+use crate::output::{FrameStats, RecordingSummary, SavedFrame};
+use crate::request::{
+    ExposureRequest, FrameType, RecordRequest, UpdateRequest, VideoCapture, VideoRequest,
+};
+
+use asi_rs::config::{
+    Config, FileFormat, OutputConfig, PreviewConfig, StarsConfig, StatsConfig, TransferConfig,
+};
+use asi_rs::reply::{ErrorCode, Reply, ReplyError};
+use asi_rs::ser::SerWriter;
+use asi_rs::stars::{self, StarSummary};
+use asi_rs::state::{StateTracker, StateUpdate};
+use asi_rs::topics::TopicRouter;
+use asi_rs::transfer::{self, AckTracker, Frame, FrameStore};
+use asi_rs::{export, fits, stats, xisf};
+use log::{debug, error, info, warn};
+use rumqttc::AsyncClient;
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::runtime;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+/// How long a single `ASIGetVideoData` call may block before the actor gets
+/// a chance to look at its command queue again.
+const VIDEO_WAIT_MS: i32 = 200;
+
+/// Commands understood by a camera actor, they are processed strictly in the
+/// order they are received.
+#[derive(Debug)]
+pub enum CameraCommand {
+    /// Refresh the properties from the device and publish the new state
+    Poll,
+    /// Publish the full state, even if nothing changed
+    Snapshot,
+    /// Apply the properties of `devices/{id}/update`
+    Update(UpdateRequest),
+    Expose(ExposureRequest),
+    /// Abort the ongoing exposure, there is nothing to do if the camera is
+    /// idle
+    Abort(Option<String>),
+    /// Start or stop the video capture
+    Video(VideoRequest),
+    /// Record the video frames to a SER file, the video capture is started
+    /// if needed
+    Record(RecordRequest),
+    /// Stop the ongoing recording, if any
+    StopRecording(Option<String>),
+    /// Change the values written in the headers of the saved frames
+    Metadata(MetadataUpdate),
+    /// Close the camera and stop the actor thread
+    Shutdown,
+}
+
+/// Events emitted by a camera actor, the MQTT layer is responsible to
+/// publish them.
+#[derive(Debug)]
+pub enum CameraEvent {
+    /// Serialized full state of the camera
+    State(String),
+    /// Serialized properties that changed since the last publication
+    Changes(String),
+    /// A frame has been written to the output directory
+    Saved(SavedFrame),
+    /// JPEG preview of the last exposure
+    Preview(Vec<u8>),
+    /// Statistics of the last exposure
+    Stats(FrameStats),
+    /// A recording is over
+    Recorded(RecordingSummary),
+    /// Outcome of a command, to be sent back to the client
+    Reply(Reply),
+}
+
+/// Handle used to talk to a camera owned by a dedicated OS thread
+pub struct CameraHandle {
+    pub id: Uuid,
+    /// The last frames sent by the camera
+    pub frames: FrameStore,
+    /// JPEG preview of the last video frame, only the latest one is kept so
+    /// that a slow connection skips frames instead of queueing them
+    pub video: watch::Receiver<Vec<u8>>,
+    tx: Sender<CameraCommand>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CameraHandle {
+    /// Move `camera` into its own thread and start processing commands.
+    /// Every event produced by the actor is sent to `events` together with
+    /// the id of the camera, the frames are published with `client` and their
+    /// acknowledgments followed by `acks`. Must be
+    /// called from within the tokio runtime.
+    pub fn spawn(
+        camera: AsiCamera,
+        config: &Config,
+        client: AsyncClient,
+        acks: AckTracker,
+        events: UnboundedSender<(Uuid, CameraEvent)>,
+    ) -> Self {
+        let id = camera.id;
+        let (tx, rx) = mpsc::channel();
+        let name = format!("asi-ccd-{}", camera.name);
+        let frames = FrameStore::new(config.transfer.keep_frames);
+        let actor = CameraActor::new(camera, config, client, acks, frames.clone(), rx, events);
+        let video = actor.video_previews.subscribe();
+
+        let thread = std::thread::Builder::new()
+            .name(name)
+            .spawn(move || actor.run())
+            .expect("Unable to spawn the camera thread");
+
+        Self {
+            id,
+            frames,
+            video,
+            tx,
+            thread: Some(thread),
+        }
+    }
+
+    /// A sender that can be moved to other tasks to queue commands
+    pub fn commands(&self) -> Sender<CameraCommand> {
+        self.tx.clone()
+    }
+
+    pub fn send(&self, command: CameraCommand) {
+        if self.tx.send(command).is_err() {
+            error!("Camera {} is not running anymore", self.id);
+        }
+    }
+
+    /// Ask the actor to close the camera and wait for its thread to finish
+    pub fn shutdown(&mut self) {
+        self.send(CameraCommand::Shutdown);
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            error!("Camera {} thread panicked", self.id);
+        }
+    }
+}
+
+/// A video recording in progress
+struct Recording {
+    request_id: Option<String>,
+    writer: SerWriter,
+    path: PathBuf,
+    max_frames: Option<u32>,
+    max_duration: Option<Duration>,
+    started: Instant,
+    /// Whether the video capture was started for the recording, it is then
+    /// stopped along with it
+    owns_video: bool,
+    /// Frames the SDK had already dropped when the recording started
+    dropped_at_start: i32,
+}
+
+impl Recording {
+    fn is_over(&self) -> bool {
+        self.max_frames.is_some_and(|n| self.writer.frames() >= n)
+            || self
+                .max_duration
+                .is_some_and(|d| self.started.elapsed() >= d)
+    }
+}
+
+struct CameraActor {
+    camera: AsiCamera,
+    /// The connection of the daemon, used to publish the frames
+    client: AsyncClient,
+    acks: AckTracker,
+    router: TopicRouter,
+    /// Runs the publication of the frames from the actor thread
+    runtime: runtime::Handle,
+    transfer: TransferConfig,
+    output: OutputConfig,
+    preview: PreviewConfig,
+    stats: StatsConfig,
+    stars: StarsConfig,
+    metadata: Metadata,
+    frames: FrameStore,
+    rx: Receiver<CameraCommand>,
+    events: UnboundedSender<(Uuid, CameraEvent)>,
+    /// Commands received while the camera was busy with an exposure
+    pending: VecDeque<CameraCommand>,
+    video: Option<Vec<u8>>,
+    video_previews: watch::Sender<Vec<u8>>,
+    /// When the last preview of the video capture was built
+    last_video_preview: Option<Instant>,
+    recording: Option<Recording>,
+    state: StateTracker,
+}
+
+impl CameraActor {
+    fn new(
+        camera: AsiCamera,
+        config: &Config,
+        client: AsyncClient,
+        acks: AckTracker,
+        frames: FrameStore,
+        rx: Receiver<CameraCommand>,
+        events: UnboundedSender<(Uuid, CameraEvent)>,
+    ) -> Self {
+        Self {
+            camera,
+            client,
+            acks,
+            router: config.mqtt.router(),
+            runtime: runtime::Handle::current(),
+            transfer: config.transfer.clone(),
+            output: config.output.clone(),
+            preview: config.preview.clone(),
+            stats: config.stats.clone(),
+            stars: config.stars.clone(),
+            metadata: Metadata::from(&config.observatory),
+            frames,
+            rx,
+            events,
+            pending: VecDeque::new(),
+            video: None,
+            video_previews: watch::Sender::new(Vec::new()),
+            last_video_preview: None,
+            recording: None,
+            state: StateTracker::new(config.snapshot_interval()),
+        }
+    }
+
+    fn run(mut self) {
+        info!("Camera actor for {} started", self.camera.name);
+
+        loop {
+            let command = match self.next_command() {
+                Some(c) => c,
+                None => {
+                    self.pull_video_frame();
+                    continue;
+                }
+            };
+            debug!("Camera {} processing {:?}", self.camera.id, command);
+
+            match command {
+                CameraCommand::Poll => {
+                    // Polling is suspended while streaming, the SDK is busy
+                    // delivering frames and the state is not changing anyway.
+                    // Polls received during an exposure are dropped as well.
+                    if self.video.is_none() {
+                        self.camera.poll_props();
+                        self.publish_state();
+                    }
+                }
+                CameraCommand::Snapshot => self.publish_snapshot(),
+                CameraCommand::Update(request) => {
+                    // The video buffer is sized on the current format
+                    let checked = if self.video.is_some() && request.changes_format() {
+                        Err(ReplyError::new(
+                            ErrorCode::Busy,
+                            "can't change the format while video capture is running",
+                        ))
+                    } else {
+                        self.camera.check_update(&request)
+                    };
+
+                    if let Err(e) = checked {
+                        warn!("Update rejected for {}: {}", self.camera.name, e);
+                        self.reply(Reply::rejected("update", request.request_id, e));
+                        continue;
+                    }
+
+                    self.reply(Reply::accepted("update", request.request_id.clone()));
+                    let result = self.camera.apply_update(&request).map_err(ReplyError::from);
+                    self.reply(Reply::outcome("update", request.request_id, result));
+                    self.publish_state();
+                }
+                CameraCommand::Expose(request) => {
+                    let checked = if self.video.is_some() {
+                        warn!("Can't expose while video capture is running");
+                        Err(ReplyError::new(ErrorCode::Busy, "video capture is running"))
+                    } else {
+                        self.camera.check_exposure(&request)
+                    };
+
+                    if let Err(e) = checked {
+                        self.reply(Reply::rejected("expose", request.request_id, e));
+                        continue;
+                    }
+
+                    self.reply(Reply::accepted("expose", request.request_id.clone()));
+                    let result = match self.camera.prepare_exposure(&request) {
+                        Ok(()) => self.expose(&request),
+                        Err(e) => Err(e.into()),
+                    };
+                    self.reply(Reply::outcome("expose", request.request_id, result));
+                    self.publish_state();
+                }
+                CameraCommand::Abort(request_id) => {
+                    // Aborts during an exposure are handled by `expose`
+                    debug!("No exposure to abort");
+                    self.reply(Reply::accepted("abort", request_id.clone()));
+                    self.reply(Reply::completed("abort", request_id));
+                }
+                CameraCommand::Video(request) => {
+                    self.reply(Reply::accepted("video", request.request_id.clone()));
+                    let result = match request.capture {
+                        VideoCapture::Start if self.video.is_some() => Ok(()),
+                        VideoCapture::Start => self.start_video(),
+                        VideoCapture::Stop => {
+                            self.finish_recording(Ok(()));
+                            self.stop_video()
+                        }
+                    };
+                    if let Err(e) = &result {
+                        error!("Video request failed for {}: {}", self.camera.name, e);
+                    }
+                    self.reply(Reply::outcome("video", request.request_id, result));
+                    self.publish_state();
+                }
+                CameraCommand::Record(request) => {
+                    let checked = if self.recording.is_some() {
+                        Err(ReplyError::new(ErrorCode::Busy, "a recording is running"))
+                    } else {
+                        request.check()
+                    };
+
+                    if let Err(e) = checked {
+                        self.reply(Reply::rejected("record", request.request_id, e));
+                        continue;
+                    }
+
+                    self.reply(Reply::accepted("record", request.request_id.clone()));
+                    if let Err(e) = self.start_recording(&request) {
+                        error!("Unable to record from {}: {}", self.camera.name, e);
+                        self.reply(Reply::failed("record", request.request_id, e));
+                    }
+                    self.publish_state();
+                }
+                CameraCommand::StopRecording(request_id) => {
+                    // Stopping twice is not an error, the recording may as
+                    // well have reached its limit in the meantime
+                    self.finish_recording(Ok(()));
+                    self.reply(Reply::completed("stop_recording", request_id));
+                }
+                CameraCommand::Metadata(update) => {
+                    let request_id = update.request_id.clone();
+                    self.metadata.apply(update);
+                    self.reply(Reply::completed(METADATA_ACTION, request_id));
+                }
+                CameraCommand::Shutdown => {
+                    self.finish_recording(Err(ReplyError::new(
+                        ErrorCode::Aborted,
+                        "the camera has been closed",
+                    )));
+                    if self.video.take().is_some() {
+                        let _ = self.camera.stop_video();
+                    }
+                    self.camera.close();
+                    break;
+                }
+            }
+        }
+
+        info!("Camera actor for {} stopped", self.camera.name);
+    }
+
+    /// Return the next command to process. While a video capture is running this
+    /// never blocks, so that the caller can keep pulling frames; otherwise it
+    /// waits until a command arrives.
+    fn next_command(&mut self) -> Option<CameraCommand> {
+        if let Some(c) = self.pending.pop_front() {
+            return Some(c);
+        }
+
+        if self.video.is_some() {
+            return match self.rx.try_recv() {
+                Ok(c) => Some(c),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => Some(CameraCommand::Shutdown),
+            };
+        }
+
+        // All the senders are gone, nobody can talk to this camera anymore
+        Some(self.rx.recv().unwrap_or(CameraCommand::Shutdown))
+    }
+
+    fn expose(&mut self, request: &ExposureRequest) -> Result<(), ReplyError> {
+        let rx = &self.rx;
+        let pending = &mut self.pending;
+        let started = SystemTime::now();
+        // Request id of the abort that stopped the exposure, if any
+        let mut abort = None;
+
+        let result = capturing::expose(request, &mut self.camera, || {
+            // Drain whatever arrived during the exposure: an abort or a shutdown
+            // stops it, polls are dropped as the camera can't be read during
+            // readout, everything else is kept and processed in order once the
+            // exposure is over
+            loop {
+                match rx.try_recv() {
+                    Ok(CameraCommand::Abort(request_id)) => {
+                        abort = Some(request_id);
+                        return true;
+                    }
+                    Ok(CameraCommand::Shutdown) => {
+                        pending.push_front(CameraCommand::Shutdown);
+                        return true;
+                    }
+                    Ok(CameraCommand::Poll) => continue,
+                    Ok(c) => pending.push_back(c),
+                    Err(TryRecvError::Empty) => return false,
+                    Err(TryRecvError::Disconnected) => {
+                        pending.push_back(CameraCommand::Shutdown);
+                        return true;
+                    }
+                }
+            }
+        });
+        info!("Task ended");
+        if let Some(request_id) = abort {
+            self.reply(Reply::accepted("abort", request_id.clone()));
+            let stopped = match &result {
+                Err(e) if e.code != ErrorCode::Aborted => Err(e.clone()),
+                _ => Ok(()),
+            };
+            self.reply(Reply::outcome("abort", request_id, stopped));
+        }
+        let data = result?;
+        let mut metadata = self.camera.frame_metadata(request);
+        if self.stars.enabled
+            && request.frame_type == FrameType::Light
+            && let Some(summary) = self.measure_stars(&data)
+        {
+            metadata.insert("stars".to_string(), serde_json::json!(summary));
+        }
+        let frame = Arc::new(Frame::new(
+            data,
+            self.transfer.chunk_size,
+            request.request_id.clone(),
+            metadata,
+        ));
+
+        // The frame is still sent if it couldn't be saved, the client may
+        // have no access to the disk anyway
+        let saved = if request.save.unwrap_or(self.output.save) {
+            self.save_frame(request, &frame, started)
+        } else {
+            Ok(())
+        };
+        self.send_frame(&frame)?;
+        if self.preview.enabled {
+            self.send_preview(&frame);
+        }
+        if self.stats.enabled {
+            self.send_stats(&frame);
+        }
+        saved
+    }
+
+    /// Star count and median measures of a light frame, failing to measure
+    /// them doesn't fail the exposure
+    fn measure_stars(&self, data: &[u8]) -> Option<StarSummary> {
+        let image = self.camera.image(data)?;
+        let started = Instant::now();
+
+        match stars::detect(&image, self.camera.adc_bits(), self.stars.threshold) {
+            Ok(stars) => {
+                debug!(
+                    "Found {} stars on the frame of {} in {:?}",
+                    stars.len(),
+                    self.camera.name,
+                    started.elapsed()
+                );
+                Some(StarSummary::new(&stars))
+            }
+            Err(e) => {
+                error!("Unable to measure the stars of {}: {}", self.camera.name, e);
+                None
+            }
+        }
+    }
+
+    /// Publish the statistics of `frame`, failing to compute them doesn't
+    /// fail the exposure
+    fn send_stats(&self, frame: &Frame) {
+        let Some(image) = self.camera.image(frame.data()) else {
+            return;
+        };
+
+        match stats::compute(&image, self.camera.adc_bits(), self.stats.histogram_bins) {
+            Ok(channels) => self.emit(CameraEvent::Stats(FrameStats {
+                request_id: frame.header.request_id.clone(),
+                transfer_id: frame.header.transfer_id,
+                channels,
+            })),
+            Err(e) => error!(
+                "Unable to compute the statistics of {}: {}",
+                self.camera.name, e
+            ),
+        }
+    }
+
+    /// Publish a small stretched version of `frame`, failing to do so doesn't
+    /// fail the exposure
+    fn send_preview(&self, frame: &Frame) {
+        let Some(image) = self.camera.image(frame.data()) else {
+            return;
+        };
+
+        match self.build_preview(&image) {
+            Ok(jpeg) => self.emit(CameraEvent::Preview(jpeg)),
+            Err(e) => error!("Unable to build the preview of {}: {}", self.camera.name, e),
+        }
+    }
+
+    /// Publish the last video frame as a preview, at most every
+    /// `preview.video_interval_ms`: the raw frames are far too large and too
+    /// frequent for the connection
+    fn send_video_preview(&mut self) {
+        let due = self
+            .last_video_preview
+            .is_none_or(|t| t.elapsed() >= self.preview.video_interval());
+        if !self.preview.enabled || !due {
+            return;
+        }
+        self.last_video_preview = Some(Instant::now());
+
+        let Some(image) = self.video.as_deref().and_then(|d| self.camera.image(d)) else {
+            return;
+        };
+        match self.build_preview(&image) {
+            Ok(jpeg) => {
+                self.video_previews.send_replace(jpeg);
+            }
+            Err(e) => error!(
+                "Unable to build the video preview of {}: {}",
+                self.camera.name, e
+            ),
+        }
+    }
+
+    fn build_preview(&self, image: &fits::Image) -> io::Result<Vec<u8>> {
+        let preview = &self.preview;
+        export::preview(
+            image,
+            preview.max_size,
+            preview.quality,
+            preview.debayer,
+            &preview.auto_stretch(),
+        )
+    }
+
+    /// Write `frame` to the output directory, in the format asked by
+    /// `request`, and announce it
+    fn save_frame(
+        &self,
+        request: &ExposureRequest,
+        frame: &Frame,
+        started: SystemTime,
+    ) -> Result<(), ReplyError> {
+        let format = request.format.unwrap_or(self.output.format);
+        let image = self.camera.image(frame.data()).ok_or_else(|| {
+            ReplyError::new(ErrorCode::SaveFailed, "unknown image type of the frame")
+        })?;
+        let frame_type = request.frame_type.as_str();
+        let mut context = self
+            .camera
+            .name_context(frame_type, request.exposure, started);
+        context.target = request
+            .target
+            .as_deref()
+            .or(self.metadata.object.as_deref());
+        context.filter = request.filter.as_deref();
+        let template = request.template.as_ref().unwrap_or(&self.output.template);
+        let path = template.path(&self.output.directory, &context, format.extension());
+
+        let mut header = self.camera.fits_header(request, started);
+        self.metadata.write_header(&mut header);
+        let written = match format {
+            FileFormat::Fits => fits::write(&path, &header, &image),
+            FileFormat::Xisf => xisf::write(&path, &header, &image, self.output.compression),
+            FileFormat::Tiff => export::write_tiff(&path, &image, self.output.debayer),
+            FileFormat::Png => export::write_png(&path, &image, self.output.debayer),
+        };
+        if let Err(e) = written {
+            error!("Unable to write {}: {}", path.display(), e);
+            return Err(ReplyError::new(
+                ErrorCode::SaveFailed,
+                format!("unable to write {}: {}", path.display(), e),
+            ));
+        }
+
+        info!("Frame saved to {}", path.display());
+        self.emit(CameraEvent::Saved(SavedFrame {
+            request_id: request.request_id.clone(),
+            transfer_id: frame.header.transfer_id,
+            path,
+            format,
+        }));
+        Ok(())
+    }
+
+    /// Publish a frame in chunks and keep it around for the resend requests
+    fn send_frame(&mut self, frame: &Arc<Frame>) -> Result<(), ReplyError> {
+        info!(
+            "Sending frame {} in {} chunks",
+            frame.header.transfer_id, frame.header.chunks
+        );
+        self.frames.insert(frame.clone());
+
+        self.runtime
+            .block_on(transfer::send(
+                &self.client,
+                &self.acks,
+                &self.router,
+                self.camera.id,
+                frame,
+                self.transfer.retry_policy(),
+            ))
+            .map_err(|e| ReplyError::new(ErrorCode::TransferFailed, e.to_string()))
+    }
+
+    fn pull_video_frame(&mut self) {
+        let Some(buffer) = self.video.as_mut() else {
+            return;
+        };
+
+        if self.camera.video_frame(buffer, VIDEO_WAIT_MS) {
+            let written = match self.recording.as_mut() {
+                Some(recording) => recording.writer.add_frame(buffer, SystemTime::now()),
+                None => Ok(()),
+            };
+            self.send_video_preview();
+
+            if let Err(e) = written {
+                let e = ReplyError::new(ErrorCode::SaveFailed, e.to_string());
+                self.finish_recording(Err(e));
+            }
+        }
+
+        if self.recording.as_ref().is_some_and(Recording::is_over) {
+            self.finish_recording(Ok(()));
+        }
+    }
+
+    /// Put the camera in video mode and allocate the buffer of its frames
+    fn start_video(&mut self) -> Result<(), ReplyError> {
+        let size = self.camera.frame_size()?;
+        self.camera.start_video()?;
+        self.video = Some(vec![0; size]);
+        Ok(())
+    }
+
+    /// Leave video mode, does nothing if the camera isn't in it
+    fn stop_video(&mut self) -> Result<(), ReplyError> {
+        if self.video.take().is_none() {
+            return Ok(());
+        }
+        self.camera.stop_video().map_err(ReplyError::from)
+    }
+
+    /// Open a SER file and start writing the video frames to it
+    fn start_recording(&mut self, request: &RecordRequest) -> Result<(), ReplyError> {
+        let mut header = self.camera.ser_header().ok_or_else(|| {
+            ReplyError::new(ErrorCode::NotSupported, "unknown image type of the frames")
+        })?;
+        header.observer = self.metadata.observer.clone().unwrap_or_default();
+        header.telescope = self.metadata.telescope.clone().unwrap_or_default();
+        let started = SystemTime::now();
+        let mut context = self
+            .camera
+            .name_context("video", self.camera.video_exposure(), started);
+        context.target = request
+            .target
+            .as_deref()
+            .or(self.metadata.object.as_deref());
+        context.filter = request.filter.as_deref();
+        let template = request.template.as_ref().unwrap_or(&self.output.template);
+        let path = template.path(&self.output.directory, &context, "ser");
+
+        let owns_video = self.video.is_none();
+        if owns_video {
+            self.start_video()?;
+        }
+
+        let writer = match SerWriter::create(&path, header, started) {
+            Ok(w) => w,
+            Err(e) => {
+                if owns_video && self.video.take().is_some() {
+                    let _ = self.camera.stop_video();
+                }
+                return Err(ReplyError::new(
+                    ErrorCode::SaveFailed,
+                    format!("unable to create {}: {}", path.display(), e),
+                ));
+            }
+        };
+
+        info!("Recording {} to {}", self.camera.name, path.display());
+        self.camera.set_recording(true);
+        self.recording = Some(Recording {
+            request_id: request.request_id.clone(),
+            writer,
+            path,
+            max_frames: request.frames,
+            max_duration: request.max_duration(),
+            started: Instant::now(),
+            owns_video,
+            dropped_at_start: self.camera.dropped_frames().unwrap_or(0),
+        });
+        Ok(())
+    }
+
+    /// Close the file of the ongoing recording, if any, announce it and
+    /// answer the `record` request with `result`
+    fn finish_recording(&mut self, result: Result<(), ReplyError>) {
+        let Some(recording) = self.recording.take() else {
+            return;
+        };
+
+        // The SDK counts the dropped frames until the video capture stops
+        let dropped = self
+            .camera
+            .dropped_frames()
+            .map(|d| (d - recording.dropped_at_start).max(0) as u32)
+            .unwrap_or(0);
+        let duration = recording.started.elapsed().as_secs_f64();
+        let finished = recording.writer.finish().map_err(|e| {
+            ReplyError::new(
+                ErrorCode::SaveFailed,
+                format!("unable to write {}: {}", recording.path.display(), e),
+            )
+        });
+
+        if recording.owns_video && self.video.take().is_some() {
+            if let Err(e) = self.camera.stop_video() {
+                error!("Unable to stop video for {}: {}", self.camera.name, e);
+            }
+        } else {
+            self.camera.set_recording(false);
+        }
+
+        let result = match finished {
+            Ok(frames) => {
+                info!(
+                    "Recorded {} frames to {}, {} dropped",
+                    frames,
+                    recording.path.display(),
+                    dropped
+                );
+                self.emit(CameraEvent::Recorded(RecordingSummary {
+                    request_id: recording.request_id.clone(),
+                    path: recording.path,
+                    frames,
+                    dropped,
+                    duration,
+                }));
+                result
+            }
+            Err(e) => Err(e),
+        };
+
+        self.reply(Reply::outcome("record", recording.request_id, result));
+        self.publish_state();
+    }
+
+    /// Publish the properties that changed, or the full state if a snapshot
+    /// is due
+    fn publish_state(&mut self) {
+        match self.state.update(&self.camera) {
+            Ok(Some(update)) => self.emit_state(update),
+            Ok(None) => (),
+            Err(e) => error!("Unable to serialize camera {}: {}", self.camera.id, e),
+        }
+    }
+
+    fn publish_snapshot(&mut self) {
+        match self.state.snapshot(&self.camera) {
+            Ok(update) => self.emit_state(update),
+            Err(e) => error!("Unable to serialize camera {}: {}", self.camera.id, e),
+        }
+    }
+
+    fn emit_state(&self, update: StateUpdate) {
+        self.emit(match update {
+            StateUpdate::Snapshot(s) => CameraEvent::State(s),
+            StateUpdate::Changes(c) => CameraEvent::Changes(c),
+        });
+    }
+
+    fn reply(&self, reply: Reply) {
+        self.emit(CameraEvent::Reply(reply));
+    }
+
+    fn emit(&self, event: CameraEvent) {
+        if self.events.send((self.camera.id, event)).is_err() {
+            debug!("Nobody is listening for events of {}", self.camera.id);
+        }
+    }
+}
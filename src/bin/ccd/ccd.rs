@@ -1,20 +1,28 @@
+use crate::request::{ControlValue, ExposureRequest, ImageType, Roi, UpdateRequest};
+use crate::schedule::PollSchedule;
 use crate::utils::fetch_control_caps;
 use crate::utils::get_num_of_controls;
-use libasi::camera::AsiCameraInfo;
+use asi_rs::config::Daemon;
+use asi_rs::discovery::{Announcement, DeviceKind};
+use asi_rs::fits::{self, Header, Image};
+use asi_rs::image::BayerPattern;
+use asi_rs::naming::NameContext;
+use asi_rs::reply::{ErrorCode, ReplyError};
+use asi_rs::ser::{ColorId, SerHeader};
+use libasi::camera::{AsiCameraInfo, AsiError};
 
 use astrotools::properties::{Permission, Prop, Property, RangeProperty};
-use log::{debug, error, info};
+use log::{debug, info};
 use serde::Serialize;
+use serde_json::{json, Map, Value};
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
 use uuid::Uuid;
 
-type Camera = Arc<RwLock<AsiCamera>>;
-
 pub mod utils {
     use crate::ccd::AsiProperty;
+    use crate::request::ImageType;
     use convert_case::{Case, Casing};
     use libasi::camera::AsiControlCaps;
     use log::{error, info, warn};
@@ -38,6 +46,7 @@ pub mod utils {
             if id.id == [0, 0, 0, 0, 0, 0, 0, 0] {
                 debug!("Setting a random uid");
                 crate::utils::generics::set_camera_id(camera_index, None);
+                get_cam_id(camera_index, &mut id);
             }
             let id_str = asi_id_to_string(&id.id);
             info!("ASI ID for camera with index {}: {:?}", camera_index, &id);
@@ -74,47 +83,34 @@ pub mod utils {
     }
 
     pub mod capturing {
-        use crate::ccd::Camera;
+        use crate::ccd::AsiCamera;
+        use crate::request::{ExposureRequest, FrameType};
+        use asi_rs::reply::{ErrorCode, ReplyError};
         use astrotools::properties::Prop;
-        use base64::prelude::BASE64_STANDARD;
-        use base64::Engine;
         use libasi::camera::{
-            download_exposure, exposure_status, set_control_value, start_exposure,
+            download_exposure, exposure_status, set_control_value, start_dark_exposure,
+            start_exposure, stop_exposure,
         };
         use log::{debug, error, info};
-        use rumqttc::Event::Incoming;
-        use rumqttc::{Client, MqttOptions};
-        use std::time::Duration;
         use std::time::SystemTime;
-        pub fn expose(length: f32, img_type: i32, device: Camera) {
-            let r_dev = device.read().unwrap();
-            let width = r_dev.width.value().clone();
-            let height = r_dev.height.value().clone();
-            let idx = r_dev.idx;
-
-            drop(r_dev);
-
-            // // Create the right sized buffer for the image to be stored.
-            // // if we shoot at 8 bit it is just width * height
-            let buffer_size: i32;
-
-            buffer_size = match img_type {
-                libasi::camera::ASI_IMG_TYPE_ASI_IMG_RGB24 => width * height * 3,
-                libasi::camera::ASI_IMG_TYPE_ASI_IMG_RAW16 => width * height * 2,
-                libasi::camera::ASI_IMG_TYPE_ASI_IMG_RAW8
-                | libasi::camera::ASI_IMG_TYPE_ASI_IMG_Y8 => width * height,
-                _ => todo!(),
-            };
 
-            let secs_to_micros: i64 = (length * 1_000_000_f32) as i64;
+        /// Run a single exposure on `device`, blocking the calling thread until the
+        /// frame is downloaded, and return the raw frame. `aborted` is checked while
+        /// the sensor is integrating, as soon as it returns true the exposure is
+        /// stopped and nothing is downloaded.
+        pub fn expose(
+            request: &ExposureRequest,
+            device: &mut AsiCamera,
+            mut aborted: impl FnMut() -> bool,
+        ) -> Result<Vec<u8>, ReplyError> {
+            let idx = device.idx;
 
-            let mut image_buffer = Vec::with_capacity(buffer_size as usize);
+            // Create the right sized buffer for the image to be stored.
+            let buffer_size = device.frame_size()?;
 
-            // Since the Vec will serve as buffer for FFI, we call set_lenght
-            // see here https://doc.rust-lang.org/std/vec/struct.Vec.html#examples-22
-            unsafe {
-                image_buffer.set_len(buffer_size as usize);
-            }
+            let secs_to_micros = request.micros();
+
+            let mut image_buffer = vec![0_u8; buffer_size];
             let mut status = 0;
 
             debug!("Update prop exposing {}", secs_to_micros);
@@ -127,7 +123,7 @@ pub mod utils {
                     libasi::camera::ASI_CONTROL_TYPE_ASI_EXPOSURE as i32,
                     secs_to_micros,
                     libasi::camera::ASI_BOOL_ASI_FALSE as i32,
-                );
+                )?;
             }
 
             #[cfg(windows)]
@@ -137,80 +133,86 @@ pub mod utils {
                     libasi::camera::ASI_CONTROL_TYPE_ASI_EXPOSURE as i32,
                     secs_to_micros as i32,
                     0,
-                );
+                )?;
             }
 
             // Send the command to start the exposure
-            start_exposure(idx);
+            match request.frame_type {
+                FrameType::Dark | FrameType::Bias => start_dark_exposure(idx)?,
+                FrameType::Light | FrameType::Flat => start_exposure(idx)?,
+            }
             exposure_status(idx, &mut status);
             let start = SystemTime::now();
             // Swapping exposure related properties AKA prepare props to show
             // informations about ongoing exposure
-            {
-                let mut d = device.write().unwrap();
-		// TODO: Fix this unused result
-		let _ = d.exposure_status
-                    .update_int(std::borrow::Cow::Borrowed("EXPOSING"));
-            }
+            // TODO: Fix this unused result
+            let _ = device
+                .exposure_status
+                .update_int(std::borrow::Cow::Borrowed("EXPOSING"));
+            let _ = device.exposing.update_int(true);
 
             debug!("Started exposure");
 
             // Loop until the status change
             while status == 1 {
+                if aborted() {
+                    info!("Aborting exposure");
+                    let stopped = stop_exposure(idx);
+                    let _ = device
+                        .exposure_status
+                        .update_int(std::borrow::Cow::Borrowed("ABORTED"));
+                    let _ = device.exposing.update_int(false);
+                    stopped?;
+                    return Err(ReplyError::new(ErrorCode::Aborted, "exposure aborted"));
+                }
                 exposure_status(idx, &mut status);
                 std::thread::sleep(std::time::Duration::from_millis(50));
             }
 
             info!("Elapsed: {}", start.elapsed().unwrap().as_micros());
+            let _ = device.exposing.update_int(false);
 
             match status {
                 libasi::camera::ASI_EXPOSURE_STATUS_ASI_EXP_SUCCESS => {
                     info!("Exposure successful");
-                    {
-                        let mut d = device.write().unwrap();
-			// TODO: Fix this unused result
-			let _ = d.exposure_status
-                            .update_int(std::borrow::Cow::Borrowed("SUCCESS"));
-                    }
+                    // TODO: Fix this unused result
+                    let _ = device
+                        .exposure_status
+                        .update_int(std::borrow::Cow::Borrowed("SUCCESS"));
 
                     info!("downloading");
-                    download_exposure(idx, image_buffer.as_mut_ptr(), buffer_size.into());
-
-                    let mut mqttoptions = MqttOptions::new("asi_exposure", "127.0.0.1", 1883);
-                    mqttoptions.set_keep_alive(Duration::from_secs(5));
-                    let (client, mut connection) = Client::new(mqttoptions, 10);
-
-                    client
-                        .publish(
-                            format!(
-                                "{}",
-                                format_args!(
-                                    "devices/{}/exposure",
-                                    &device.read().unwrap().id.to_string()
-                                )
-                            ),
-                            rumqttc::QoS::AtLeastOnce,
-                            false,
-                            BASE64_STANDARD.encode(&image_buffer),
-                        )
-                        .unwrap();
-
-                    for notification in connection.iter() {
-                        match notification {
-                            Ok(Incoming(inc)) => match inc {
-                                rumqttc::Packet::PubAck(_m) => break,
-                                _ => continue,
-                            },
-                            _ => continue,
-                        }
-                    }
+                    download_exposure(idx, image_buffer.as_mut_ptr(), buffer_size as _)?;
+                    Ok(image_buffer)
+                }
+                libasi::camera::ASI_EXPOSURE_STATUS_ASI_EXP_FAILED => {
+                    error!("Exposure failed");
+                    let _ = device
+                        .exposure_status
+                        .update_int(std::borrow::Cow::Borrowed("FAILED"));
+                    Err(ReplyError::new(ErrorCode::DeviceError, "exposure failed"))
+                }
+                n => {
+                    error!("A error happened: {}", n);
+                    Err(ReplyError::new(
+                        ErrorCode::DeviceError,
+                        format!("unexpected exposure status {}", n),
+                    ))
                 }
-                libasi::camera::ASI_EXPOSURE_STATUS_ASI_EXP_FAILED => error!("Exposure failed"),
-                n => error!("A error happened: {}", n),
             }
         }
     }
 
+    /// Size in bytes of a frame with the given dimensions and image type
+    pub fn buffer_size(width: i32, height: i32, image_type: ImageType) -> usize {
+        let pixels = width.max(0) as usize * height.max(0) as usize;
+
+        match image_type {
+            ImageType::Rgb24 => pixels * 3,
+            ImageType::Raw16 => pixels * 2,
+            ImageType::Raw8 | ImageType::Y8 => pixels,
+        }
+    }
+
     pub fn asi_name_to_string_i8(name_array: &[i8]) -> String {
         let mut to_u8: Vec<u8> = vec![];
 
@@ -398,10 +400,10 @@ pub mod utils {
             let cap = AsiProperty {
                 name: crate::utils::asi_name_to_string_i8(&control_caps.Name).to_case(Case::Snake),
                 _description: crate::utils::asi_name_to_string_i8(&control_caps.Description),
-                _max_value: control_caps.MaxValue,
-                _min_value: control_caps.MinValue,
+                max_value: control_caps.MaxValue,
+                min_value: control_caps.MinValue,
                 _default_value: control_caps.DefaultValue,
-                _is_auto_supported: control_caps.IsAutoSupported != 0,
+                is_auto_supported: control_caps.IsAutoSupported != 0,
                 is_writable: control_caps.IsWritable != 0,
                 control_type: control_caps.ControlType as i32,
             };
@@ -425,10 +427,10 @@ pub mod utils {
 pub struct AsiProperty {
     name: String,
     _description: String,
-    _max_value: i64,
-    _min_value: i64,
+    max_value: i64,
+    min_value: i64,
     _default_value: i64,
-    _is_auto_supported: bool,
+    is_auto_supported: bool,
     is_writable: bool,
     control_type: i32,
 }
@@ -438,11 +440,15 @@ pub struct AsiCamera {
     #[serde(skip)]
     pub id: Uuid,
     pub name: String,
+    #[serde(skip)]
+    serial: Option<String>,
     idx: i32,
     #[serde(skip)]
     caps: Vec<AsiProperty>,
     #[serde(flatten)]
     controls: std::collections::HashMap<String, RangeProperty<isize>>,
+    /// Whether the controls supporting it are automatically adjusted
+    auto: HashMap<String, Property<bool>>,
     #[serde(skip)]
     _ls_rand_id: [u8; 8],
     is_color: Property<bool>,
@@ -465,10 +471,16 @@ pub struct AsiCamera {
     height: Property<i32>,
     bin: Property<i32>,
     image_type: Property<i32>,
+    #[serde(skip)]
+    supported_bins: Vec<i32>,
+    #[serde(skip)]
+    supported_formats: Vec<i32>,
+    #[serde(skip)]
+    schedule: PollSchedule,
 }
 
 impl AsiCamera {
-    pub fn new(index: i32) -> Self {
+    pub fn new(index: i32, schedule: PollSchedule) -> Self {
         // From the SDK documentation, in order:
         // 1) Get count of connected cameras (THIS IS DONE ALREADY as we already called look_for_devices
         // 2) get camera ID using ASIGetCameraProperty
@@ -499,18 +511,28 @@ impl AsiCamera {
 
         // Check if we have a random generated id for the camera, if not generate one,
         // store it on the camera itself and assign it to self.ls_rand_id
-        let _ls_rand_id = utils::generics::get_camera_id(index);
+        let ls_rand_id = utils::generics::get_camera_id(index);
+
+        // The serial number is the most reliable way to identify a camera, but only
+        // USB3 models have one, the others rely on the ID stored above
+        let name = utils::asi_name_to_string_i8(&info.Name);
+        let serial = libasi::camera::get_serial_number(index)
+            .ok()
+            .and_then(|sn| asi_rs::utils::serial_to_string(&sn.id));
+        let hw_id = serial.clone().unwrap_or(ls_rand_id);
 
         //for (i, byte) in ls_rand_id.as_bytes().iter().enumerate() {
         //    self.ls_rand_id[i] = *byte;
         //}
 
         let mut device = Self {
-            id: Uuid::new_v4(),
-            name: utils::asi_name_to_string_i8(&info.Name),
+            id: asi_rs::utils::device_uuid(&name, &hw_id),
+            name,
+            serial,
             idx: info.CameraID,
             caps,
             controls: HashMap::new(),
+            auto: HashMap::new(),
             _ls_rand_id: [0; 8],
             is_color: Property::new(info.IsColorCam == 1, Permission::ReadOnly),
             camera_id: Property::<u8>::new(info.CameraID as u8, Permission::ReadOnly),
@@ -547,6 +569,19 @@ impl AsiCamera {
             height: Property::new(0, Permission::ReadWrite),
             bin: Property::new(0, Permission::ReadWrite),
             image_type: Property::new(0, Permission::ReadWrite),
+            supported_bins: info
+                .SupportedBins
+                .iter()
+                .copied()
+                .take_while(|b| *b != 0)
+                .collect(),
+            supported_formats: info
+                .SupportedVideoFormat
+                .iter()
+                .copied()
+                .take_while(|f| *f != libasi::camera::ASI_IMG_TYPE_ASI_IMG_END)
+                .collect(),
+            schedule,
         };
 
         device.asi_caps_to_lightspeed_props();
@@ -554,40 +589,526 @@ impl AsiCamera {
         device
     }
 
+    /// Read every control from the camera, to be used after writing to it
     pub fn fetch_props(&mut self) {
+        let all: Vec<i32> = self.caps.iter().map(|c| c.control_type).collect();
+        self.read_controls(&all);
+    }
+
+    /// Read only the controls that are due according to the polling schedule
+    pub fn poll_props(&mut self) {
+        let now = Instant::now();
+        let due: Vec<i32> = self
+            .caps
+            .iter()
+            .filter(|cap| {
+                let auto = self.auto.get(&cap.name).is_some_and(|a| *a.value());
+                self.schedule.is_due(cap.control_type, auto, now)
+            })
+            .map(|c| c.control_type)
+            .collect();
+
+        if !due.is_empty() {
+            self.read_controls(&due);
+        }
+    }
+
+    fn read_controls(&mut self, control_types: &[i32]) {
         let now = Instant::now();
         debug!("Fetching properties for device {}", self.name);
 
-        for cap in &self.caps {
-            let val = self.get_control_value(&cap);
+        for cap in self
+            .caps
+            .iter()
+            .filter(|c| control_types.contains(&c.control_type))
+        {
+            let (val, is_auto) = self.get_control_value(&cap);
             debug!("Cap {} value is  {}", &cap.name, &val);
             let v = self.controls.get_mut(&cap.name).unwrap();
             if v.value() != &val {
 		// TODO: Fix this unused error
 		let _ = v.update_int(val);
             }
+            if let Some(a) = self.auto.get_mut(&cap.name)
+                && a.value() != &is_auto
+            {
+                let _ = a.update_int(is_auto);
+            }
+            self.schedule.read(cap.control_type, now);
         }
 
         let elapsed = now.elapsed();
         debug!("Elapsed: {:.2?}", elapsed);
     }
 
+    /// Describe a frame taken with `request`, sent along with it
+    pub fn frame_metadata(&self, request: &ExposureRequest) -> Map<String, Value> {
+        let image_type = ImageType::from_asi(*self.image_type.value());
+        let metadata = json!({
+            "camera": self.name,
+            "width": self.width.value(),
+            "height": self.height.value(),
+            "bin": self.bin.value(),
+            "image_type": image_type,
+            "frame_type": request.frame_type,
+            "exposure": request.exposure,
+            "gain": self.controls.get("gain").map(|g| *g.value()),
+            "offset": self.controls.get("offset").map(|o| *o.value()),
+            // Neither is obvious from the frame itself
+            "color_order": (image_type == Some(ImageType::Rgb24)).then_some("BGR"),
+            "bayer_pattern": self.bayer().map(|p| p.as_str()),
+        });
+
+        match metadata {
+            Value::Object(map) => map,
+            _ => unreachable!("json! object literal"),
+        }
+    }
+
+    /// FITS keywords of a frame taken with `request`, whose exposure started
+    /// at `started`
+    pub fn fits_header(&self, request: &ExposureRequest, started: SystemTime) -> Header {
+        let mut header = Header::new();
+        let bin = *self.bin.value();
+        let control = |name: &str| self.controls.get(name).map(|c| *c.value() as i64);
+
+        header.set("EXPTIME", request.exposure, "exposure time in seconds");
+        header.set("DATE-OBS", fits::date_obs(started), "UTC exposure start");
+        header.set("IMAGETYP", request.frame_type.fits_name(), "type of frame");
+        header.set("INSTRUME", self.name.as_str(), "camera model");
+        if let Some(target) = &request.target {
+            header.set("OBJECT", target.as_str(), "name of the object");
+        }
+        if let Some(filter) = &request.filter {
+            header.set("FILTER", filter.as_str(), "filter in the optical path");
+        }
+        if let Some(serial) = &self.serial {
+            header.set("SERIALNO", serial.as_str(), "camera serial number");
+        }
+        if let Some(gain) = control("gain") {
+            header.set("GAIN", gain, "sensor gain");
+        }
+        if let Some(offset) = control("offset") {
+            header.set("OFFSET", offset, "sensor offset");
+        }
+        // The SDK reports the temperature in tenths of degree
+        if let Some(temp) = control("temperature") {
+            header.set("CCD-TEMP", temp as f64 / 10.0, "sensor temperature in C");
+        }
+        if let Some(target) = control("target_temp") {
+            header.set("SET-TEMP", target as f64, "cooler set point in C");
+        }
+        header.set("XBINNING", bin, "binning factor along X");
+        header.set("YBINNING", bin, "binning factor along Y");
+        let pixel_size = *self.pix_size.value() * bin as f64;
+        header.set("XPIXSZ", pixel_size, "binned pixel width in microns");
+        header.set("YPIXSZ", pixel_size, "binned pixel height in microns");
+
+        let (mut x, mut y) = (0, 0);
+        libasi::camera::get_start_position(*self.index(), &mut x, &mut y);
+        header.set("XORGSUBF", x, "ROI origin along X, in binned pixels");
+        header.set("YORGSUBF", y, "ROI origin along Y, in binned pixels");
+
+        if let Some(pattern) = self.bayer() {
+            header.set("BAYERPAT", pattern.as_str(), "colour filter array pattern");
+        }
+
+        header
+    }
+
+    /// What the file of a frame of type `frame_type`, lasting `exposure`
+    /// seconds and started at `started`, is named after
+    pub fn name_context<'a>(
+        &'a self,
+        frame_type: &'a str,
+        exposure: f64,
+        started: SystemTime,
+    ) -> NameContext<'a> {
+        let control = |name: &str| self.controls.get(name).map(|c| *c.value() as i64);
+
+        NameContext {
+            camera: &self.name,
+            target: None,
+            filter: None,
+            frame_type,
+            exposure,
+            gain: control("gain"),
+            // The SDK reports the temperature in tenths of degree
+            temperature: control("temperature").map(|t| t as f64 / 10.0),
+            time: started,
+        }
+    }
+
+    /// Exposure length of the video frames in seconds
+    pub fn video_exposure(&self) -> f64 {
+        self.controls
+            .get("exposure")
+            .map_or(0.0, |c| *c.value() as f64 / 1_000_000.0)
+    }
+
+    /// Bits per pixel of the analog to digital converter of the sensor
+    pub fn adc_bits(&self) -> u32 {
+        u32::from(*self.bit_depth.value())
+    }
+
+    /// Header of a SER recording of the frames with the current ROI format
+    pub fn ser_header(&self) -> Option<SerHeader> {
+        let image_type = ImageType::from_asi(*self.image_type.value())?;
+        let color = match image_type {
+            ImageType::Rgb24 => ColorId::Bgr,
+            ImageType::Y8 => ColorId::Mono,
+            ImageType::Raw8 | ImageType::Raw16 if *self.is_color.value() => {
+                ColorId::bayer(self.bayer()?.as_str())?
+            }
+            ImageType::Raw8 | ImageType::Raw16 => ColorId::Mono,
+        };
+
+        Some(SerHeader {
+            color,
+            width: *self.width.value() as usize,
+            height: *self.height.value() as usize,
+            bit_depth: if image_type == ImageType::Raw16 {
+                16
+            } else {
+                8
+            },
+            observer: String::new(),
+            instrument: self.name.clone(),
+            telescope: String::new(),
+        })
+    }
+
+    /// `data`, downloaded with the current ROI format, as an image to save
+    pub fn image<'a>(&self, data: &'a [u8]) -> Option<Image<'a>> {
+        let image_type = ImageType::from_asi(*self.image_type.value())?;
+
+        Some(Image {
+            width: *self.width.value() as usize,
+            height: *self.height.value() as usize,
+            format: image_type.pixel_format(),
+            bayer: self.bayer(),
+            data,
+        })
+    }
+
+    /// Colour filter array of the frames with the current format, `None`
+    /// unless they are raw frames of a colour camera
+    pub fn bayer(&self) -> Option<BayerPattern> {
+        let raw = matches!(
+            ImageType::from_asi(*self.image_type.value()),
+            Some(ImageType::Raw8 | ImageType::Raw16)
+        );
+        if !*self.is_color.value() || !raw {
+            return None;
+        }
+
+        // The SDK reports the pattern of the sensor, mirroring the frame
+        // moves the colours
+        use libasi::camera::{
+            ASI_FLIP_STATUS_ASI_FLIP_BOTH as BOTH, ASI_FLIP_STATUS_ASI_FLIP_HORIZ as HORIZ,
+            ASI_FLIP_STATUS_ASI_FLIP_VERT as VERT,
+        };
+        let flip = self.controls.get("flip").map_or(0, |f| *f.value());
+        let horizontal = flip == HORIZ as isize || flip == BOTH as isize;
+        let vertical = flip == VERT as isize || flip == BOTH as isize;
+        bayer_pattern(self.bayer_pattern.value()).map(|p| p.flipped(horizontal, vertical))
+    }
+
+    /// Describe the camera for the discovery topic
+    pub fn announcement(&self) -> Announcement {
+        let formats: Vec<ImageType> = self
+            .supported_formats
+            .iter()
+            .filter_map(|f| ImageType::from_asi(*f))
+            .collect();
+        let controls: Vec<&str> = self.caps.iter().map(|c| c.name.as_str()).collect();
+
+        let capabilities = json!({
+            "max_width": self.max_width.value(),
+            "max_height": self.max_height.value(),
+            "pixel_size": self.pix_size.value(),
+            "bit_depth": self.bit_depth.value(),
+            "is_color": self.is_color.value(),
+            "bayer_pattern": self.bayer_pattern.value(),
+            "bins": self.supported_bins,
+            "image_types": formats,
+            "controls": controls,
+            "cooler": controls.contains(&"cooler_on"),
+            "has_shutter": self.has_shutter.value(),
+            "st4": self.st4.value(),
+        });
+
+        Announcement::new(
+            Daemon::Ccd.name(),
+            DeviceKind::Camera,
+            self.id,
+            &self.name,
+            self.serial.clone(),
+            capabilities,
+        )
+    }
+
     /// Method to be used when receving requests from clients to update properties.
     ///
-    /// Ideally this should call internally `update_property_remote` which will be
-    /// responsible to trigger the action against the device to update the property
-    /// on the device itself, if the action is successful the last thing this method
-    /// does would be to update the property inside `self.properties`.
-    pub fn update_property(&mut self, prop_name: &str, val: i32) {
-        info!("UPDATE: prop name {}", &prop_name);
-        info!("UPDATE: val {}", &val);
-        match prop_name {
-            "img_type" => {
-                self.set_roi_format(None, None, None, Some(val));
-                self.fetch_roi_format();
+    /// Every property is checked before touching the device, if any of them is
+    /// invalid nothing should be applied.
+    pub fn check_update(&self, request: &UpdateRequest) -> Result<(), ReplyError> {
+        let bin = request.bin.unwrap_or(*self.bin.value());
+        self.check_format(request.image_type, bin, request.roi.as_ref())?;
+        for (name, value) in &request.controls {
+            self.check_control_update(name, value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Apply an update validated by `check_update`. The new values are then read
+    /// back from the camera, even on failure, so that the state reflects what the
+    /// SDK actually accepted.
+    pub fn apply_update(&mut self, request: &UpdateRequest) -> Result<(), AsiError> {
+        info!("UPDATE: {:?}", request);
+        let result = self.write_update(request);
+
+        self.fetch_roi_format();
+        self.fetch_props();
+        result
+    }
+
+    fn write_update(&self, request: &UpdateRequest) -> Result<(), AsiError> {
+        if request.changes_format() {
+            let bin = request.bin.unwrap_or(*self.bin.value());
+            let img_type = request.image_type.map(|t| t.as_asi());
+            // A new binning without ROI means the full frame at that binning
+            match request
+                .roi
+                .or_else(|| request.bin.map(|b| self.full_frame(b)))
+            {
+                Some(roi) => {
+                    self.set_roi_format(Some(roi.width), Some(roi.height), Some(bin), img_type)?;
+                    libasi::camera::set_start_position(*self.index(), roi.x, roi.y)?;
+                }
+                None => self.set_roi_format(None, None, None, img_type)?,
+            }
+        }
+
+        for (name, value) in &request.controls {
+            let cap = self.caps.iter().find(|c| &c.name == name).unwrap();
+            let val = match value.value() {
+                Some(v) => v,
+                None => *self.controls[name].value() as i64,
+            };
+            self.set_control(cap.control_type, val, value.auto())?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that `request` can be run by this camera
+    pub fn check_exposure(&self, request: &ExposureRequest) -> Result<(), ReplyError> {
+        if request.exposure <= 0.0 {
+            return Err(ReplyError::invalid("exposure", "must be greater than 0"));
+        }
+
+        if let Some(cap) = self.cap(libasi::camera::ASI_CONTROL_TYPE_ASI_EXPOSURE as i32) {
+            let micros = request.micros();
+            if micros < cap.min_value || micros > cap.max_value {
+                return Err(ReplyError::invalid(
+                    "exposure",
+                    format!(
+                        "must be between {}s and {}s",
+                        cap.min_value as f64 / 1_000_000_f64,
+                        cap.max_value as f64 / 1_000_000_f64
+                    ),
+                ));
             }
-            _ => error!("Unknown property: {}", prop_name),
         }
+
+        self.check_format(request.image_type, request.bin, request.roi.as_ref())?;
+        self.check_control(
+            "gain",
+            libasi::camera::ASI_CONTROL_TYPE_ASI_GAIN as i32,
+            request.gain,
+        )?;
+        self.check_control(
+            "offset",
+            libasi::camera::ASI_CONTROL_TYPE_ASI_OFFSET as i32,
+            request.offset,
+        )
+    }
+
+    /// Apply the settings of `request` to the camera, it must have been
+    /// validated with `check_exposure` first.
+    pub fn prepare_exposure(&mut self, request: &ExposureRequest) -> Result<(), AsiError> {
+        let result = self.write_exposure_settings(request);
+
+        self.fetch_roi_format();
+        self.fetch_props();
+        result
+    }
+
+    fn write_exposure_settings(&self, request: &ExposureRequest) -> Result<(), AsiError> {
+        let bin = request.bin;
+        let roi = request.roi.unwrap_or_else(|| self.full_frame(bin));
+
+        self.set_roi_format(
+            Some(roi.width),
+            Some(roi.height),
+            Some(bin),
+            request.image_type.map(|t| t.as_asi()),
+        )?;
+        libasi::camera::set_start_position(*self.index(), roi.x, roi.y)?;
+
+        if let Some(gain) = request.gain {
+            self.set_control(
+                libasi::camera::ASI_CONTROL_TYPE_ASI_GAIN as i32,
+                gain,
+                false,
+            )?;
+        }
+        if let Some(offset) = request.offset {
+            self.set_control(
+                libasi::camera::ASI_CONTROL_TYPE_ASI_OFFSET as i32,
+                offset,
+                false,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn check_format(
+        &self,
+        image_type: Option<ImageType>,
+        bin: i32,
+        roi: Option<&Roi>,
+    ) -> Result<(), ReplyError> {
+        if let Some(img_type) = image_type
+            && !self.supported_formats.contains(&img_type.as_asi())
+        {
+            return Err(ReplyError::invalid(
+                "image_type",
+                format!(
+                    "{:?} is not supported, supported values are {}",
+                    img_type,
+                    self.video_formats.value()
+                ),
+            ));
+        }
+
+        if !self.supported_bins.contains(&bin) {
+            return Err(ReplyError::invalid(
+                "bin",
+                format!("supported values are {}", self.bins.value()),
+            ));
+        }
+
+        match roi {
+            Some(roi) => self.check_roi(roi, bin),
+            None => Ok(()),
+        }
+    }
+
+    fn check_roi(&self, roi: &Roi, bin: i32) -> Result<(), ReplyError> {
+        let max_width = *self.max_width.value() as i32 / bin;
+        let max_height = *self.max_height.value() as i32 / bin;
+
+        if roi.width <= 0 || roi.width % 8 != 0 {
+            return Err(ReplyError::invalid(
+                "roi.width",
+                "must be a positive multiple of 8",
+            ));
+        }
+        if roi.height <= 0 || roi.height % 2 != 0 {
+            return Err(ReplyError::invalid(
+                "roi.height",
+                "must be a positive multiple of 2",
+            ));
+        }
+        if roi.x < 0 || roi.y < 0 {
+            return Err(ReplyError::invalid(
+                "roi",
+                "the start position can't be negative",
+            ));
+        }
+        // Both sides are positive past the checks above, unlike the sums of
+        // values from the request the differences can't overflow
+        if roi.x > max_width - roi.width || roi.y > max_height - roi.height {
+            return Err(ReplyError::invalid(
+                "roi",
+                format!("doesn't fit in {}x{} at bin {}", max_width, max_height, bin),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn check_control(
+        &self,
+        field: &str,
+        control_type: i32,
+        value: Option<i64>,
+    ) -> Result<(), ReplyError> {
+        let Some(value) = value else {
+            return Ok(());
+        };
+
+        match self.cap(control_type) {
+            None => Err(ReplyError::invalid(
+                field,
+                format!("not supported by {}", self.name),
+            )),
+            Some(cap) if !cap.is_writable => Err(ReplyError::invalid(field, "read only")),
+            Some(cap) if value < cap.min_value || value > cap.max_value => {
+                Err(ReplyError::invalid(
+                    field,
+                    format!("must be between {} and {}", cap.min_value, cap.max_value),
+                ))
+            }
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Check a value of `devices/{id}/update` against the capabilities of
+    /// the corresponding control
+    fn check_control_update(&self, name: &str, value: &ControlValue) -> Result<(), ReplyError> {
+        let Some(cap) = self.caps.iter().find(|c| c.name == name) else {
+            return Err(ReplyError::invalid(name, "unknown property"));
+        };
+
+        if !cap.is_writable {
+            return Err(ReplyError::invalid(name, "read only"));
+        }
+
+        if value.auto() && !cap.is_auto_supported {
+            return Err(ReplyError::invalid(
+                name,
+                "automatic adjustment not supported",
+            ));
+        }
+
+        self.check_control(name, cap.control_type, value.value())
+    }
+
+    /// ROI covering the whole sensor at `bin`, the SDK wants the width to be
+    /// a multiple of 8 and the height a multiple of 2
+    fn full_frame(&self, bin: i32) -> Roi {
+        let width = *self.max_width.value() as i32 / bin;
+        let height = *self.max_height.value() as i32 / bin;
+
+        Roi {
+            x: 0,
+            y: 0,
+            width: width - width % 8,
+            height: height - height % 2,
+        }
+    }
+
+    fn cap(&self, control_type: i32) -> Option<&AsiProperty> {
+        self.caps.iter().find(|c| c.control_type == control_type)
+    }
+
+    fn set_control(&self, control_type: i32, value: i64, auto: bool) -> Result<(), AsiError> {
+        libasi::camera::set_control_value(*self.index(), control_type, value as _, auto as i32)
     }
 
     fn index(&self) -> &i32 {
@@ -597,7 +1118,7 @@ impl AsiCamera {
     fn asi_caps_to_lightspeed_props(&mut self) {
         for cap in &self.caps {
             debug!("CAP name: {}", &cap.name);
-            let cap_value = self.get_control_value(cap);
+            let (cap_value, is_auto) = self.get_control_value(cap);
             // here we create lightspeed properties from AsiCaps
             let prop = RangeProperty::<isize>::new(
                 cap_value,
@@ -606,14 +1127,24 @@ impl AsiCamera {
                 } else {
                     Permission::ReadOnly
                 },
-                cap._min_value.try_into().unwrap(),
-                cap._max_value.try_into().unwrap(),
+                cap.min_value.try_into().unwrap(),
+                cap.max_value.try_into().unwrap(),
             );
             self.controls.insert(cap.name.to_owned(), prop);
+
+            if cap.is_auto_supported {
+                let permission = if cap.is_writable {
+                    Permission::ReadWrite
+                } else {
+                    Permission::ReadOnly
+                };
+                self.auto
+                    .insert(cap.name.to_owned(), Property::new(is_auto, permission));
+            }
         }
     }
 
-    fn get_control_value(&self, cap: &AsiProperty) -> isize {
+    fn get_control_value(&self, cap: &AsiProperty) -> (isize, bool) {
         debug!("Getting value for prop {}", cap.name);
         let mut is_auto_set = 0;
         let mut val: i64 = 0;
@@ -626,9 +1157,67 @@ impl AsiCamera {
         );
         debug!(
             "Value for {} is {} - Auto adjusted? {}",
-            cap.name, val, cap.is_writable
+            cap.name, val, is_auto_set
         );
-        val as isize
+        (val as isize, is_auto_set != 0)
+    }
+
+    /// Size in bytes of a frame with the current ROI format, an error if the
+    /// SDK reports an image type this driver doesn't know
+    pub fn frame_size(&self) -> Result<usize, ReplyError> {
+        let raw = *self.image_type.value();
+        let image_type = ImageType::from_asi(raw).ok_or_else(|| {
+            ReplyError::new(
+                ErrorCode::NotSupported,
+                format!("unknown image type {} of the frames", raw),
+            )
+        })?;
+
+        Ok(utils::buffer_size(
+            *self.width.value(),
+            *self.height.value(),
+            image_type,
+        ))
+    }
+
+    /// Put the camera in video mode, frames must then be pulled with `video_frame`
+    pub fn start_video(&mut self) -> Result<(), AsiError> {
+        info!("Starting video capture for {}", self.name);
+        libasi::camera::start_video_capture(*self.index())?;
+        // TODO: Fix this unused result
+        let _ = self.exposure_status.update_int(Cow::Borrowed("VIDEO"));
+        Ok(())
+    }
+
+    /// Frames dropped by the SDK since the video capture started
+    pub fn dropped_frames(&self) -> Result<i32, AsiError> {
+        libasi::camera::get_dropped_frames(*self.index())
+    }
+
+    /// Tell in the state whether the video frames are being recorded
+    pub fn set_recording(&mut self, recording: bool) {
+        let status = if recording { "RECORDING" } else { "VIDEO" };
+        // TODO: Fix this unused result
+        let _ = self.exposure_status.update_int(Cow::Borrowed(status));
+    }
+
+    pub fn stop_video(&mut self) -> Result<(), AsiError> {
+        info!("Stopping video capture for {}", self.name);
+        let result = libasi::camera::stop_video_capture(*self.index());
+        // TODO: Fix this unused result
+        let _ = self.exposure_status.update_int(Cow::Borrowed("IDLE"));
+        result
+    }
+
+    /// Fill `buffer` with the next video frame, returns false if no frame arrived
+    /// within `wait_ms`. The buffer must be `frame_size()` bytes long.
+    pub fn video_frame(&self, buffer: &mut [u8], wait_ms: i32) -> bool {
+        libasi::camera::get_video_data(
+            *self.index(),
+            buffer.as_mut_ptr(),
+            buffer.len() as _,
+            wait_ms,
+        )
     }
 
     /// Close gently the connection to the camera using the SDK
@@ -673,7 +1262,7 @@ impl AsiCamera {
         height: Option<i32>,
         bin: Option<i32>,
         img_type: Option<i32>,
-    ) {
+    ) -> Result<(), AsiError> {
         info!("Setting ROI");
         let w = if let Some(w) = width {
             w
@@ -696,6 +1285,17 @@ impl AsiCamera {
             *self.image_type.value()
         };
 
-        libasi::camera::set_roi_format(*self.index(), w, h, b, img);
+        libasi::camera::set_roi_format(*self.index(), w, h, b, img)
+    }
+}
+
+/// Full colour filter array pattern from the first row given by the SDK
+fn bayer_pattern(first_row: &str) -> Option<BayerPattern> {
+    match first_row {
+        "RG" => Some(BayerPattern::Rggb),
+        "BG" => Some(BayerPattern::Bggr),
+        "GR" => Some(BayerPattern::Grbg),
+        "GB" => Some(BayerPattern::Gbrg),
+        _ => None,
     }
 }
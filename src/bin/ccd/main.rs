@@ -1,56 +1,315 @@
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
-
+use asi_rs::config::{Config, Daemon};
+use asi_rs::discovery::{self, Announcement, DISCOVER_PATH};
+use asi_rs::presence::{DeviceStatus, Presence};
+use asi_rs::reply::{self, ErrorCode, Reply, ReplyError, SimpleRequest, REPLY_ACTION};
+use asi_rs::state::{CHANGES_ACTION, SNAPSHOT_ACTION};
+use asi_rs::topics::{Handler, TopicRouter};
+use asi_rs::transfer::{self, AckTracker, FrameStore, ResendRequest, RetryPolicy, RESEND_ACTION};
 use env_logger::Env;
-use log::{debug, info};
-use rumqttc::{AsyncClient, MqttOptions, QoS};
+use log::{debug, error, info, warn};
+use rumqttc::{AsyncClient, QoS, SubscribeFilter};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::time::Duration;
 use tokio::signal;
+use tokio::sync::mpsc::{self, UnboundedSender};
 use tokio::task;
+use tokio::time::Sleep;
 use uuid::Uuid;
 
+pub mod actor;
 pub mod ccd;
+pub mod metadata;
+pub mod output;
+pub mod request;
+pub mod schedule;
+use actor::{CameraCommand, CameraEvent, CameraHandle};
 use ccd::utils;
 use ccd::AsiCamera;
-use std::time::Instant;
+use metadata::{MetadataUpdate, METADATA_ACTION, METADATA_PATH};
+use output::{RECORDING_ACTION, SAVED_ACTION, STATS_ACTION};
+use request::{ExposureRequest, RecordRequest, UpdateRequest, VideoRequest};
+use schedule::PollSchedule;
 
 use rumqttc::Event::{Incoming, Outgoing};
-use rumqttc::Packet::Publish;
+use rumqttc::Packet::{ConnAck, PubAck, Publish};
+
+/// Bounds of the delay before polling a broken connection again, it doubles
+/// after every failure and is reset once the connection works
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Topic action where the previews of a running video capture are published
+const VIDEO_PREVIEW_ACTION: &str = "video_preview";
 
-#[derive(Default, Clone)]
 struct AsiCcd {
-    devices: Vec<Arc<RwLock<AsiCamera>>>,
+    devices: Vec<CameraHandle>,
+    /// Published on the discovery topic, they don't change while running
+    announcements: Vec<Announcement>,
+    /// Used to reply to commands rejected before reaching the camera
+    events: UnboundedSender<(Uuid, CameraEvent)>,
+    /// Used to resend the chunks of the frames
+    client: AsyncClient,
+    acks: AckTracker,
+    router: TopicRouter,
+    retry: RetryPolicy,
 }
 
 impl AsiCcd {
-    fn new() -> Self {
+    fn new(
+        config: &Config,
+        client: AsyncClient,
+        acks: AckTracker,
+        events: UnboundedSender<(Uuid, CameraEvent)>,
+    ) -> Self {
         let found = utils::look_for_devices();
-        let mut devices: Vec<Arc<RwLock<AsiCamera>>> = Vec::with_capacity(found as usize);
+        let mut devices: Vec<CameraHandle> = Vec::with_capacity(found as usize);
+
+        let mut announcements = Vec::with_capacity(found as usize);
 
         for idx in 0..found {
-            let device = Arc::new(RwLock::new(AsiCamera::new(idx)));
+            let camera = AsiCamera::new(idx, PollSchedule::from_config(&config.polling));
+            announcements.push(camera.announcement());
+            let device =
+                CameraHandle::spawn(camera, config, client.clone(), acks.clone(), events.clone());
             devices.push(device)
         }
 
-        Self { devices }
+        Self {
+            devices,
+            announcements,
+            events,
+            client,
+            acks,
+            router: config.mqtt.router(),
+            retry: config.transfer.retry_policy(),
+        }
+    }
+
+    fn reply(&self, id: Uuid, reply: Reply) {
+        if self.events.send((id, CameraEvent::Reply(reply))).is_err() {
+            error!("Unable to reply to a command for {}", id);
+        }
+    }
+
+    fn device(&self, id: &str) -> Option<&CameraHandle> {
+        self.devices.iter().find(|d| d.id.to_string() == id)
+    }
+
+    /// Apply the metadata published on `metadata` to every camera, invalid
+    /// payloads concern no device in particular and are only logged
+    fn broadcast_metadata(&self, payload: &[u8]) {
+        match MetadataUpdate::from_payload(payload) {
+            Ok(update) => {
+                for device in &self.devices {
+                    device.send(CameraCommand::Metadata(update.clone()));
+                }
+            }
+            Err(e) => warn!("Invalid metadata: {}", e),
+        }
+    }
+
+    /// Publish again the chunks of a frame that a client missed
+    fn resend(&self, id: Uuid, frames: &FrameStore, request: ResendRequest) {
+        let action = CcdAction::Resend.as_str();
+        let Some(frame) = frames.get(request.transfer_id) else {
+            let e = ReplyError::invalid("transfer_id", "unknown or expired transfer");
+            self.reply(id, Reply::rejected(action, request.request_id, e));
+            return;
+        };
+
+        if let Some(index) = request.chunks.iter().find(|i| **i >= frame.header.chunks) {
+            let e = ReplyError::invalid(
+                "chunks",
+                format!(
+                    "{} out of range, the frame has {} chunks",
+                    index, frame.header.chunks
+                ),
+            );
+            self.reply(id, Reply::rejected(action, request.request_id, e));
+            return;
+        }
+
+        self.reply(id, Reply::accepted(action, request.request_id.clone()));
+        let client = self.client.clone();
+        let acks = self.acks.clone();
+        let router = self.router.clone();
+        let events = self.events.clone();
+        let retry = self.retry;
+        task::spawn(async move {
+            let chunks = &request.chunks;
+            let result = transfer::resend(&client, &acks, &router, id, &frame, chunks, retry)
+                .await
+                .map_err(|e| ReplyError::new(ErrorCode::TransferFailed, e.to_string()));
+            let reply = Reply::outcome(action, request.request_id, result);
+            if events.send((id, CameraEvent::Reply(reply))).is_err() {
+                error!("Unable to reply to a resend for {}", id);
+            }
+        });
+    }
+}
+
+/// Commands accepted on `devices/{id}/{action}`
+#[derive(Debug, Clone, Copy)]
+enum CcdAction {
+    Expose,
+    Update,
+    Abort,
+    Video,
+    Snapshot,
+    Resend,
+    Record,
+    StopRecording,
+    Metadata,
+}
+
+impl CcdAction {
+    const ALL: [CcdAction; 9] = [
+        CcdAction::Expose,
+        CcdAction::Update,
+        CcdAction::Abort,
+        CcdAction::Video,
+        CcdAction::Snapshot,
+        CcdAction::Resend,
+        CcdAction::Record,
+        CcdAction::StopRecording,
+        CcdAction::Metadata,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            CcdAction::Expose => "expose",
+            CcdAction::Update => "update",
+            CcdAction::Abort => "abort",
+            CcdAction::Video => "video",
+            CcdAction::Snapshot => SNAPSHOT_ACTION,
+            CcdAction::Resend => RESEND_ACTION,
+            CcdAction::Record => "record",
+            CcdAction::StopRecording => "stop_recording",
+            CcdAction::Metadata => METADATA_ACTION,
+        }
     }
 }
 
-async fn subscribe(client: AsyncClient, ids: &Vec<Uuid>) {
-    for id in ids {
-        client
-            .subscribe(
-                format!("{}", format_args!("devices/{}/expose", &id)),
-                QoS::AtLeastOnce,
-            )
-            .await
-            .unwrap();
-        client
-            .subscribe(
-                format!("{}", format_args!("devices/{}/update", &id)),
-                QoS::AtLeastOnce,
-            )
-            .await
-            .unwrap();
+impl FromStr for CcdAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CcdAction::ALL
+            .into_iter()
+            .find(|a| a.as_str() == s)
+            .ok_or(())
+    }
+}
+
+impl Handler for AsiCcd {
+    type Action = CcdAction;
+
+    fn handle(&mut self, device_id: &str, action: CcdAction, payload: &[u8]) {
+        let Some(device) = self.device(device_id) else {
+            warn!("Unknown device: `{}`", device_id);
+            return;
+        };
+
+        match action {
+            CcdAction::Update => match UpdateRequest::from_payload(payload) {
+                Ok(request) => {
+                    info!("received update for {}: {:?}", device_id, request);
+                    device.send(CameraCommand::Update(request));
+                }
+                Err(e) => {
+                    warn!("Invalid update for {}: {}", device_id, e);
+                    let reply = Reply::rejected(action.as_str(), reply::request_id(payload), e);
+                    self.reply(device.id, reply);
+                }
+            },
+            CcdAction::Expose => match ExposureRequest::from_payload(payload) {
+                Ok(request) => {
+                    info!("Exposure requested for {}: {:?}", device_id, request);
+                    device.send(CameraCommand::Expose(request));
+                }
+                Err(e) => {
+                    warn!("Invalid exposure request for {}: {}", device_id, e);
+                    let reply = Reply::rejected(action.as_str(), reply::request_id(payload), e);
+                    self.reply(device.id, reply);
+                }
+            },
+            CcdAction::Abort => match SimpleRequest::from_payload(payload) {
+                Ok(request) => device.send(CameraCommand::Abort(request.request_id)),
+                Err(e) => {
+                    let reply = Reply::rejected(action.as_str(), reply::request_id(payload), e);
+                    self.reply(device.id, reply);
+                }
+            },
+            CcdAction::Snapshot => device.send(CameraCommand::Snapshot),
+            CcdAction::Resend => match ResendRequest::from_payload(payload) {
+                Ok(request) => self.resend(device.id, &device.frames, request),
+                Err(e) => {
+                    warn!("Invalid resend request for {}: {}", device_id, e);
+                    let reply = Reply::rejected(action.as_str(), reply::request_id(payload), e);
+                    self.reply(device.id, reply);
+                }
+            },
+            CcdAction::Record => match RecordRequest::from_payload(payload) {
+                Ok(request) => {
+                    info!("Recording requested for {}: {:?}", device_id, request);
+                    device.send(CameraCommand::Record(request));
+                }
+                Err(e) => {
+                    warn!("Invalid record request for {}: {}", device_id, e);
+                    let reply = Reply::rejected(action.as_str(), reply::request_id(payload), e);
+                    self.reply(device.id, reply);
+                }
+            },
+            CcdAction::StopRecording => match SimpleRequest::from_payload(payload) {
+                Ok(request) => device.send(CameraCommand::StopRecording(request.request_id)),
+                Err(e) => {
+                    let reply = Reply::rejected(action.as_str(), reply::request_id(payload), e);
+                    self.reply(device.id, reply);
+                }
+            },
+            CcdAction::Metadata => match MetadataUpdate::from_payload(payload) {
+                Ok(update) => device.send(CameraCommand::Metadata(update)),
+                Err(e) => {
+                    warn!("Invalid metadata for {}: {}", device_id, e);
+                    let reply = Reply::rejected(action.as_str(), reply::request_id(payload), e);
+                    self.reply(device.id, reply);
+                }
+            },
+            CcdAction::Video => match VideoRequest::from_payload(payload) {
+                Ok(request) => device.send(CameraCommand::Video(request)),
+                Err(e) => {
+                    warn!("Invalid video request for {}: {}", device_id, e);
+                    let reply = Reply::rejected(action.as_str(), reply::request_id(payload), e);
+                    self.reply(device.id, reply);
+                }
+            },
+        }
+    }
+}
+
+/// Subscribe to the commands of every device and to the daemon wide `topics`,
+/// with a request per device and one for the daemon. The requests wait in the
+/// bounded queue of the client, which only the event loop drains, so this must
+/// run beside it rather than before it.
+async fn subscribe(client: AsyncClient, router: TopicRouter, ids: Vec<Uuid>, topics: Vec<String>) {
+    let filters = |topics: Vec<String>| {
+        topics
+            .into_iter()
+            .map(|t| SubscribeFilter::new(t, QoS::AtLeastOnce))
+            .collect::<Vec<_>>()
+    };
+
+    for id in &ids {
+        let topics = CcdAction::ALL.map(|action| router.device_action(id, action.as_str()));
+        if let Err(e) = client.subscribe_many(filters(topics.to_vec())).await {
+            error!("Unable to subscribe to the commands of {}: {}", id, e);
+        }
+    }
+
+    if let Err(e) = client.subscribe_many(filters(topics)).await {
+        error!("Unable to subscribe to the daemon topics: {}", e);
     }
 }
 
@@ -60,99 +319,259 @@ async fn main() {
     let env = Env::default().filter_or("LS_LOG_LEVEL", "info");
     env_logger::init_from_env(env);
 
-    let driver = AsiCcd::new();
-    let mut mqttoptions = MqttOptions::new("asi_ccd", "127.0.0.1", 1883);
-    mqttoptions.set_keep_alive(Duration::from_secs(5));
-    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+    let config = match Config::from_args(Daemon::Ccd) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(2);
+        }
+    };
 
-    let mut devices_id = Vec::with_capacity(driver.devices.len());
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    let mut options = config.mqtt.options(Daemon::Ccd.name());
+    let incoming = options.max_packet_size();
+    let largest = config.transfer.chunk_size.max(config.preview.max_bytes());
+    options.set_max_packet_size(incoming, transfer::packet_size(largest));
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    // Everything is published through the relay, for the transfers to know
+    // which PubAck acknowledges their messages
+    let acks = AckTracker::new();
+    let client = acks.relay(client);
+    let mut driver = AsiCcd::new(&config, client.clone(), acks.clone(), events_tx);
 
-    for d in &driver.devices {
-        devices_id.push(d.read().unwrap().id)
-    }
+    let router = config.mqtt.router();
+    let devices_id: Vec<Uuid> = driver.devices.iter().map(|d| d.id).collect();
 
-    subscribe(client.clone(), &devices_id).await;
+    let discover_topic = router.topic(DISCOVER_PATH);
+    let metadata_topic = router.topic(METADATA_PATH);
 
-    for d in &driver.devices {
-        let device = Arc::clone(d);
+    // Each device announces itself online on a dedicated connection, whose
+    // last will flips it offline if the daemon dies
+    let mut presences: Vec<Presence> = devices_id
+        .iter()
+        .map(|id| Presence::spawn(&config.mqtt, Daemon::Ccd.name(), id))
+        .collect();
+    let statuses: HashMap<Uuid, _> = devices_id
+        .iter()
+        .zip(&presences)
+        .map(|(id, p)| (*id, p.sender()))
+        .collect();
 
-        tokio::spawn(async move {
-            signal::ctrl_c().await.unwrap();
-            debug!("ctrl-c received!");
-            device.read().unwrap().close();
-            std::process::exit(0);
+    // Ask every camera to refresh its state periodically, the actor publishes
+    // the result through the events channel
+    let poll_interval = config.poll_interval(Daemon::Ccd);
+    for d in &driver.devices {
+        let commands = d.commands();
+        task::spawn(async move {
+            while commands.send(CameraCommand::Poll).is_ok() {
+                tokio::time::sleep(poll_interval).await;
+            }
         });
     }
 
+    // Video previews are superseded quickly, losing one is better than
+    // slowing down the whole stream
     for d in &driver.devices {
-        let device = Arc::clone(d);
-        let c = client.clone();
+        let (c, topic) = (
+            client.clone(),
+            router.device_action(d.id, VIDEO_PREVIEW_ACTION),
+        );
+        let mut video = d.video.clone();
         task::spawn(async move {
-            let d_id = device.read().unwrap().id;
-            loop {
-                let now = Instant::now();
-                device.write().unwrap().fetch_props();
-                let serialized = serde_json::to_string(&*device.read().unwrap()).unwrap();
-                c.publish(
-                    format!("{}", format_args!("devices/{}", &d_id)),
-                    QoS::AtLeastOnce,
-                    false,
-                    serialized,
-                )
-                .await
-                .unwrap();
-                let elapsed = now.elapsed();
-                debug!("Refreshed and publishing state took: {:.2?}", elapsed);
-                tokio::time::sleep(Duration::from_millis(2500)).await;
+            while video.changed().await.is_ok() {
+                let jpeg = video.borrow_and_update().clone();
+                if let Err(e) = c.publish(&topic, QoS::AtMostOnce, false, jpeg).await {
+                    error!("Unable to publish the video preview on {}: {}", topic, e);
+                }
             }
         });
     }
 
-    while let Ok(event) = eventloop.poll().await {
-        debug!("Received = {:?}", event);
-        match event {
-            Incoming(inc) => match inc {
-                Publish(data) => {
-                    // All topics are in the form of devices/{UUID}/{action} so let's
-                    // take advantage of this fact and avoid a string split
-                    match &data.topic[45..data.topic.len()] {
-                        "update" => {
-                            info!(
-                                "received message from topic: {}\nmessage: {:?}",
-                                &data.topic, &data.payload
-                            );
-                            let device = &data.topic[8..44];
-                            for d in &driver.devices {
-                                if device == &d.read().unwrap().id.to_string() {
-                                    d.write().unwrap().update_property("img_type", 1)
-                                }
-                            }
-                        }
-                        "expose" => {
-                            let device = &data.topic[8..44];
-                            info!("mqtt id: `{}`", &device);
-
-                            for d in &driver.devices {
-                                info!("device id: `{}`", &d.read().unwrap().id.to_string());
-                                if device == &d.read().unwrap().id.to_string() {
-                                    let device = Arc::clone(d);
-                                    let _c = client.clone();
-                                    task::spawn_blocking(move || {
-                                        utils::capturing::expose(
-                                            2.0,
-                                            libasi::camera::ASI_IMG_TYPE_ASI_IMG_RGB24,
-                                            device,
-                                        );
-                                        info!("Task ended");
-                                    });
-                                }
-                            }
-                        }
-                        _ => (),
+    // Publish whatever the camera actors produce
+    let c = client.clone();
+    let r = router.clone();
+    task::spawn(async move {
+        while let Some((id, event)) = events_rx.recv().await {
+            if let CameraEvent::Reply(reply) = &event
+                && let Some(status) = DeviceStatus::after(reply)
+                && let Some(sender) = statuses.get(&id)
+            {
+                sender.set(status);
+            }
+
+            let result = match event {
+                CameraEvent::State(serialized) => {
+                    c.publish(r.device(id), QoS::AtLeastOnce, true, serialized)
+                        .await
+                }
+                CameraEvent::Changes(changes) => {
+                    c.publish(
+                        r.device_action(id, CHANGES_ACTION),
+                        QoS::AtLeastOnce,
+                        false,
+                        changes,
+                    )
+                    .await
+                }
+                CameraEvent::Preview(jpeg) => {
+                    c.publish(
+                        r.device_action(id, "preview"),
+                        QoS::AtLeastOnce,
+                        false,
+                        jpeg,
+                    )
+                    .await
+                }
+                CameraEvent::Saved(saved) => match serde_json::to_string(&saved) {
+                    Ok(payload) => {
+                        c.publish(
+                            r.device_action(id, SAVED_ACTION),
+                            QoS::AtLeastOnce,
+                            false,
+                            payload,
+                        )
+                        .await
+                    }
+                    Err(e) => {
+                        error!("Unable to serialize saved frame for {}: {}", &id, e);
+                        continue;
                     }
+                },
+                CameraEvent::Stats(stats) => match serde_json::to_string(&stats) {
+                    Ok(payload) => {
+                        c.publish(
+                            r.device_action(id, STATS_ACTION),
+                            QoS::AtLeastOnce,
+                            false,
+                            payload,
+                        )
+                        .await
+                    }
+                    Err(e) => {
+                        error!("Unable to serialize stats for {}: {}", &id, e);
+                        continue;
+                    }
+                },
+                CameraEvent::Recorded(summary) => match serde_json::to_string(&summary) {
+                    Ok(payload) => {
+                        c.publish(
+                            r.device_action(id, RECORDING_ACTION),
+                            QoS::AtLeastOnce,
+                            false,
+                            payload,
+                        )
+                        .await
+                    }
+                    Err(e) => {
+                        error!("Unable to serialize recording for {}: {}", &id, e);
+                        continue;
+                    }
+                },
+                CameraEvent::Reply(reply) => match serde_json::to_string(&reply) {
+                    Ok(payload) => {
+                        c.publish(
+                            r.device_action(id, REPLY_ACTION),
+                            QoS::AtLeastOnce,
+                            false,
+                            payload,
+                        )
+                        .await
+                    }
+                    Err(e) => {
+                        error!("Unable to serialize reply for {}: {}", &id, e);
+                        continue;
+                    }
+                },
+            };
+
+            if let Err(e) = result {
+                error!("Unable to publish event for {}: {}", &id, e);
+            }
+        }
+    });
+
+    let announce = {
+        let c = client.clone();
+        let r = router.clone();
+        let announcements = driver.announcements.clone();
+        move || {
+            let (c, r, announcements) = (c.clone(), r.clone(), announcements.clone());
+            task::spawn(async move { discovery::announce(&c, &r, &announcements).await });
+        }
+    };
+    announce();
+
+    let ctrl_c = signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    // Joins the camera threads on shutdown, the connection keeps being polled
+    // meanwhile so that a frame being sent by an actor can complete
+    let mut stopping: Option<task::JoinHandle<()>> = None;
+    let mut reconnect_delay = MIN_RECONNECT_DELAY;
+    // Wait before polling a broken connection again, raced against ctrl-c
+    let mut backoff: Option<Pin<Box<Sleep>>> = None;
+
+    loop {
+        let event = tokio::select! {
+            _ = &mut ctrl_c, if stopping.is_none() => {
+                debug!("ctrl-c received!");
+                for p in presences.drain(..) {
+                    p.shutdown().await;
+                }
+                let mut devices = std::mem::take(&mut driver.devices);
+                stopping = Some(task::spawn_blocking(move || {
+                    for d in devices.iter_mut() {
+                        d.shutdown();
+                    }
+                }));
+                continue;
+            }
+            _ = async { stopping.as_mut().unwrap().await }, if stopping.is_some() => {
+                std::process::exit(0);
+            }
+            _ = async { backoff.as_mut().unwrap().await }, if backoff.is_some() => {
+                backoff = None;
+                continue;
+            }
+            event = eventloop.poll(), if backoff.is_none() => match event {
+                Ok(event) => {
+                    reconnect_delay = MIN_RECONNECT_DELAY;
+                    event
+                }
+                // rumqttc reconnects on the next poll
+                Err(e) => {
+                    error!(
+                        "MQTT connection error: {}, retrying in {:?}",
+                        e, reconnect_delay
+                    );
+                    backoff = Some(Box::pin(tokio::time::sleep(reconnect_delay)));
+                    reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+                    continue;
                 }
-                _ => debug!("Incoming event: {:?}", inc),
             },
+        };
+
+        debug!("Received = {:?}", event);
+        match event {
+            // The session is clean, the subscriptions are made again on every
+            // connection
+            Incoming(ConnAck(_)) => {
+                let topics = vec![discover_topic.clone(), metadata_topic.clone()];
+                let ids = devices_id.clone();
+                task::spawn(subscribe(client.clone(), router.clone(), ids, topics));
+            }
+            Incoming(Publish(data)) if data.topic == discover_topic => {
+                info!("Discovery requested");
+                announce();
+            }
+            Incoming(Publish(data)) if data.topic == metadata_topic => {
+                driver.broadcast_metadata(&data.payload);
+            }
+            Incoming(Publish(data)) => {
+                router.dispatch(&mut driver, &data.topic, &data.payload);
+            }
+            Incoming(PubAck(ack)) => acks.acked(ack.pkid),
+            Incoming(inc) => debug!("Incoming event: {:?}", inc),
+            Outgoing(rumqttc::Outgoing::Publish(pkid)) => acks.outgoing(pkid),
             Outgoing(out) => {
                 debug!("Outgoing MQTT event: {:?}", out);
             }
@@ -0,0 +1,198 @@
+//! What the camera can't know about its frames: where and with what they are
+//! taken, and what is being imaged.
+//!
+//! The values start from the `[observatory]` section of the configuration and
+//! are changed with JSON payloads published on `devices/{id}/metadata`, for a
+//! single camera, or on `metadata`, for all of them, e.g. by a mount driver
+//! publishing its coordinates:
+//!
+//! ```json
+//! {"object": "M31", "ra": 10.6847, "dec": 41.2687}
+//! ```
+//!
+//! Missing fields are kept, `null` clears them. The values end up in the
+//! headers of the saved frames.
+
+use asi_rs::config::ObservatoryConfig;
+use asi_rs::fits::Header;
+use asi_rs::reply::{ErrorCode, ReplyError};
+use serde::{Deserialize, Deserializer};
+
+/// Topic action updating the metadata of a camera
+pub const METADATA_ACTION: &str = "metadata";
+
+/// Topic, under the prefix, updating the metadata of every camera
+pub const METADATA_PATH: &str = "metadata";
+
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub observer: Option<String>,
+    pub telescope: Option<String>,
+    /// Degrees, positive to the north
+    pub site_latitude: Option<f64>,
+    /// Degrees, positive to the east
+    pub site_longitude: Option<f64>,
+    /// Meters above the sea level
+    pub site_elevation: Option<f64>,
+    /// Millimeters
+    pub focal_length: Option<f64>,
+    /// Millimeters
+    pub aperture: Option<f64>,
+    /// Name of the object being imaged
+    pub object: Option<String>,
+    /// J2000 right ascension of the center of the frame, in degrees
+    pub ra: Option<f64>,
+    /// J2000 declination of the center of the frame, in degrees
+    pub dec: Option<f64>,
+}
+
+impl From<&ObservatoryConfig> for Metadata {
+    fn from(config: &ObservatoryConfig) -> Self {
+        Self {
+            observer: config.observer.clone(),
+            telescope: config.telescope.clone(),
+            site_latitude: config.site_latitude,
+            site_longitude: config.site_longitude,
+            site_elevation: config.site_elevation,
+            focal_length: config.focal_length,
+            aperture: config.aperture,
+            ..Default::default()
+        }
+    }
+}
+
+impl Metadata {
+    pub fn apply(&mut self, update: MetadataUpdate) {
+        fn set<T>(field: &mut Option<T>, value: Option<Option<T>>) {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+
+        set(&mut self.observer, update.observer);
+        set(&mut self.telescope, update.telescope);
+        set(&mut self.site_latitude, update.site_latitude);
+        set(&mut self.site_longitude, update.site_longitude);
+        set(&mut self.site_elevation, update.site_elevation);
+        set(&mut self.focal_length, update.focal_length);
+        set(&mut self.aperture, update.aperture);
+        set(&mut self.object, update.object);
+        set(&mut self.ra, update.ra);
+        set(&mut self.dec, update.dec);
+    }
+
+    /// Add the keywords to `header`, an `OBJECT` already set by the exposure
+    /// request is kept
+    pub fn write_header(&self, header: &mut Header) {
+        if let Some(observer) = &self.observer {
+            header.set("OBSERVER", observer.as_str(), "observer name");
+        }
+        if let Some(telescope) = &self.telescope {
+            header.set("TELESCOP", telescope.as_str(), "telescope name");
+        }
+        if let Some(focal_length) = self.focal_length {
+            header.set("FOCALLEN", focal_length, "focal length in mm");
+        }
+        if let Some(aperture) = self.aperture {
+            header.set("APTDIA", aperture, "aperture diameter in mm");
+        }
+        if let Some(latitude) = self.site_latitude {
+            header.set("SITELAT", latitude, "site latitude in degrees");
+        }
+        if let Some(longitude) = self.site_longitude {
+            header.set("SITELONG", longitude, "site longitude in degrees east");
+        }
+        if let Some(elevation) = self.site_elevation {
+            header.set("SITEELEV", elevation, "site elevation in m");
+        }
+        if header.get("OBJECT").is_none()
+            && let Some(object) = &self.object
+        {
+            header.set("OBJECT", object.as_str(), "name of the object");
+        }
+        if let (Some(ra), Some(dec)) = (self.ra, self.dec) {
+            header.set("RA", ra, "J2000 right ascension in degrees");
+            header.set("DEC", dec, "J2000 declination in degrees");
+            header.set("EQUINOX", 2000.0, "equinox of the coordinates");
+        }
+    }
+}
+
+/// Payload of `devices/{id}/metadata` and `metadata`, `None` leaves the
+/// value untouched and `Some(None)` clears it
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetadataUpdate {
+    pub request_id: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    pub observer: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub telescope: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub site_latitude: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "present")]
+    pub site_longitude: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "present")]
+    pub site_elevation: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "present")]
+    pub focal_length: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "present")]
+    pub aperture: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "present")]
+    pub object: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub ra: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "present")]
+    pub dec: Option<Option<f64>>,
+}
+
+/// Tell a `null` field, `Some(None)`, from a missing one, `None`
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl MetadataUpdate {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ReplyError> {
+        let update: Self = serde_json::from_slice(payload)
+            .map_err(|e| ReplyError::new(ErrorCode::InvalidPayload, e.to_string()))?;
+        update.check()?;
+        Ok(update)
+    }
+
+    fn check(&self) -> Result<(), ReplyError> {
+        let ranges = [
+            ("site_latitude", self.site_latitude, -90.0, 90.0),
+            ("site_longitude", self.site_longitude, -180.0, 180.0),
+            ("ra", self.ra, 0.0, 360.0),
+            ("dec", self.dec, -90.0, 90.0),
+        ];
+        for (field, value, min, max) in ranges {
+            if let Some(Some(v)) = value
+                && !(min..=max).contains(&v)
+            {
+                return Err(ReplyError::invalid(
+                    field,
+                    format!("must be between {} and {} degrees", min, max),
+                ));
+            }
+        }
+
+        let lengths = [
+            ("focal_length", self.focal_length),
+            ("aperture", self.aperture),
+        ];
+        for (field, length) in lengths {
+            if let Some(Some(l)) = length
+                && !(l > 0.0 && l.is_finite())
+            {
+                return Err(ReplyError::invalid(field, "must be positive"));
+            }
+        }
+
+        Ok(())
+    }
+}
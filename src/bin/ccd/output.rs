@@ -0,0 +1,56 @@
+//! Frames written to the output directory.
+//!
+//! Every saved frame is announced on `<prefix>/devices/<id>/saved`, so that
+//! the clients sharing the disk with the daemon can pick the file up instead
+//! of downloading the frame. Video recordings are announced on
+//! `<prefix>/devices/<id>/recording` once they are over.
+//!
+//! The statistics of every exposure, saved or not, are published on
+//! `<prefix>/devices/<id>/stats`.
+
+use asi_rs::config::FileFormat;
+use asi_rs::stats::ChannelStats;
+use serde::Serialize;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Topic action where the saved frames are announced
+pub const SAVED_ACTION: &str = "saved";
+
+/// Topic action where the finished recordings are announced
+pub const RECORDING_ACTION: &str = "recording";
+
+/// Topic action where the statistics of the frames are published
+pub const STATS_ACTION: &str = "stats";
+
+/// Payload of `devices/{id}/saved`
+#[derive(Debug, Clone, Serialize)]
+pub struct SavedFrame {
+    pub request_id: Option<String>,
+    /// The transfer carrying the same frame over MQTT
+    pub transfer_id: Uuid,
+    pub path: PathBuf,
+    pub format: FileFormat,
+}
+
+/// Payload of `devices/{id}/recording`
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordingSummary {
+    pub request_id: Option<String>,
+    pub path: PathBuf,
+    pub frames: u32,
+    /// Frames the SDK dropped during the recording, they are missing from
+    /// the file
+    pub dropped: u32,
+    /// Length of the recording in seconds
+    pub duration: f64,
+}
+
+/// Payload of `devices/{id}/stats`
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameStats {
+    pub request_id: Option<String>,
+    /// The transfer carrying the frame
+    pub transfer_id: Uuid,
+    pub channels: Vec<ChannelStats>,
+}
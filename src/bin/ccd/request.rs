@@ -0,0 +1,304 @@
+//! Payloads accepted by the camera commands
+
+use asi_rs::config::FileFormat;
+use asi_rs::fits::PixelFormat;
+use asi_rs::naming::Template;
+use asi_rs::reply::{ErrorCode, ReplyError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Image formats understood by the ASI SDK
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ImageType {
+    Raw8,
+    Rgb24,
+    Raw16,
+    Y8,
+}
+
+impl ImageType {
+    pub fn as_asi(&self) -> i32 {
+        match self {
+            ImageType::Raw8 => libasi::camera::ASI_IMG_TYPE_ASI_IMG_RAW8,
+            ImageType::Rgb24 => libasi::camera::ASI_IMG_TYPE_ASI_IMG_RGB24,
+            ImageType::Raw16 => libasi::camera::ASI_IMG_TYPE_ASI_IMG_RAW16,
+            ImageType::Y8 => libasi::camera::ASI_IMG_TYPE_ASI_IMG_Y8,
+        }
+    }
+
+    pub fn from_asi(img_type: i32) -> Option<Self> {
+        match img_type {
+            libasi::camera::ASI_IMG_TYPE_ASI_IMG_RAW8 => Some(ImageType::Raw8),
+            libasi::camera::ASI_IMG_TYPE_ASI_IMG_RGB24 => Some(ImageType::Rgb24),
+            libasi::camera::ASI_IMG_TYPE_ASI_IMG_RAW16 => Some(ImageType::Raw16),
+            libasi::camera::ASI_IMG_TYPE_ASI_IMG_Y8 => Some(ImageType::Y8),
+            _ => None,
+        }
+    }
+
+    /// Layout of the frames downloaded with this type
+    pub fn pixel_format(&self) -> PixelFormat {
+        match self {
+            ImageType::Raw8 | ImageType::Y8 => PixelFormat::Mono8,
+            ImageType::Raw16 => PixelFormat::Mono16,
+            ImageType::Rgb24 => PixelFormat::Bgr24,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameType {
+    #[default]
+    Light,
+    Dark,
+    Flat,
+    Bias,
+}
+
+impl FrameType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FrameType::Light => "light",
+            FrameType::Dark => "dark",
+            FrameType::Flat => "flat",
+            FrameType::Bias => "bias",
+        }
+    }
+
+    /// Value of the `IMAGETYP` keyword
+    pub fn fits_name(&self) -> &'static str {
+        match self {
+            FrameType::Light => "Light Frame",
+            FrameType::Dark => "Dark Frame",
+            FrameType::Flat => "Flat Frame",
+            FrameType::Bias => "Bias Frame",
+        }
+    }
+}
+
+/// Region of interest, expressed in binned pixels
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Roi {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+/// Payload of `devices/{id}/expose`
+///
+/// ```json
+/// {
+///   "request_id": "42",
+///   "exposure": 2.5,
+///   "image_type": "RAW16",
+///   "bin": 2,
+///   "roi": {"x": 0, "y": 0, "width": 1024, "height": 768},
+///   "gain": 120,
+///   "offset": 30,
+///   "frame_type": "light",
+///   "target": "M31",
+///   "filter": "Ha",
+///   "save": true,
+///   "format": "xisf",
+///   "template": "{target}/{filter}/{frame_type}_{seq:04}"
+/// }
+/// ```
+///
+/// Only `exposure` is mandatory, missing settings keep the current value of
+/// the camera, except `bin` which defaults to 1, `roi` which defaults to
+/// the full frame, `save`, `format` and `template` which default to
+/// `output.save`, `output.format` and `output.template`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExposureRequest {
+    pub request_id: Option<String>,
+    /// Exposure length in seconds
+    pub exposure: f64,
+    pub image_type: Option<ImageType>,
+    #[serde(default = "default_bin")]
+    pub bin: i32,
+    pub roi: Option<Roi>,
+    pub gain: Option<i64>,
+    pub offset: Option<i64>,
+    #[serde(default)]
+    pub frame_type: FrameType,
+    /// Object being imaged, written in the headers and used in the file name
+    pub target: Option<String>,
+    /// Filter in front of the sensor, the camera doesn't know about it
+    pub filter: Option<String>,
+    /// Write the frame to the output directory
+    pub save: Option<bool>,
+    /// Format of the saved frame
+    pub format: Option<FileFormat>,
+    /// Path of the saved frame in the output directory
+    pub template: Option<Template>,
+}
+
+fn default_bin() -> i32 {
+    1
+}
+
+impl ExposureRequest {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ReplyError> {
+        serde_json::from_slice(payload)
+            .map_err(|e| ReplyError::new(ErrorCode::InvalidPayload, e.to_string()))
+    }
+
+    /// Exposure length in microseconds, as expected by the SDK
+    pub fn micros(&self) -> i64 {
+        (self.exposure * 1_000_000_f64) as i64
+    }
+}
+
+/// Payload of `devices/{id}/record`
+///
+/// ```json
+/// {
+///   "request_id": "50",
+///   "frames": 5000,
+///   "duration": 120,
+///   "target": "Jupiter"
+/// }
+/// ```
+///
+/// The recording stops when either limit is reached, at least one of them is
+/// required. `duration` is in seconds. `target`, `filter` and `template` name
+/// the file as they do for the exposures.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordRequest {
+    pub request_id: Option<String>,
+    pub frames: Option<u32>,
+    pub duration: Option<f64>,
+    pub target: Option<String>,
+    pub filter: Option<String>,
+    pub template: Option<Template>,
+}
+
+impl RecordRequest {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ReplyError> {
+        serde_json::from_slice(payload)
+            .map_err(|e| ReplyError::new(ErrorCode::InvalidPayload, e.to_string()))
+    }
+
+    pub fn check(&self) -> Result<(), ReplyError> {
+        if self.frames.is_none() && self.duration.is_none() {
+            return Err(ReplyError::new(
+                ErrorCode::InvalidValue,
+                "either frames or duration is required",
+            ));
+        }
+        if self.frames == Some(0) {
+            return Err(ReplyError::invalid("frames", "must be at least 1"));
+        }
+        if let Some(d) = self.duration
+            && !(d > 0.0 && Duration::try_from_secs_f64(d).is_ok())
+        {
+            return Err(ReplyError::invalid("duration", "must be positive"));
+        }
+
+        Ok(())
+    }
+
+    pub fn max_duration(&self) -> Option<Duration> {
+        self.duration
+            .and_then(|d| Duration::try_from_secs_f64(d).ok())
+    }
+}
+
+/// What a `devices/{id}/video` request does to the video capture
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoCapture {
+    Start,
+    Stop,
+}
+
+/// Payload of `devices/{id}/video`:
+///
+/// ```json
+/// {"request_id": "52", "capture": "start"}
+/// ```
+///
+/// Starting a running capture or stopping a stopped one does nothing.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VideoRequest {
+    pub request_id: Option<String>,
+    pub capture: VideoCapture,
+}
+
+impl VideoRequest {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ReplyError> {
+        serde_json::from_slice(payload)
+            .map_err(|e| ReplyError::new(ErrorCode::InvalidPayload, e.to_string()))
+    }
+}
+
+/// New value of a camera control, either the bare value or an object to
+/// also toggle the automatic adjustment, e.g. `{"auto": true}`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum ControlValue {
+    Value(i64),
+    Auto { value: Option<i64>, auto: bool },
+}
+
+impl ControlValue {
+    pub fn value(&self) -> Option<i64> {
+        match self {
+            ControlValue::Value(v) => Some(*v),
+            ControlValue::Auto { value, .. } => *value,
+        }
+    }
+
+    pub fn auto(&self) -> bool {
+        match self {
+            ControlValue::Value(_) => false,
+            ControlValue::Auto { auto, .. } => *auto,
+        }
+    }
+}
+
+/// Payload of `devices/{id}/update`
+///
+/// ```json
+/// {
+///   "request_id": "43",
+///   "image_type": "RAW16",
+///   "bin": 2,
+///   "roi": {"x": 0, "y": 0, "width": 1024, "height": 768},
+///   "gain": 120,
+///   "exposure": {"auto": true},
+///   "target_temp": -10
+/// }
+/// ```
+///
+/// Every other key is the name of a camera control, as published in the
+/// state of the device.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpdateRequest {
+    pub request_id: Option<String>,
+    pub image_type: Option<ImageType>,
+    pub bin: Option<i32>,
+    pub roi: Option<Roi>,
+    #[serde(flatten)]
+    pub controls: BTreeMap<String, ControlValue>,
+}
+
+impl UpdateRequest {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ReplyError> {
+        serde_json::from_slice(payload)
+            .map_err(|e| ReplyError::new(ErrorCode::InvalidPayload, e.to_string()))
+    }
+
+    /// Whether the ROI format of the camera has to be changed
+    pub fn changes_format(&self) -> bool {
+        self.image_type.is_some() || self.bin.is_some() || self.roi.is_some()
+    }
+}
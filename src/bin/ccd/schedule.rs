@@ -0,0 +1,63 @@
+//! Decide which controls are read from the camera on every poll.
+//!
+//! Most controls (gamma, white balance, flip, bandwidth...) only change when
+//! they are written, they are read back right after a write and never polled.
+//! Only the temperature, the cooler power and the controls in auto mode are
+//! read periodically, each at its own rate. Rates are rounded up to the poll
+//! interval, as the schedule is only checked when the camera is polled.
+
+use asi_rs::config::PollingConfig;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Controls changing on their own, regardless of the auto mode
+const VOLATILE_CONTROLS: [i32; 2] = [
+    libasi::camera::ASI_CONTROL_TYPE_ASI_TEMPERATURE as i32,
+    libasi::camera::ASI_CONTROL_TYPE_ASI_COOLER_POWER_PERC as i32,
+];
+
+#[derive(Debug)]
+pub struct PollSchedule {
+    temperature_interval: Duration,
+    auto_interval: Duration,
+    /// When each control type was last read
+    last_read: HashMap<i32, Instant>,
+}
+
+impl PollSchedule {
+    pub fn new(temperature_interval: Duration, auto_interval: Duration) -> Self {
+        Self {
+            temperature_interval,
+            auto_interval,
+            last_read: HashMap::new(),
+        }
+    }
+
+    pub fn from_config(polling: &PollingConfig) -> Self {
+        Self::new(
+            Duration::from_millis(polling.ccd_temperature_interval_ms),
+            Duration::from_millis(polling.ccd_auto_interval_ms),
+        )
+    }
+
+    /// Whether `control_type` should be read at `now`, `auto` tells if the
+    /// camera is adjusting it by itself
+    pub fn is_due(&self, control_type: i32, auto: bool, now: Instant) -> bool {
+        let interval = if VOLATILE_CONTROLS.contains(&control_type) {
+            self.temperature_interval
+        } else if auto {
+            self.auto_interval
+        } else {
+            return false;
+        };
+
+        self.last_read
+            .get(&control_type)
+            .is_none_or(|t| now.duration_since(*t) >= interval)
+    }
+
+    /// Record that `control_type` has been read at `now`
+    pub fn read(&mut self, control_type: i32, now: Instant) {
+        self.last_read.insert(control_type, now);
+    }
+}
@@ -0,0 +1,423 @@
+use crate::efw::EfwDevice;
+use crate::names::{NamesFile, WheelNames};
+use crate::request::UpdateRequest;
+
+use asi_rs::config::MovePolicy;
+use asi_rs::reply::{ErrorCode, Reply, ReplyError};
+use asi_rs::state::{StateTracker, StateUpdate};
+use log::{debug, error, info, warn};
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use uuid::Uuid;
+
+/// How often the wheel is checked while it is moving
+const MOTION_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Commands understood by a filter wheel actor
+#[derive(Debug)]
+pub enum EfwCommand {
+    /// Refresh the properties from the device and publish the new state
+    Poll,
+    /// Publish the full state, even if nothing changed
+    Snapshot,
+    Move {
+        slot: i32,
+        request_id: Option<String>,
+    },
+    Calibrate {
+        request_id: Option<String>,
+    },
+    /// Apply the properties of `devices/{id}/update`
+    Update(UpdateRequest),
+    /// Close the wheel and stop the actor thread
+    Shutdown,
+}
+
+/// Events emitted by a filter wheel actor, the MQTT layer is responsible
+/// to publish them.
+#[derive(Debug)]
+pub enum EfwEvent {
+    /// Serialized full state of the wheel
+    State(String),
+    /// Serialized properties that changed since the last publication
+    Changes(String),
+    MoveStarted {
+        from: i32,
+        to: i32,
+    },
+    MoveFinished {
+        slot: i32,
+    },
+    /// Outcome of a command, to be sent back to the client
+    Reply(Reply),
+}
+
+/// Handle used to talk to a filter wheel owned by a dedicated OS thread
+pub struct EfwHandle {
+    pub id: Uuid,
+    tx: Sender<EfwCommand>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EfwHandle {
+    /// Move `device` into its own thread and start processing commands.
+    /// Every event produced by the actor is sent to `events` together with
+    /// the id of the wheel, the names given to the wheel are saved in
+    /// `names`.
+    pub fn spawn(
+        device: EfwDevice,
+        policy: MovePolicy,
+        snapshot_interval: Duration,
+        names: NamesFile,
+        events: UnboundedSender<(Uuid, EfwEvent)>,
+    ) -> Self {
+        let id = device.id;
+        let (tx, rx) = mpsc::channel();
+
+        let thread = std::thread::Builder::new()
+            .name(format!("asi-efw-{}", device.efw_id()))
+            .spawn(move || {
+                EfwActor::new(device, policy, snapshot_interval, names, rx, events).run()
+            })
+            .expect("Unable to spawn the filter wheel thread");
+
+        Self {
+            id,
+            tx,
+            thread: Some(thread),
+        }
+    }
+
+    /// A sender that can be moved to other tasks to queue commands
+    pub fn commands(&self) -> Sender<EfwCommand> {
+        self.tx.clone()
+    }
+
+    pub fn send(&self, command: EfwCommand) {
+        if self.tx.send(command).is_err() {
+            error!("Filter wheel {} is not running anymore", self.id);
+        }
+    }
+
+    /// Ask the actor to close the wheel and wait for its thread to finish
+    pub fn shutdown(&mut self) {
+        self.send(EfwCommand::Shutdown);
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            error!("Filter wheel {} thread panicked", self.id);
+        }
+    }
+}
+
+/// The client request that started a motion, it is completed once the
+/// wheel stops
+#[derive(Debug, Clone)]
+struct Request {
+    action: &'static str,
+    id: Option<String>,
+}
+
+/// The kind of motion the wheel is performing
+#[derive(Debug, Clone)]
+enum Motion {
+    Move { slot: i32, request: Request },
+    Calibration(Request),
+}
+
+struct EfwActor {
+    device: EfwDevice,
+    policy: MovePolicy,
+    names: NamesFile,
+    rx: Receiver<EfwCommand>,
+    events: UnboundedSender<(Uuid, EfwEvent)>,
+    motion: Option<Motion>,
+    /// Commands waiting for the wheel to stop, in the order they arrived
+    pending: VecDeque<EfwCommand>,
+    state: StateTracker,
+}
+
+impl EfwActor {
+    fn new(
+        device: EfwDevice,
+        policy: MovePolicy,
+        snapshot_interval: Duration,
+        names: NamesFile,
+        rx: Receiver<EfwCommand>,
+        events: UnboundedSender<(Uuid, EfwEvent)>,
+    ) -> Self {
+        Self {
+            device,
+            policy,
+            names,
+            rx,
+            events,
+            motion: None,
+            pending: VecDeque::new(),
+            state: StateTracker::new(snapshot_interval),
+        }
+    }
+
+    fn run(mut self) {
+        info!("Filter wheel actor for {} started", self.device.name);
+
+        loop {
+            let command = if self.motion.is_some() {
+                match self.rx.recv_timeout(MOTION_CHECK_INTERVAL) {
+                    Ok(c) => c,
+                    Err(RecvTimeoutError::Timeout) => {
+                        self.check_motion();
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => EfwCommand::Shutdown,
+                }
+            } else if let Some(c) = self.pending.pop_front() {
+                c
+            } else {
+                // All the senders are gone, nobody can talk to this wheel anymore
+                self.rx.recv().unwrap_or(EfwCommand::Shutdown)
+            };
+            debug!("Filter wheel {} processing {:?}", self.device.id, command);
+
+            if let EfwCommand::Shutdown = command {
+                self.device.close();
+                break;
+            }
+
+            if self.motion.is_some() {
+                self.defer(command);
+            } else {
+                self.process(command);
+            }
+        }
+
+        info!("Filter wheel actor for {} stopped", self.device.name);
+    }
+
+    /// Handle a command received while the wheel is moving
+    fn defer(&mut self, command: EfwCommand) {
+        let busy = || ReplyError::new(ErrorCode::Busy, "the wheel is moving");
+
+        match command {
+            // The position can't be read while moving, the state will be
+            // published once the motion is over
+            EfwCommand::Poll => (),
+            EfwCommand::Snapshot => self.publish_snapshot(),
+            EfwCommand::Move { slot, request_id } if self.policy == MovePolicy::Reject => {
+                warn!("Rejecting move to slot {}, the wheel is moving", slot);
+                self.reply(Reply::rejected("set_slot", request_id, busy()));
+            }
+            EfwCommand::Calibrate { request_id } if self.policy == MovePolicy::Reject => {
+                warn!("Rejecting calibration, the wheel is moving");
+                self.reply(Reply::rejected("calibrate", request_id, busy()));
+            }
+            EfwCommand::Update(request)
+                if self.policy == MovePolicy::Reject && request.current_slot.is_some() =>
+            {
+                warn!("Rejecting update, the wheel is moving");
+                self.reply(Reply::rejected("update", request.request_id, busy()));
+            }
+            c => self.pending.push_back(c),
+        }
+    }
+
+    fn process(&mut self, command: EfwCommand) {
+        match command {
+            EfwCommand::Poll => {
+                self.device.fetch_props();
+                self.publish_state();
+            }
+            EfwCommand::Snapshot => self.publish_snapshot(),
+            EfwCommand::Move { slot, request_id } => {
+                if slot < 1 || slot > self.device.slot_num {
+                    warn!("Slot {} out of range 1..={}", slot, self.device.slot_num);
+                    let e = ReplyError::invalid(
+                        "slot",
+                        format!("must be within 1..={}", self.device.slot_num),
+                    );
+                    self.reply(Reply::rejected("set_slot", request_id, e));
+                    return;
+                }
+
+                self.reply(Reply::accepted("set_slot", request_id.clone()));
+                self.start_move(
+                    slot,
+                    Request {
+                        action: "set_slot",
+                        id: request_id,
+                    },
+                );
+            }
+            EfwCommand::Calibrate { request_id } => {
+                info!("Starting calibration for {}", self.device.id);
+                self.reply(Reply::accepted("calibrate", request_id.clone()));
+                if let Err(e) = self.device.calibrate() {
+                    self.reply(Reply::failed("calibrate", request_id, e.into()));
+                    return;
+                }
+
+                self.device.calibrating = true;
+                self.motion = Some(Motion::Calibration(Request {
+                    action: "calibrate",
+                    id: request_id,
+                }));
+                self.publish_state();
+            }
+            EfwCommand::Update(request) => self.update(request),
+            EfwCommand::Shutdown => unreachable!("shutdown is handled by the run loop"),
+        }
+    }
+
+    /// Start moving to `slot`, `request` is completed once the wheel stops
+    fn start_move(&mut self, slot: i32, request: Request) {
+        let from = self.device.current_slot;
+        if let Err(e) = self.device.set_slot(slot) {
+            self.reply(Reply::failed(request.action, request.id, e.into()));
+            return;
+        }
+
+        self.device.moving = true;
+        self.motion = Some(Motion::Move { slot, request });
+        self.emit(EfwEvent::MoveStarted { from, to: slot });
+        self.publish_state();
+    }
+
+    /// Apply an update, nothing is changed if any of the values is invalid.
+    /// A new slot starts a move like `EfwCommand::Move` and the update is
+    /// completed once the wheel stops.
+    fn update(&mut self, request: UpdateRequest) {
+        if let Err(e) = request.validate(self.device.slot_num) {
+            warn!("Update rejected for {}: {}", self.device.name, e);
+            self.reply(Reply::rejected("update", request.request_id, e));
+            return;
+        }
+
+        self.reply(Reply::accepted("update", request.request_id.clone()));
+        let result = self.write_update(&request);
+        self.device.fetch_props();
+        self.publish_state();
+
+        if let Err(e) = result {
+            self.reply(Reply::failed("update", request.request_id, e));
+            return;
+        }
+
+        match request.current_slot {
+            Some(slot) if slot != self.device.current_slot => self.start_move(
+                slot,
+                Request {
+                    action: "update",
+                    id: request.request_id,
+                },
+            ),
+            _ => self.reply(Reply::completed("update", request.request_id)),
+        }
+    }
+
+    /// Write the values of `request` to the wheel, the alias with
+    /// `EFWSetID`, and save the names given to the wheel
+    fn write_update(&mut self, request: &UpdateRequest) -> Result<(), ReplyError> {
+        if let Some(flag) = request.unidirectional {
+            self.device.set_unidirectional(flag)?;
+        }
+        if let Some(id) = request.alias_id()? {
+            self.device.set_alias(id)?;
+            self.device.alias = request.alias.clone();
+        }
+        if let Some(names) = &request.filter_names {
+            self.device.filter_names = names.clone();
+        }
+
+        if request.alias.is_some() || request.filter_names.is_some() {
+            let names = WheelNames {
+                alias: self.device.alias.clone(),
+                filter_names: self.device.filter_names.clone(),
+            };
+            self.names.save(self.device.id, names).map_err(|e| {
+                ReplyError::new(
+                    ErrorCode::SaveFailed,
+                    format!("unable to save the names of the wheel: {}", e),
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Check if the ongoing motion is over, if so publish the new state
+    fn check_motion(&mut self) {
+        if self.device.is_moving() {
+            return;
+        }
+
+        let motion = self.motion.take();
+        self.device.moving = false;
+        self.device.calibrating = false;
+        self.device.fetch_props();
+
+        match motion {
+            Some(Motion::Move {
+                slot: target,
+                request,
+            }) => {
+                let slot = self.device.current_slot;
+                debug!("Move finished at slot {}", slot);
+                self.emit(EfwEvent::MoveFinished { slot });
+
+                if slot == target {
+                    self.reply(Reply::completed(request.action, request.id));
+                } else {
+                    warn!("Wheel stopped at slot {} instead of {}", slot, target);
+                    let e = ReplyError::new(
+                        ErrorCode::DeviceError,
+                        format!("the wheel stopped at slot {} instead of {}", slot, target),
+                    );
+                    self.reply(Reply::failed(request.action, request.id, e));
+                }
+            }
+            Some(Motion::Calibration(request)) => {
+                info!("Calibration complete");
+                self.reply(Reply::completed(request.action, request.id));
+            }
+            None => (),
+        }
+        self.publish_state();
+    }
+
+    /// Publish the properties that changed, or the full state if a snapshot
+    /// is due
+    fn publish_state(&mut self) {
+        match self.state.update(&self.device) {
+            Ok(Some(update)) => self.emit_state(update),
+            Ok(None) => (),
+            Err(e) => error!("Unable to serialize filter wheel {}: {}", self.device.id, e),
+        }
+    }
+
+    fn publish_snapshot(&mut self) {
+        match self.state.snapshot(&self.device) {
+            Ok(update) => self.emit_state(update),
+            Err(e) => error!("Unable to serialize filter wheel {}: {}", self.device.id, e),
+        }
+    }
+
+    fn emit_state(&self, update: StateUpdate) {
+        self.emit(match update {
+            StateUpdate::Snapshot(s) => EfwEvent::State(s),
+            StateUpdate::Changes(c) => EfwEvent::Changes(c),
+        });
+    }
+
+    fn reply(&self, reply: Reply) {
+        self.emit(EfwEvent::Reply(reply));
+    }
+
+    fn emit(&self, event: EfwEvent) {
+        if self.events.send((self.device.id, event)).is_err() {
+            debug!("Nobody is listening for events of {}", self.device.id);
+        }
+    }
+}
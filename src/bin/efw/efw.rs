@@ -1,5 +1,9 @@
+use asi_rs::config::Daemon;
+use asi_rs::discovery::{Announcement, DeviceKind};
+use libasi::efw::EfwError;
 use log::{debug, info, warn};
 use serde::Serialize;
+use serde_json::json;
 use uuid::Uuid;
 
 pub fn look_for_devices() -> i32 {
@@ -17,11 +21,18 @@ pub struct EfwDevice {
     pub id: Uuid,
     pub name: String,
     #[serde(skip)]
+    serial: Option<String>,
+    #[serde(skip)]
     efw_id: i32,
     pub slot_num: i32,
     pub current_slot: i32,
     pub unidirectional: bool,
     pub calibrating: bool,
+    pub moving: bool,
+    /// Last alias written to the wheel, restored from `efw.names_file`
+    pub alias: Option<String>,
+    /// User defined name of the filter in each slot
+    pub filter_names: Vec<String>,
 }
 
 impl EfwDevice {
@@ -39,25 +50,51 @@ impl EfwDevice {
         let current_slot = libasi::efw::get_efw_position(efw_id);
         let unidirectional = libasi::efw::is_unidirectional(efw_id);
 
+        // Old firmwares have no serial number, fall back to the id assigned by
+        // the SDK which is stable as long as the wheel stays on the same port
+        let serial = asi_rs::utils::serial_to_string(&libasi::efw::get_serial_number(efw_id).id);
+        let hw_id = serial.clone().unwrap_or_else(|| efw_id.to_string());
+
         info!(
             "EFW '{}' opened: {} slots, current={}, unidirectional={}",
             name, slot_num, current_slot, unidirectional
         );
 
         Self {
-            id: Uuid::new_v4(),
+            id: asi_rs::utils::device_uuid(&name, &hw_id),
             name: format!("ZWO {}", name),
+            serial,
             efw_id,
             slot_num,
             current_slot,
             unidirectional,
             calibrating: false,
+            moving: false,
+            alias: None,
+            filter_names: Vec::new(),
         }
     }
 
+    /// Describe the wheel for the discovery topic
+    pub fn announcement(&self) -> Announcement {
+        Announcement::new(
+            Daemon::Efw.name(),
+            DeviceKind::Efw,
+            self.id,
+            &self.name,
+            self.serial.clone(),
+            json!({
+                "slots": self.slot_num,
+                "unidirectional": true,
+                "calibration": true,
+                "alias": true,
+            }),
+        )
+    }
+
     pub fn fetch_props(&mut self) {
-        // Don't poll while calibrating — position will be -1 (returns 0 via wrapper)
-        if self.calibrating {
+        // Don't poll while moving — position will be -1 (returns 0 via wrapper)
+        if self.calibrating || self.moving {
             return;
         }
         let slot = libasi::efw::get_efw_position(self.efw_id);
@@ -71,14 +108,31 @@ impl EfwDevice {
         }
     }
 
-    pub fn set_slot(&self, position: i32) {
+    pub fn set_slot(&self, position: i32) -> Result<(), EfwError> {
         debug!("Setting EFW slot to {}", position);
-        libasi::efw::set_efw_position(self.efw_id, position);
+        libasi::efw::set_efw_position(self.efw_id, position)
+    }
+
+    pub fn calibrate(&self) -> Result<(), EfwError> {
+        debug!("Calibrating EFW '{}'", self.name);
+        libasi::efw::calibrate_wheel(self.efw_id)
+    }
+
+    pub fn is_moving(&self) -> bool {
+        libasi::efw::check_wheel_is_moving(self.efw_id)
+    }
+
+    /// Store an 8 bytes alias in the flash memory of the wheel
+    pub fn set_alias(&self, alias: [u8; 8]) -> Result<(), EfwError> {
+        debug!("Setting EFW alias to {:?}", alias);
+        let mut id = libasi::efw::EFWId::new();
+        id.id = alias;
+        libasi::efw::set_id(self.efw_id, id)
     }
 
-    pub fn set_unidirectional(&self, flag: bool) {
+    pub fn set_unidirectional(&self, flag: bool) -> Result<(), EfwError> {
         debug!("Setting EFW unidirectional to {}", flag);
-        libasi::efw::set_unidirection(self.efw_id, flag);
+        libasi::efw::set_unidirection(self.efw_id, flag)
     }
 
     pub fn efw_id(&self) -> i32 {
@@ -1,50 +1,187 @@
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
-
+use asi_rs::config::{Config, Daemon, MovePolicy};
+use asi_rs::discovery::{self, Announcement, DISCOVER_PATH};
+use asi_rs::presence::{DeviceStatus, Presence};
+use asi_rs::reply::{self, Reply, SimpleRequest, REPLY_ACTION};
+use asi_rs::state::{CHANGES_ACTION, SNAPSHOT_ACTION};
+use asi_rs::topics::{Handler, TopicRouter};
 use env_logger::Env;
-use log::{debug, info};
-use rumqttc::{AsyncClient, MqttOptions, QoS};
+use log::{debug, error, info, warn};
+use rumqttc::{AsyncClient, QoS, SubscribeFilter};
+use serde_json::json;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::time::Duration;
 use tokio::signal;
+use tokio::sync::mpsc::{self, UnboundedSender};
 use tokio::task;
+use tokio::time::Sleep;
 use uuid::Uuid;
 
 use rumqttc::Event::{Incoming, Outgoing};
-use rumqttc::Packet::Publish;
+use rumqttc::Packet::{ConnAck, Publish};
 
+/// Bounds of the delay before polling a broken connection again, it doubles
+/// after every failure and is reset once the connection works
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+pub mod actor;
 pub mod efw;
+pub mod names;
+pub mod request;
+use actor::{EfwCommand, EfwEvent, EfwHandle};
 use efw::EfwDevice;
+use names::NamesFile;
+use request::{SlotRequest, UpdateRequest};
 
-#[derive(Default, Clone)]
 struct AsiEfwDriver {
-    devices: Vec<Arc<RwLock<EfwDevice>>>,
+    devices: Vec<EfwHandle>,
+    /// Published on the discovery topic, they don't change while running
+    announcements: Vec<Announcement>,
+    /// Used to reply to commands rejected before reaching the wheel
+    events: UnboundedSender<(Uuid, EfwEvent)>,
 }
 
 impl AsiEfwDriver {
-    fn new() -> Self {
+    fn new(
+        policy: MovePolicy,
+        snapshot_interval: Duration,
+        names: &NamesFile,
+        events: UnboundedSender<(Uuid, EfwEvent)>,
+    ) -> Self {
         let found = efw::look_for_devices();
         let mut devices = Vec::with_capacity(found as usize);
+        let mut announcements = Vec::with_capacity(found as usize);
         for idx in 0..found {
-            let device = Arc::new(RwLock::new(EfwDevice::new(idx)));
+            let mut wheel = EfwDevice::new(idx);
+            let saved = names.get(wheel.id);
+            wheel.alias = saved.alias;
+            wheel.filter_names = saved.filter_names;
+            announcements.push(wheel.announcement());
+            let device = EfwHandle::spawn(
+                wheel,
+                policy,
+                snapshot_interval,
+                names.clone(),
+                events.clone(),
+            );
             devices.push(device);
         }
-        Self { devices }
+        Self {
+            devices,
+            announcements,
+            events,
+        }
+    }
+
+    fn reply(&self, id: Uuid, reply: Reply) {
+        if self.events.send((id, EfwEvent::Reply(reply))).is_err() {
+            error!("Unable to reply to a command for {}", id);
+        }
+    }
+
+    fn device(&self, id: &str) -> Option<&EfwHandle> {
+        self.devices.iter().find(|d| d.id.to_string() == id)
+    }
+}
+
+/// Commands accepted on `devices/{id}/{action}`
+#[derive(Debug, Clone, Copy)]
+enum EfwAction {
+    SetSlot,
+    Calibrate,
+    Update,
+    Snapshot,
+}
+
+impl EfwAction {
+    const ALL: [EfwAction; 4] = [
+        EfwAction::SetSlot,
+        EfwAction::Calibrate,
+        EfwAction::Update,
+        EfwAction::Snapshot,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            EfwAction::SetSlot => "set_slot",
+            EfwAction::Calibrate => "calibrate",
+            EfwAction::Update => "update",
+            EfwAction::Snapshot => SNAPSHOT_ACTION,
+        }
+    }
+}
+
+impl FromStr for EfwAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EfwAction::ALL
+            .into_iter()
+            .find(|a| a.as_str() == s)
+            .ok_or(())
+    }
+}
+
+impl Handler for AsiEfwDriver {
+    type Action = EfwAction;
+
+    fn handle(&mut self, device_id: &str, action: EfwAction, payload: &[u8]) {
+        let Some(device) = self.device(device_id) else {
+            warn!("Unknown device: `{}`", device_id);
+            return;
+        };
+
+        let command = match action {
+            EfwAction::SetSlot => SlotRequest::from_payload(payload).map(|r| EfwCommand::Move {
+                slot: r.slot,
+                request_id: r.request_id,
+            }),
+            EfwAction::Calibrate => {
+                SimpleRequest::from_payload(payload).map(|r| EfwCommand::Calibrate {
+                    request_id: r.request_id,
+                })
+            }
+            EfwAction::Update => UpdateRequest::from_payload(payload).map(EfwCommand::Update),
+            EfwAction::Snapshot => Ok(EfwCommand::Snapshot),
+        };
+
+        match command {
+            Ok(command) => {
+                info!("Command for {}: {:?}", device_id, command);
+                device.send(command);
+            }
+            Err(e) => {
+                warn!("Invalid `{}` for {}: {}", action.as_str(), device_id, e);
+                let reply = Reply::rejected(action.as_str(), reply::request_id(payload), e);
+                self.reply(device.id, reply);
+            }
+        }
     }
 }
 
-async fn subscribe(client: AsyncClient, ids: &Vec<Uuid>) {
-    for id in ids {
-        client
-            .subscribe(format!("devices/{}/set_slot", id), QoS::AtLeastOnce)
-            .await
-            .unwrap();
-        client
-            .subscribe(format!("devices/{}/calibrate", id), QoS::AtLeastOnce)
-            .await
-            .unwrap();
-        client
-            .subscribe(format!("devices/{}/update", id), QoS::AtLeastOnce)
-            .await
-            .unwrap();
+/// Subscribe to the commands of every device and to the daemon wide `topics`,
+/// with a request per device and one for the daemon. The requests wait in the
+/// bounded queue of the client, which only the event loop drains, so this must
+/// run beside it rather than before it.
+async fn subscribe(client: AsyncClient, router: TopicRouter, ids: Vec<Uuid>, topics: Vec<String>) {
+    let filters = |topics: Vec<String>| {
+        topics
+            .into_iter()
+            .map(|t| SubscribeFilter::new(t, QoS::AtLeastOnce))
+            .collect::<Vec<_>>()
+    };
+
+    for id in &ids {
+        let topics = EfwAction::ALL.map(|action| router.device_action(id, action.as_str()));
+        if let Err(e) = client.subscribe_many(filters(topics.to_vec())).await {
+            error!("Unable to subscribe to the commands of {}: {}", id, e);
+        }
+    }
+
+    if let Err(e) = client.subscribe_many(filters(topics)).await {
+        error!("Unable to subscribe to the daemon topics: {}", e);
     }
 }
 
@@ -54,105 +191,179 @@ async fn main() {
     let env = Env::default().filter_or("LS_LOG_LEVEL", "info");
     env_logger::init_from_env(env);
 
-    let driver = AsiEfwDriver::new();
-    let mut mqttoptions = MqttOptions::new("asi_efw", "127.0.0.1", 1883);
-    mqttoptions.set_keep_alive(Duration::from_secs(5));
-    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+    let config = match Config::from_args(Daemon::Efw) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let names = match NamesFile::load(&config.efw.names_file) {
+        Ok(n) => n,
+        Err(e) => {
+            error!("Unable to read {}: {}", config.efw.names_file.display(), e);
+            std::process::exit(2);
+        }
+    };
 
-    let mut devices_id = Vec::with_capacity(driver.devices.len());
-    for d in &driver.devices {
-        devices_id.push(d.read().unwrap().id);
-    }
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    let mut driver = AsiEfwDriver::new(
+        config.efw.move_policy,
+        config.snapshot_interval(),
+        &names,
+        events_tx,
+    );
+    let (client, mut eventloop) = AsyncClient::new(config.mqtt.options(Daemon::Efw.name()), 10);
 
-    subscribe(client.clone(), &devices_id).await;
+    let router = config.mqtt.router();
+    let devices_id: Vec<Uuid> = driver.devices.iter().map(|d| d.id).collect();
 
-    // Spawn a ctrl-c handler per device to close cleanly on shutdown
-    for d in &driver.devices {
-        let device = Arc::clone(d);
-        tokio::spawn(async move {
-            signal::ctrl_c().await.unwrap();
-            debug!("ctrl-c received, closing EFW device");
-            device.read().unwrap().close();
-            std::process::exit(0);
-        });
-    }
+    let discover_topic = router.topic(DISCOVER_PATH);
 
-    // Periodic state fetch and publish per device
+    // Each device announces itself online on a dedicated connection, whose
+    // last will flips it offline if the daemon dies
+    let mut presences: Vec<Presence> = devices_id
+        .iter()
+        .map(|id| Presence::spawn(&config.mqtt, Daemon::Efw.name(), id))
+        .collect();
+    let statuses: HashMap<Uuid, _> = devices_id
+        .iter()
+        .zip(&presences)
+        .map(|(id, p)| (*id, p.sender()))
+        .collect();
+
+    // Periodic state fetch per device, the actor publishes the result
+    // through the events channel
+    let poll_interval = config.poll_interval(Daemon::Efw);
     for d in &driver.devices {
-        let device = Arc::clone(d);
-        let c = client.clone();
+        let commands = d.commands();
         task::spawn(async move {
-            let d_id = device.read().unwrap().id;
-            loop {
-                device.write().unwrap().fetch_props();
-                let serialized = serde_json::to_string(&*device.read().unwrap()).unwrap();
-                c.publish(
-                    format!("devices/{}", &d_id),
-                    QoS::AtLeastOnce,
-                    false,
-                    serialized,
-                )
-                .await
-                .unwrap();
-                tokio::time::sleep(Duration::from_millis(2500)).await;
+            while commands.send(EfwCommand::Poll).is_ok() {
+                tokio::time::sleep(poll_interval).await;
             }
         });
     }
 
-    // MQTT event loop
-    // Topics are in the form devices/{UUID}/{action}
-    // "devices/" = 8 chars, UUID = 36 chars, "/" = 1 char → action starts at index 45
-    while let Ok(event) = eventloop.poll().await {
-        debug!("Received = {:?}", event);
-        match event {
-            Incoming(inc) => match inc {
-                Publish(data) => {
-                    let action = &data.topic[45..];
-                    let device_id = &data.topic[8..44];
-
-                    match action {
-                        "set_slot" => {
-                            let payload = String::from_utf8_lossy(&data.payload);
-                            if let Ok(slot) = payload.trim().parse::<i32>() {
-                                for d in &driver.devices {
-                                    if d.read().unwrap().id.to_string() == device_id {
-                                        info!("Setting slot {} for {}", slot, device_id);
-                                        d.read().unwrap().set_slot(slot);
-                                    }
-                                }
-                            }
-                        }
-                        "calibrate" => {
-                            for d in &driver.devices {
-                                if d.read().unwrap().id.to_string() == device_id {
-                                    info!("Starting calibration for {}", device_id);
-                                    let device = Arc::clone(d);
-                                    task::spawn_blocking(move || {
-                                        let efw_id = device.read().unwrap().efw_id();
-                                        device.write().unwrap().calibrating = true;
-                                        libasi::efw::calibrate_wheel(efw_id);
-                                        while libasi::efw::check_wheel_is_moving(efw_id) {
-                                            std::thread::sleep(Duration::from_millis(100));
-                                        }
-                                        device.write().unwrap().calibrating = false;
-                                        info!("Calibration complete");
-                                    });
-                                }
-                            }
-                        }
-                        "update" => {
-                            let payload = String::from_utf8_lossy(&data.payload);
-                            info!(
-                                "Update request for {}: {}",
-                                device_id, payload
-                            );
-                            // TODO: parse and dispatch generic property updates
-                        }
-                        _ => (),
+    // Publish whatever the filter wheel actors produce
+    let c = client.clone();
+    let r = router.clone();
+    task::spawn(async move {
+        while let Some((id, event)) = events_rx.recv().await {
+            if let EfwEvent::Reply(reply) = &event
+                && let Some(status) = DeviceStatus::after(reply)
+                && let Some(sender) = statuses.get(&id)
+            {
+                sender.set(status);
+            }
+
+            // Only the state is retained, a new client gets it straight away
+            let (topic, payload, retain) = match event {
+                EfwEvent::State(serialized) => (r.device(id), serialized, true),
+                EfwEvent::Changes(changes) => (r.device_action(id, CHANGES_ACTION), changes, false),
+                EfwEvent::MoveStarted { from, to } => (
+                    r.device_action(id, "events"),
+                    json!({"event": "move_started", "from": from, "to": to}).to_string(),
+                    false,
+                ),
+                EfwEvent::MoveFinished { slot } => (
+                    r.device_action(id, "events"),
+                    json!({"event": "move_finished", "slot": slot}).to_string(),
+                    false,
+                ),
+                EfwEvent::Reply(reply) => match serde_json::to_string(&reply) {
+                    Ok(payload) => (r.device_action(id, REPLY_ACTION), payload, false),
+                    Err(e) => {
+                        error!("Unable to serialize reply for {}: {}", &id, e);
+                        continue;
+                    }
+                },
+            };
+
+            if let Err(e) = c.publish(topic, QoS::AtLeastOnce, retain, payload).await {
+                error!("Unable to publish event for {}: {}", &id, e);
+            }
+        }
+    });
+
+    let announce = {
+        let c = client.clone();
+        let r = router.clone();
+        let announcements = driver.announcements.clone();
+        move || {
+            let (c, r, announcements) = (c.clone(), r.clone(), announcements.clone());
+            task::spawn(async move { discovery::announce(&c, &r, &announcements).await });
+        }
+    };
+    announce();
+
+    let ctrl_c = signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    // Joins the wheel threads on shutdown, the connection keeps being polled
+    // meanwhile so that their last events are published
+    let mut stopping: Option<task::JoinHandle<()>> = None;
+    let mut reconnect_delay = MIN_RECONNECT_DELAY;
+    // Wait before polling a broken connection again, raced against ctrl-c
+    let mut backoff: Option<Pin<Box<Sleep>>> = None;
+
+    // MQTT event loop, commands are routed to the handler implemented above
+    loop {
+        let event = tokio::select! {
+            _ = &mut ctrl_c, if stopping.is_none() => {
+                debug!("ctrl-c received, closing EFW devices");
+                for p in presences.drain(..) {
+                    p.shutdown().await;
+                }
+                let mut devices = std::mem::take(&mut driver.devices);
+                stopping = Some(task::spawn_blocking(move || {
+                    for d in devices.iter_mut() {
+                        d.shutdown();
                     }
+                }));
+                continue;
+            }
+            _ = async { stopping.as_mut().unwrap().await }, if stopping.is_some() => {
+                std::process::exit(0);
+            }
+            _ = async { backoff.as_mut().unwrap().await }, if backoff.is_some() => {
+                backoff = None;
+                continue;
+            }
+            event = eventloop.poll(), if backoff.is_none() => match event {
+                Ok(event) => {
+                    reconnect_delay = MIN_RECONNECT_DELAY;
+                    event
+                }
+                // rumqttc reconnects on the next poll
+                Err(e) => {
+                    error!(
+                        "MQTT connection error: {}, retrying in {:?}",
+                        e, reconnect_delay
+                    );
+                    backoff = Some(Box::pin(tokio::time::sleep(reconnect_delay)));
+                    reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+                    continue;
                 }
-                _ => debug!("Incoming event: {:?}", inc),
             },
+        };
+
+        debug!("Received = {:?}", event);
+        match event {
+            // The session is clean, the subscriptions are made again on every
+            // connection
+            Incoming(ConnAck(_)) => {
+                let topics = vec![discover_topic.clone()];
+                let ids = devices_id.clone();
+                task::spawn(subscribe(client.clone(), router.clone(), ids, topics));
+            }
+            Incoming(Publish(data)) if data.topic == discover_topic => {
+                info!("Discovery requested");
+                announce();
+            }
+            Incoming(Publish(data)) => {
+                router.dispatch(&mut driver, &data.topic, &data.payload);
+            }
+            Incoming(inc) => debug!("Incoming event: {:?}", inc),
             Outgoing(out) => debug!("Outgoing MQTT event: {:?}", out),
         }
     }
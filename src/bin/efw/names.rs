@@ -0,0 +1,100 @@
+//! Names given by the users to the filter wheels and their filters.
+//!
+//! The SDK writes the alias to the flash memory of the wheel but can't read
+//! it back, and the filter names only exist in the daemon: both are kept in
+//! `efw.names_file`, keyed by the id of the wheel, and restored on startup.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WheelNames {
+    pub alias: Option<String>,
+    /// Name of the filter in each slot, starting from slot 1
+    pub filter_names: Vec<String>,
+}
+
+/// The names of every wheel, written to disk on every change. It can be
+/// cloned and shared between threads.
+#[derive(Debug, Clone)]
+pub struct NamesFile {
+    path: PathBuf,
+    wheels: Arc<Mutex<BTreeMap<Uuid, WheelNames>>>,
+}
+
+impl NamesFile {
+    /// Read the names from `path`, a missing file holds no names
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let wheels = match fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            wheels: Arc::new(Mutex::new(wheels)),
+        })
+    }
+
+    /// Names of the wheel `id`, empty if it has never been named
+    pub fn get(&self, id: Uuid) -> WheelNames {
+        self.wheels
+            .lock()
+            .ok()
+            .and_then(|wheels| wheels.get(&id).cloned())
+            .unwrap_or_default()
+    }
+
+    /// Replace the names of the wheel `id` and write the file. It is written
+    /// aside first, a crash never leaves a truncated file behind.
+    pub fn save(&self, id: Uuid, names: WheelNames) -> io::Result<()> {
+        let mut wheels = self
+            .wheels
+            .lock()
+            .map_err(|_| io::Error::other("the names of the wheels are poisoned"))?;
+        wheels.insert(id, names);
+
+        let content = serde_json::to_vec_pretty(&*wheels).map_err(io::Error::other)?;
+        if let Some(dir) = self.path.parent()
+            && !dir.as_os_str().is_empty()
+        {
+            fs::create_dir_all(dir)?;
+        }
+        let partial = self.path.with_extension("partial");
+        fs::write(&partial, content)?;
+        fs::rename(&partial, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("asi-rs-{}", Uuid::new_v4()));
+        let path = dir.join("efw-names.json");
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let names = NamesFile::load(&path).unwrap();
+        assert_eq!(names.get(first), WheelNames::default());
+        let named = WheelNames {
+            alias: Some("MAINEFW".into()),
+            filter_names: vec!["L".into(), "R".into(), "G".into(), "B".into()],
+        };
+        names.save(first, named.clone()).unwrap();
+
+        let reloaded = NamesFile::load(&path).unwrap();
+        assert_eq!(reloaded.get(first), named);
+        assert_eq!(reloaded.get(second), WheelNames::default());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+//! Payloads accepted by the filter wheel commands
+
+use asi_rs::reply::{ErrorCode, ReplyError};
+use serde::Deserialize;
+
+/// Payload of `devices/{id}/update`
+///
+/// ```json
+/// {
+///   "request_id": "44",
+///   "unidirectional": true,
+///   "current_slot": 3,
+///   "alias": "MAINEFW",
+///   "filter_names": ["L", "R", "G", "B", "Ha", "OIII", "SII"]
+/// }
+/// ```
+///
+/// Every field is optional, only the given ones are changed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateRequest {
+    pub request_id: Option<String>,
+    pub unidirectional: Option<bool>,
+    pub current_slot: Option<i32>,
+    /// Up to 8 ASCII characters, stored in the flash memory of the wheel
+    pub alias: Option<String>,
+    /// Name of the filter in each slot, starting from slot 1
+    pub filter_names: Option<Vec<String>>,
+}
+
+impl UpdateRequest {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ReplyError> {
+        serde_json::from_slice(payload)
+            .map_err(|e| ReplyError::new(ErrorCode::InvalidPayload, e.to_string()))
+    }
+
+    /// Check the request against a wheel with `slot_num` slots
+    pub fn validate(&self, slot_num: i32) -> Result<(), ReplyError> {
+        if let Some(slot) = self.current_slot
+            && (slot < 1 || slot > slot_num)
+        {
+            return Err(ReplyError::invalid(
+                "current_slot",
+                format!("must be within 1..={}", slot_num),
+            ));
+        }
+
+        if let Some(names) = &self.filter_names
+            && names.len() > slot_num as usize
+        {
+            return Err(ReplyError::invalid(
+                "filter_names",
+                format!("the wheel has only {} slots", slot_num),
+            ));
+        }
+
+        self.alias_id().map(|_| ())
+    }
+
+    /// The alias as expected by `EFWSetID`, padded with zeros
+    pub fn alias_id(&self) -> Result<Option<[u8; 8]>, ReplyError> {
+        let Some(alias) = &self.alias else {
+            return Ok(None);
+        };
+
+        if alias.is_empty() || alias.len() > 8 || !alias.is_ascii() {
+            return Err(ReplyError::invalid(
+                "alias",
+                "must be 1 to 8 ASCII characters",
+            ));
+        }
+
+        let mut id = [0; 8];
+        id[..alias.len()].copy_from_slice(alias.as_bytes());
+        Ok(Some(id))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SlotPayload {
+    Slot(i32),
+    Request {
+        slot: i32,
+        request_id: Option<String>,
+    },
+}
+
+/// Payload of `devices/{id}/set_slot`, either the bare slot number or
+/// `{"slot": 3, "request_id": "45"}`
+#[derive(Debug, Clone)]
+pub struct SlotRequest {
+    pub slot: i32,
+    pub request_id: Option<String>,
+}
+
+impl SlotRequest {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ReplyError> {
+        let parsed = serde_json::from_slice(payload)
+            .map_err(|e| ReplyError::new(ErrorCode::InvalidPayload, e.to_string()))?;
+
+        Ok(match parsed {
+            SlotPayload::Slot(slot) => Self {
+                slot,
+                request_id: None,
+            },
+            SlotPayload::Request { slot, request_id } => Self { slot, request_id },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(payload: &str) -> UpdateRequest {
+        UpdateRequest::from_payload(payload.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn rejects_slots_out_of_range() {
+        for slot in [0, -1, 8] {
+            let request = update(&format!(r#"{{"current_slot": {}}}"#, slot));
+            let e = request.validate(7).unwrap_err();
+            assert_eq!(e.code, ErrorCode::InvalidValue);
+            assert!(e.message.starts_with("current_slot"), "{}", e.message);
+        }
+
+        for slot in [1, 7] {
+            let request = update(&format!(r#"{{"current_slot": {}}}"#, slot));
+            request.validate(7).unwrap();
+        }
+    }
+
+    #[test]
+    fn pads_the_alias() {
+        let request = update(r#"{"alias": "MAIN"}"#);
+        assert_eq!(request.alias_id().unwrap(), Some(*b"MAIN\0\0\0\0"));
+
+        for alias in ["", "MAINEFW01", "Filtré"] {
+            let request = update(&format!(r#"{{"alias": "{}"}}"#, alias));
+            assert!(request.validate(7).is_err(), "{}", alias);
+        }
+    }
+}
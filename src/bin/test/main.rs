@@ -22,7 +22,7 @@ fn expose(idx: i32) -> u32 {
     );
     println!("Exp time: {}", e_val);
     println!("Exposing");
-    libasi::camera::start_exposure(idx);
+    libasi::camera::start_exposure(idx).expect("Unable to start the exposure");
 
     let mut status = 0;
     libasi::camera::exposure_status(idx, &mut status);
@@ -109,7 +109,8 @@ fn main() {
             libasi::camera::ASI_CONTROL_TYPE_ASI_EXPOSURE as i32,
             length,
             libasi::camera::ASI_BOOL_ASI_FALSE as i32,
-        );
+        )
+        .expect("Unable to set the exposure");
         let mut e_val = 0;
         libasi::camera::get_control_value(
             idx,
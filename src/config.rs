@@ -0,0 +1,1220 @@
+//! Configuration shared by the `asi_ccd` and `asi_efw` daemons.
+//!
+//! Values are resolved in this order, the last one winning: built-in defaults,
+//! the TOML file passed with `--config`, environment variables and finally
+//! command line flags.
+//!
+//! ```toml
+//! [mqtt]
+//! host = "broker.observatory.lan"
+//! port = 8883
+//! username = "asi"
+//! password_file = "/etc/asi-rs/mqtt-password"
+//! client_id_prefix = "dome-east"
+//! topic_prefix = "observatory/east"
+//! keep_alive_secs = 5
+//!
+//! [mqtt.tls]
+//! enabled = true
+//! ca_file = "/etc/asi-rs/ca.pem"
+//! client_cert = "/etc/asi-rs/asi.crt"
+//! client_key = "/etc/asi-rs/asi.key"
+//!
+//! [polling]
+//! ccd_interval_ms = 2500
+//! efw_interval_ms = 2500
+//! snapshot_interval_ms = 60000
+//! ccd_temperature_interval_ms = 5000
+//! ccd_auto_interval_ms = 2500
+//!
+//! [output]
+//! directory = "/data/images"
+//! save = true
+//! format = "xisf"
+//! compression = "lz4"
+//! template = "{date}/{target}/{camera}_{frame_type}_{filter}_{exposure}s_{seq:04}"
+//! debayer = "edge_aware"
+//!
+//! [observatory]
+//! observer = "Jane Doe"
+//! telescope = "Esprit 100ED"
+//! site_latitude = 43.75
+//! site_longitude = 6.92
+//! site_elevation = 1270
+//! focal_length = 550
+//! aperture = 100
+//!
+//! [preview]
+//! enabled = true
+//! max_size = 1024
+//! quality = 80
+//! debayer = "bilinear"
+//! stretch = "mtf"
+//! linked = true
+//! shadows_clip = -2.8
+//! target_background = 0.25
+//! video_interval_ms = 1000
+//!
+//! [stats]
+//! enabled = true
+//! histogram_bins = 1024
+//!
+//! [stars]
+//! enabled = true
+//! threshold = 5.0
+//!
+//! [transfer]
+//! chunk_size = 262144
+//! keep_frames = 2
+//! publish_timeout_ms = 10000
+//! publish_retries = 3
+//!
+//! [efw]
+//! move_policy = "queue"
+//! names_file = "/var/lib/asi-rs/efw-names.json"
+//! ```
+
+use crate::image::DebayerMethod;
+use crate::naming::Template;
+use crate::stretch::{Stretch, StretchMethod};
+use crate::topics::TopicRouter;
+use crate::transfer::RetryPolicy;
+use crate::xisf::Compression;
+use clap::Parser;
+use log::warn;
+use rumqttc::{MqttOptions, TlsConfiguration, Transport};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The daemon loading the configuration, some values only make sense for
+/// one of them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Daemon {
+    Ccd,
+    Efw,
+}
+
+impl Daemon {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Daemon::Ccd => "asi_ccd",
+            Daemon::Efw => "asi_efw",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    Invalid {
+        field: &'static str,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "unable to read {}: {}", path.display(), source)
+            }
+            ConfigError::Parse { path, source } => {
+                write!(f, "invalid config file {}: {}", path.display(), source)
+            }
+            ConfigError::Invalid { field, reason } => {
+                write!(f, "invalid value for `{}`: {}", field, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            ConfigError::Parse { source, .. } => Some(source),
+            ConfigError::Invalid { .. } => None,
+        }
+    }
+}
+
+fn invalid(field: &'static str, reason: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        field,
+        reason: reason.into(),
+    }
+}
+
+/// Command line flags, every flag can also be set through the environment
+#[derive(Debug, Default, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path of the TOML configuration file
+    #[arg(short, long, env = "ASI_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Hostname or IP address of the MQTT broker
+    #[arg(long, env = "ASI_MQTT_HOST")]
+    pub mqtt_host: Option<String>,
+    #[arg(long, env = "ASI_MQTT_PORT")]
+    pub mqtt_port: Option<u16>,
+    #[arg(long, env = "ASI_MQTT_USERNAME")]
+    pub mqtt_username: Option<String>,
+    /// Prefer the environment variable, flags are visible to other users
+    #[arg(long, env = "ASI_MQTT_PASSWORD", hide_env_values = true)]
+    pub mqtt_password: Option<String>,
+    /// Read the MQTT password from this file
+    #[arg(long, env = "ASI_MQTT_PASSWORD_FILE")]
+    pub mqtt_password_file: Option<PathBuf>,
+    /// Connect to the broker using TLS
+    #[arg(long, env = "ASI_MQTT_TLS")]
+    pub mqtt_tls: bool,
+    /// PEM file with the CA certificates used to verify the broker, the
+    /// system ones are used if not set
+    #[arg(long, env = "ASI_MQTT_CA_FILE")]
+    pub mqtt_ca_file: Option<PathBuf>,
+    /// PEM client certificate, for brokers requiring mutual TLS
+    #[arg(long, env = "ASI_MQTT_CLIENT_CERT")]
+    pub mqtt_client_cert: Option<PathBuf>,
+    /// PEM private key of the client certificate
+    #[arg(long, env = "ASI_MQTT_CLIENT_KEY")]
+    pub mqtt_client_key: Option<PathBuf>,
+    /// Prepended to the MQTT client ID of every connection
+    #[arg(long, env = "ASI_CLIENT_ID_PREFIX")]
+    pub client_id_prefix: Option<String>,
+    /// Prepended to every topic, e.g. `observatory/east`
+    #[arg(long, env = "ASI_TOPIC_PREFIX")]
+    pub topic_prefix: Option<String>,
+    /// How often the devices are polled, in milliseconds
+    #[arg(long, env = "ASI_POLL_INTERVAL_MS")]
+    pub poll_interval_ms: Option<u64>,
+    /// How often the full state of the devices is published, in milliseconds
+    #[arg(long, env = "ASI_SNAPSHOT_INTERVAL_MS")]
+    pub snapshot_interval_ms: Option<u64>,
+    /// Where images are written
+    #[arg(long, env = "ASI_OUTPUT_DIR")]
+    pub output_dir: Option<PathBuf>,
+    /// Size in bytes of the chunks the frames are split in
+    #[arg(long, env = "ASI_CHUNK_SIZE")]
+    pub chunk_size: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub ca_file: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !self.enabled {
+            if self.ca_file.is_some() || self.client_cert.is_some() || self.client_key.is_some() {
+                return Err(invalid(
+                    "mqtt.tls.enabled",
+                    "certificates are configured but TLS is disabled",
+                ));
+            }
+            return Ok(());
+        }
+
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            return Err(invalid(
+                "mqtt.tls.client_cert",
+                "client_cert and client_key must be set together",
+            ));
+        }
+
+        if self.client_cert.is_some() && self.ca_file.is_none() {
+            return Err(invalid(
+                "mqtt.tls.ca_file",
+                "client certificates require a CA file",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Read the certificates from disk, `None` if TLS is disabled
+    fn load(&self) -> Result<Option<TlsConfiguration>, ConfigError> {
+        if !self.enabled {
+            return Ok(None);
+        }
+
+        let Some(ca_file) = &self.ca_file else {
+            return Ok(Some(TlsConfiguration::default()));
+        };
+
+        let ca = read_pem("mqtt.tls.ca_file", ca_file)?;
+        let client_auth = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Some((
+                read_pem("mqtt.tls.client_cert", cert)?,
+                read_pem("mqtt.tls.client_key", key)?,
+            )),
+            _ => None,
+        };
+
+        Ok(Some(TlsConfiguration::Simple {
+            ca,
+            alpn: None,
+            client_auth,
+        }))
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, ConfigError> {
+    std::fs::read(path).map_err(|source| ConfigError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Read a PEM file, failing early with a clear message if it is not one
+fn read_pem(field: &'static str, path: &Path) -> Result<Vec<u8>, ConfigError> {
+    let content = read_file(path)?;
+
+    if !String::from_utf8_lossy(&content).contains("-----BEGIN ") {
+        return Err(invalid(
+            field,
+            format!("{} is not a PEM file", path.display()),
+        ));
+    }
+
+    Ok(content)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// File containing the password, keeps it out of the config file
+    pub password_file: Option<PathBuf>,
+    pub client_id_prefix: String,
+    pub topic_prefix: String,
+    pub keep_alive_secs: u64,
+    pub tls: TlsConfig,
+    /// Certificates loaded from `tls`, shared by every connection
+    #[serde(skip)]
+    tls_config: Option<TlsConfiguration>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 1883,
+            username: None,
+            password: None,
+            password_file: None,
+            client_id_prefix: String::new(),
+            topic_prefix: String::new(),
+            keep_alive_secs: 5,
+            tls: TlsConfig::default(),
+            tls_config: None,
+        }
+    }
+}
+
+impl MqttConfig {
+    /// Build the client ID for a connection named `name`, e.g. `asi_ccd`
+    pub fn client_id(&self, name: &str) -> String {
+        if self.client_id_prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}-{}", self.client_id_prefix, name)
+        }
+    }
+
+    /// Router building and parsing the topics under the configured prefix
+    pub fn router(&self) -> TopicRouter {
+        TopicRouter::new(&self.topic_prefix)
+    }
+
+    /// Options for a new connection to the broker named `name`
+    pub fn options(&self, name: &str) -> MqttOptions {
+        let mut options = MqttOptions::new(self.client_id(name), &self.host, self.port);
+        options.set_keep_alive(Duration::from_secs(self.keep_alive_secs));
+
+        if let Some(username) = &self.username {
+            options.set_credentials(username, self.password.as_deref().unwrap_or_default());
+        }
+
+        if let Some(tls) = &self.tls_config {
+            options.set_transport(Transport::tls_with_config(tls.clone()));
+        }
+
+        options
+    }
+
+    /// Read the secrets and the certificates referenced by the configuration
+    fn load_secrets(&mut self) -> Result<(), ConfigError> {
+        if let Some(path) = &self.password_file {
+            let content = read_file(path)?;
+            let password = String::from_utf8(content)
+                .map_err(|_| invalid("mqtt.password_file", "the password must be UTF-8"))?;
+            self.password = Some(password.trim_end_matches(['\r', '\n']).to_string());
+        }
+
+        self.tls_config = self.tls.load()?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.host.trim().is_empty() || self.host.contains(char::is_whitespace) {
+            return Err(invalid("mqtt.host", "must be a hostname or an IP address"));
+        }
+
+        if self.port == 0 {
+            return Err(invalid("mqtt.port", "must be between 1 and 65535"));
+        }
+
+        if self.keep_alive_secs == 0 {
+            return Err(invalid("mqtt.keep_alive_secs", "must be at least 1 second"));
+        }
+
+        if self.password.is_some() && self.password_file.is_some() {
+            return Err(invalid(
+                "mqtt.password_file",
+                "set either password or password_file, not both",
+            ));
+        }
+
+        if (self.password.is_some() || self.password_file.is_some()) && self.username.is_none() {
+            return Err(invalid("mqtt.password", "a password requires a username"));
+        }
+
+        self.tls.validate()?;
+
+        if self.username.is_some() && !self.tls.enabled {
+            warn!("MQTT credentials will be sent in clear text, consider enabling TLS");
+        }
+
+        if !self
+            .client_id_prefix
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(invalid(
+                "mqtt.client_id_prefix",
+                "only ASCII letters, digits, `-` and `_` are allowed",
+            ));
+        }
+
+        if self.topic_prefix.contains(['+', '#']) {
+            return Err(invalid("mqtt.topic_prefix", "wildcards are not allowed"));
+        }
+
+        if !self.topic_prefix.is_empty() && self.topic_prefix.split('/').any(str::is_empty) {
+            return Err(invalid(
+                "mqtt.topic_prefix",
+                "must not start or end with `/` nor contain empty levels",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PollingConfig {
+    pub ccd_interval_ms: u64,
+    pub efw_interval_ms: u64,
+    /// How often the full state is published, changes only in between
+    pub snapshot_interval_ms: u64,
+    /// How often the camera temperature and cooler power are read
+    pub ccd_temperature_interval_ms: u64,
+    /// How often the controls in auto mode are read
+    pub ccd_auto_interval_ms: u64,
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        Self {
+            ccd_interval_ms: 2500,
+            efw_interval_ms: 2500,
+            snapshot_interval_ms: 60000,
+            ccd_temperature_interval_ms: 5000,
+            ccd_auto_interval_ms: 2500,
+        }
+    }
+}
+
+/// Polling faster than this only keeps the USB bus busy
+const MIN_POLL_INTERVAL_MS: u64 = 100;
+
+impl PollingConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let intervals = [
+            ("polling.ccd_interval_ms", self.ccd_interval_ms),
+            ("polling.efw_interval_ms", self.efw_interval_ms),
+            ("polling.snapshot_interval_ms", self.snapshot_interval_ms),
+            (
+                "polling.ccd_temperature_interval_ms",
+                self.ccd_temperature_interval_ms,
+            ),
+            ("polling.ccd_auto_interval_ms", self.ccd_auto_interval_ms),
+        ];
+
+        for (field, interval) in intervals {
+            if interval < MIN_POLL_INTERVAL_MS {
+                return Err(invalid(
+                    field,
+                    format!("must be at least {} ms", MIN_POLL_INTERVAL_MS),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub directory: PathBuf,
+    /// Whether the exposures are written to `directory`, the requests can
+    /// override it
+    pub save: bool,
+    /// Format of the saved exposures, the requests can override it
+    pub format: FileFormat,
+    /// Only used by XISF, FITS files are never compressed
+    pub compression: Compression,
+    /// Path of the saved files in `directory`, see `naming`, the requests
+    /// can override it
+    pub template: Template,
+    /// Only used by TIFF and PNG, raw frames of colour cameras are kept as
+    /// mosaics without it
+    pub debayer: Option<DebayerMethod>,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("images"),
+            save: false,
+            format: FileFormat::Fits,
+            compression: Compression::None,
+            template: Template::default(),
+            debayer: None,
+        }
+    }
+}
+
+/// Formats the exposures can be saved in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    #[default]
+    Fits,
+    Xisf,
+    Tiff,
+    Png,
+}
+
+impl FileFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FileFormat::Fits => "fits",
+            FileFormat::Xisf => "xisf",
+            FileFormat::Tiff => "tiff",
+            FileFormat::Png => "png",
+        }
+    }
+}
+
+impl OutputConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.directory.as_os_str().is_empty() {
+            return Err(invalid("output.directory", "must not be empty"));
+        }
+
+        // The directory is created on demand, but if something is already
+        // there it must be usable
+        if self.directory.exists() && !self.directory.is_dir() {
+            return Err(invalid(
+                "output.directory",
+                format!("{} is not a directory", self.directory.display()),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Where and how the frames are taken, the camera can't know it. The values
+/// can be changed at runtime, see the `metadata` action of `asi_ccd`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ObservatoryConfig {
+    pub observer: Option<String>,
+    pub telescope: Option<String>,
+    /// Degrees, positive to the north
+    pub site_latitude: Option<f64>,
+    /// Degrees, positive to the east
+    pub site_longitude: Option<f64>,
+    /// Meters above the sea level
+    pub site_elevation: Option<f64>,
+    /// Millimeters
+    pub focal_length: Option<f64>,
+    /// Diameter of the objective in millimeters
+    pub aperture: Option<f64>,
+}
+
+impl ObservatoryConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(lat) = self.site_latitude
+            && !(-90.0..=90.0).contains(&lat)
+        {
+            return Err(invalid(
+                "observatory.site_latitude",
+                "must be between -90 and 90 degrees",
+            ));
+        }
+        if let Some(long) = self.site_longitude
+            && !(-180.0..=180.0).contains(&long)
+        {
+            return Err(invalid(
+                "observatory.site_longitude",
+                "must be between -180 and 180 degrees",
+            ));
+        }
+
+        let lengths = [
+            ("observatory.focal_length", self.focal_length),
+            ("observatory.aperture", self.aperture),
+        ];
+        for (field, length) in lengths {
+            if length.is_some_and(|l| !(l > 0.0 && l.is_finite())) {
+                return Err(invalid(field, "must be positive"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Bounds of `preview.max_size`, the upper one keeps the previews well below
+/// the size of a frame
+const MIN_PREVIEW_SIZE: usize = 64;
+const MAX_PREVIEW_SIZE: usize = 2048;
+
+/// Lowest `preview.shadows_clip`, further down the shadows are not clipped at
+/// all on any real frame
+const MIN_SHADOWS_CLIP: f64 = -10.0;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PreviewConfig {
+    /// Whether a JPEG preview is published after each exposure and while
+    /// a video capture is running
+    pub enabled: bool,
+    /// The previews fit in a square of this many pixels
+    pub max_size: usize,
+    /// JPEG quality, from 1 to 100
+    pub quality: u8,
+    /// How the raw frames of colour cameras are turned into colour previews
+    pub debayer: DebayerMethod,
+    /// Curve of the automatic stretch
+    pub stretch: StretchMethod,
+    /// Whether the channels of colour previews are stretched together
+    pub linked: bool,
+    /// Where the shadows are clipped, in noise standard deviations from the
+    /// median of the frame
+    pub shadows_clip: f64,
+    /// Brightness of the stretched background, from 0 to 1
+    pub target_background: f64,
+    /// How often a frame of a running video capture is published as a
+    /// preview
+    pub video_interval_ms: u64,
+}
+
+impl Default for PreviewConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_size: 1024,
+            quality: 80,
+            debayer: DebayerMethod::Bilinear,
+            stretch: StretchMethod::Mtf,
+            linked: true,
+            shadows_clip: -2.8,
+            target_background: 0.25,
+            video_interval_ms: 1000,
+        }
+    }
+}
+
+impl PreviewConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !(MIN_PREVIEW_SIZE..=MAX_PREVIEW_SIZE).contains(&self.max_size) {
+            return Err(invalid(
+                "preview.max_size",
+                format!(
+                    "must be between {} and {} pixels",
+                    MIN_PREVIEW_SIZE, MAX_PREVIEW_SIZE
+                ),
+            ));
+        }
+
+        if !(1..=100).contains(&self.quality) {
+            return Err(invalid("preview.quality", "must be between 1 and 100"));
+        }
+
+        if !(MIN_SHADOWS_CLIP..=0.0).contains(&self.shadows_clip) {
+            return Err(invalid(
+                "preview.shadows_clip",
+                format!("must be between {} and 0", MIN_SHADOWS_CLIP),
+            ));
+        }
+
+        if !(self.target_background > 0.0 && self.target_background < 1.0) {
+            return Err(invalid(
+                "preview.target_background",
+                "must be between 0 and 1, both excluded",
+            ));
+        }
+
+        if self.video_interval_ms < MIN_POLL_INTERVAL_MS {
+            return Err(invalid(
+                "preview.video_interval_ms",
+                format!("must be at least {} ms", MIN_POLL_INTERVAL_MS),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn video_interval(&self) -> Duration {
+        Duration::from_millis(self.video_interval_ms)
+    }
+
+    /// Automatic stretch of the previews
+    pub fn auto_stretch(&self) -> Stretch {
+        Stretch {
+            method: self.stretch,
+            linked: self.linked,
+            shadows_clip: self.shadows_clip,
+            target_background: self.target_background,
+        }
+    }
+
+    /// Generous upper bound of the size of a preview in bytes: the
+    /// uncompressed RGB image plus room for the JPEG headers
+    pub fn max_bytes(&self) -> usize {
+        self.max_size * self.max_size * 3 + 64 * 1024
+    }
+}
+
+/// Sizes accepted for the histograms of the frame statistics
+const HISTOGRAM_BINS: [usize; 2] = [256, 1024];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsConfig {
+    /// Whether the statistics of every exposure are published
+    pub enabled: bool,
+    /// Number of bins of the histograms, 256 or 1024
+    pub histogram_bins: usize,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            histogram_bins: 256,
+        }
+    }
+}
+
+impl StatsConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !HISTOGRAM_BINS.contains(&self.histogram_bins) {
+            return Err(invalid("stats.histogram_bins", "must be 256 or 1024"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Bounds of `stars.threshold`, in multiples of the background noise
+const MIN_STAR_THRESHOLD: f64 = 2.0;
+const MAX_STAR_THRESHOLD: f64 = 100.0;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StarsConfig {
+    /// Whether the stars of the light frames are measured
+    pub enabled: bool,
+    /// Detection threshold above the background, in multiples of its noise
+    pub threshold: f64,
+}
+
+impl Default for StarsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: 5.0,
+        }
+    }
+}
+
+impl StarsConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !(MIN_STAR_THRESHOLD..=MAX_STAR_THRESHOLD).contains(&self.threshold) {
+            return Err(invalid(
+                "stars.threshold",
+                format!(
+                    "must be between {} and {}",
+                    MIN_STAR_THRESHOLD, MAX_STAR_THRESHOLD
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Bounds of `transfer.chunk_size`, the upper one keeps the chunks well below
+/// the 256 MB limit of an MQTT message
+const MIN_CHUNK_SIZE: usize = 1024;
+const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransferConfig {
+    /// Size in bytes of the chunks the frames are split in
+    pub chunk_size: usize,
+    /// How many frames are kept in memory to answer the resend requests
+    pub keep_frames: usize,
+    /// How long the broker may take to acknowledge a message of a frame
+    pub publish_timeout_ms: u64,
+    /// How many times a message that wasn't acknowledged is published again
+    pub publish_retries: u32,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 256 * 1024,
+            keep_frames: 2,
+            publish_timeout_ms: 10000,
+            publish_retries: 3,
+        }
+    }
+}
+
+impl TransferConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&self.chunk_size) {
+            return Err(invalid(
+                "transfer.chunk_size",
+                format!(
+                    "must be between {} and {} bytes",
+                    MIN_CHUNK_SIZE, MAX_CHUNK_SIZE
+                ),
+            ));
+        }
+
+        if self.publish_timeout_ms < MIN_POLL_INTERVAL_MS {
+            return Err(invalid(
+                "transfer.publish_timeout_ms",
+                format!("must be at least {} ms", MIN_POLL_INTERVAL_MS),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            timeout: Duration::from_millis(self.publish_timeout_ms),
+            retries: self.publish_retries,
+        }
+    }
+}
+
+/// What a filter wheel does with a move requested while it is already moving
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MovePolicy {
+    /// Run the move as soon as the ongoing one is finished
+    #[default]
+    Queue,
+    /// Drop the move and reply to the client that the wheel is busy
+    Reject,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EfwConfig {
+    pub move_policy: MovePolicy,
+    /// Where the aliases and the filter names of the wheels are kept between
+    /// runs
+    pub names_file: PathBuf,
+}
+
+impl Default for EfwConfig {
+    fn default() -> Self {
+        Self {
+            move_policy: MovePolicy::Queue,
+            names_file: PathBuf::from("efw-names.json"),
+        }
+    }
+}
+
+impl EfwConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.names_file.as_os_str().is_empty() {
+            return Err(invalid("efw.names_file", "must not be empty"));
+        }
+
+        if self.names_file.is_dir() {
+            return Err(invalid(
+                "efw.names_file",
+                format!("{} is a directory", self.names_file.display()),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub mqtt: MqttConfig,
+    pub polling: PollingConfig,
+    pub output: OutputConfig,
+    pub observatory: ObservatoryConfig,
+    pub preview: PreviewConfig,
+    pub stats: StatsConfig,
+    pub stars: StarsConfig,
+    pub transfer: TransferConfig,
+    pub efw: EfwConfig,
+}
+
+impl Config {
+    /// Load the configuration for `daemon` from the command line, the
+    /// environment and the optional config file, then validate it.
+    pub fn from_args(daemon: Daemon) -> Result<Self, ConfigError> {
+        Self::load(Cli::parse(), daemon)
+    }
+
+    pub fn load(cli: Cli, daemon: Daemon) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        config.apply(cli, daemon);
+        config.validate()?;
+        config.mqtt.load_secrets()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        toml::from_str(&content).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Override the values from the file with the ones from the command line
+    /// and the environment.
+    fn apply(&mut self, cli: Cli, daemon: Daemon) {
+        if let Some(host) = cli.mqtt_host {
+            self.mqtt.host = host;
+        }
+        if let Some(port) = cli.mqtt_port {
+            self.mqtt.port = port;
+        }
+        if let Some(username) = cli.mqtt_username {
+            self.mqtt.username = Some(username);
+        }
+        if let Some(password) = cli.mqtt_password {
+            self.mqtt.password = Some(password);
+            self.mqtt.password_file = None;
+        }
+        if let Some(path) = cli.mqtt_password_file {
+            self.mqtt.password_file = Some(path);
+            self.mqtt.password = None;
+        }
+        if cli.mqtt_tls {
+            self.mqtt.tls.enabled = true;
+        }
+        if let Some(path) = cli.mqtt_ca_file {
+            self.mqtt.tls.ca_file = Some(path);
+        }
+        if let Some(path) = cli.mqtt_client_cert {
+            self.mqtt.tls.client_cert = Some(path);
+        }
+        if let Some(path) = cli.mqtt_client_key {
+            self.mqtt.tls.client_key = Some(path);
+        }
+        if let Some(prefix) = cli.client_id_prefix {
+            self.mqtt.client_id_prefix = prefix;
+        }
+        if let Some(prefix) = cli.topic_prefix {
+            self.mqtt.topic_prefix = prefix;
+        }
+        if let Some(interval) = cli.poll_interval_ms {
+            match daemon {
+                Daemon::Ccd => self.polling.ccd_interval_ms = interval,
+                Daemon::Efw => self.polling.efw_interval_ms = interval,
+            }
+        }
+        if let Some(interval) = cli.snapshot_interval_ms {
+            self.polling.snapshot_interval_ms = interval;
+        }
+        if let Some(dir) = cli.output_dir {
+            self.output.directory = dir;
+        }
+        if let Some(size) = cli.chunk_size {
+            self.transfer.chunk_size = size;
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.mqtt.validate()?;
+        self.polling.validate()?;
+        self.output.validate()?;
+        self.observatory.validate()?;
+        self.preview.validate()?;
+        self.stats.validate()?;
+        self.stars.validate()?;
+        self.transfer.validate()?;
+        self.efw.validate()
+    }
+
+    /// How often `daemon` should poll its devices
+    pub fn poll_interval(&self, daemon: Daemon) -> Duration {
+        Duration::from_millis(match daemon {
+            Daemon::Ccd => self.polling.ccd_interval_ms,
+            Daemon::Efw => self.polling.efw_interval_ms,
+        })
+    }
+
+    /// How often the full state of a device is published
+    pub fn snapshot_interval(&self) -> Duration {
+        Duration::from_millis(self.polling.snapshot_interval_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn parse(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    /// Field reported by the validation of `toml`
+    fn invalid_field(toml: &str) -> &'static str {
+        match parse(toml).validate() {
+            Err(ConfigError::Invalid { field, .. }) => field,
+            other => panic!("expected an invalid value, got {:?}", other),
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("asi-rs-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn parses_documented_example() {
+        let doc = include_str!("config.rs")
+            .lines()
+            .take_while(|l| l.starts_with("//!"))
+            .map(|l| l.trim_start_matches("//!").trim_start())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let example = doc
+            .split("```toml")
+            .nth(1)
+            .unwrap()
+            .split("```")
+            .next()
+            .unwrap();
+
+        let config = parse(example);
+        config.validate().unwrap();
+        assert_eq!(config.mqtt.port, 8883);
+        assert_eq!(config.output.format, FileFormat::Xisf);
+        assert_eq!(config.output.compression, Compression::Lz4);
+        assert_eq!(config.stats.histogram_bins, 1024);
+        assert_eq!(config.transfer.chunk_size, 262144);
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(toml::from_str::<Config>("[mqtt]\nhots = \"broker\"").is_err());
+    }
+
+    #[test]
+    fn parses_move_policy() {
+        assert_eq!(Config::default().efw.move_policy, MovePolicy::Queue);
+        let config = parse("[efw]\nmove_policy = \"reject\"");
+        assert_eq!(config.efw.move_policy, MovePolicy::Reject);
+        assert!(toml::from_str::<Config>("[efw]\nmove_policy = \"drop\"").is_err());
+    }
+
+    #[test]
+    fn reports_invalid_values() {
+        let cases = [
+            ("[mqtt]\nhost = \"\"", "mqtt.host"),
+            ("[mqtt]\nport = 0", "mqtt.port"),
+            ("[mqtt]\npassword = \"secret\"", "mqtt.password"),
+            (
+                "[mqtt]\nclient_id_prefix = \"dome east\"",
+                "mqtt.client_id_prefix",
+            ),
+            ("[mqtt]\ntopic_prefix = \"obs/+\"", "mqtt.topic_prefix"),
+            ("[mqtt]\ntopic_prefix = \"obs/\"", "mqtt.topic_prefix"),
+            ("[mqtt.tls]\nca_file = \"ca.pem\"", "mqtt.tls.enabled"),
+            (
+                "[mqtt.tls]\nenabled = true\nclient_cert = \"asi.crt\"",
+                "mqtt.tls.client_cert",
+            ),
+            ("[polling]\nccd_interval_ms = 10", "polling.ccd_interval_ms"),
+            (
+                "[observatory]\nsite_latitude = 91.0",
+                "observatory.site_latitude",
+            ),
+            ("[observatory]\naperture = 0.0", "observatory.aperture"),
+            ("[preview]\nmax_size = 32", "preview.max_size"),
+            ("[preview]\nquality = 0", "preview.quality"),
+            ("[preview]\nshadows_clip = 1.0", "preview.shadows_clip"),
+            (
+                "[preview]\ntarget_background = 1.0",
+                "preview.target_background",
+            ),
+            (
+                "[preview]\nvideo_interval_ms = 10",
+                "preview.video_interval_ms",
+            ),
+            ("[stats]\nhistogram_bins = 512", "stats.histogram_bins"),
+            ("[stars]\nthreshold = 1.0", "stars.threshold"),
+            ("[transfer]\nchunk_size = 100", "transfer.chunk_size"),
+            (
+                "[transfer]\npublish_timeout_ms = 0",
+                "transfer.publish_timeout_ms",
+            ),
+            ("[efw]\nnames_file = \"\"", "efw.names_file"),
+        ];
+
+        for (toml, field) in cases {
+            assert_eq!(invalid_field(toml), field, "{}", toml);
+        }
+    }
+
+    #[test]
+    fn output_directory_must_be_a_directory() {
+        let dir = temp_dir();
+        let file = dir.join("images");
+        fs::write(&file, []).unwrap();
+
+        let mut config = Config::default();
+        config.output.directory = file;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::Invalid {
+                field: "output.directory",
+                ..
+            })
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let dir = temp_dir();
+        let path = dir.join("asi.toml");
+        fs::write(
+            &path,
+            "[mqtt]\nhost = \"broker\"\npassword = \"from-file\"\nusername = \"asi\"\n\n[polling]\nefw_interval_ms = 1000\n",
+        )
+        .unwrap();
+        let password_file = dir.join("password");
+        fs::write(&password_file, "secret\n").unwrap();
+
+        let cli = Cli {
+            config: Some(path),
+            mqtt_port: Some(1884),
+            mqtt_password_file: Some(password_file),
+            poll_interval_ms: Some(500),
+            ..Cli::default()
+        };
+        let config = Config::load(cli, Daemon::Efw).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(config.mqtt.host, "broker");
+        assert_eq!(config.mqtt.port, 1884);
+        assert_eq!(config.mqtt.password.as_deref(), Some("secret"));
+        assert_eq!(
+            config.poll_interval(Daemon::Efw),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            config.poll_interval(Daemon::Ccd),
+            Duration::from_millis(2500)
+        );
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_pem() {
+        let dir = temp_dir();
+        let ca_file = dir.join("ca.pem");
+        fs::write(&ca_file, "not a certificate").unwrap();
+
+        let tls = TlsConfig {
+            enabled: true,
+            ca_file: Some(ca_file),
+            ..TlsConfig::default()
+        };
+        let result = tls.load();
+        fs::remove_dir_all(dir).unwrap();
+
+        assert!(matches!(
+            result,
+            Err(ConfigError::Invalid {
+                field: "mqtt.tls.ca_file",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn prefixes_client_ids() {
+        let mut mqtt = MqttConfig::default();
+        assert_eq!(mqtt.client_id("asi_ccd"), "asi_ccd");
+        mqtt.client_id_prefix = "dome-east".into();
+        assert_eq!(mqtt.client_id("asi_ccd"), "dome-east-asi_ccd");
+    }
+}
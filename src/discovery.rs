@@ -0,0 +1,149 @@
+//! Announcement of the devices handled by the daemons.
+//!
+//! Every device publishes a retained announcement on
+//! `<prefix>/discovery/<id>`, a client subscribing to `discovery/+` gets the
+//! list of devices straight away. Publishing anything on `<prefix>/discover`
+//! makes every daemon announce its devices again.
+
+use crate::topics::TopicRouter;
+use log::{debug, error};
+use rumqttc::{AsyncClient, QoS};
+use serde::Serialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+/// Topic listening for discovery requests, relative to the prefix
+pub const DISCOVER_PATH: &str = "discover";
+
+/// Version of the daemons, sent along with every announcement
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+    Camera,
+    Efw,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Announcement {
+    /// Name of the daemon handling the device
+    pub driver: &'static str,
+    pub version: &'static str,
+    pub kind: DeviceKind,
+    pub id: Uuid,
+    pub model: String,
+    /// `None` for devices that don't report a serial number
+    pub serial: Option<String>,
+    /// Summary of what the device can do, depends on `kind`
+    pub capabilities: Value,
+}
+
+impl Announcement {
+    pub fn new(
+        driver: &'static str,
+        kind: DeviceKind,
+        id: Uuid,
+        model: impl Into<String>,
+        serial: Option<String>,
+        capabilities: Value,
+    ) -> Self {
+        Self {
+            driver,
+            version: VERSION,
+            kind,
+            id,
+            model: model.into(),
+            serial,
+            capabilities,
+        }
+    }
+}
+
+/// Publish the retained announcement of every device
+pub async fn announce(client: &AsyncClient, router: &TopicRouter, announcements: &[Announcement]) {
+    for a in announcements {
+        let payload = match serde_json::to_string(a) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("Unable to serialize the announcement of {}: {}", a.id, e);
+                continue;
+            }
+        };
+
+        debug!("Announcing {} {}", a.model, a.id);
+        if let Err(e) = client
+            .publish(router.discovery(a.id), QoS::AtLeastOnce, true, payload)
+            .await
+        {
+            error!("Unable to announce {}: {}", a.id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::Request;
+    use serde_json::json;
+
+    fn efw() -> Announcement {
+        let capabilities = json!({"slots": 7, "unidirectional": true});
+        Announcement::new(
+            "asi_efw",
+            DeviceKind::Efw,
+            Uuid::new_v4(),
+            "ZWO EFW",
+            None,
+            capabilities,
+        )
+    }
+
+    #[test]
+    fn announcement_payload() {
+        let efw = efw();
+
+        assert_eq!(
+            serde_json::to_value(&efw).unwrap(),
+            json!({
+                "driver": "asi_efw",
+                "version": VERSION,
+                "kind": "efw",
+                "id": efw.id,
+                "model": "ZWO EFW",
+                "serial": null,
+                "capabilities": {"slots": 7, "unidirectional": true},
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn announcements_are_retained_on_their_topic() {
+        let router = TopicRouter::new("obs");
+        let camera = Announcement::new(
+            "asi_ccd",
+            DeviceKind::Camera,
+            Uuid::new_v4(),
+            "ZWO ASI294MM Pro",
+            Some("1a2b3c".into()),
+            json!({}),
+        );
+        let announcements = [efw(), camera];
+        let (tx, rx) = flume::bounded(10);
+
+        announce(&AsyncClient::from_senders(tx), &router, &announcements).await;
+
+        let published: Vec<Request> = rx.try_iter().collect();
+        assert_eq!(published.len(), announcements.len());
+        for (request, announcement) in published.iter().zip(&announcements) {
+            let Request::Publish(publish) = request else {
+                panic!("unexpected request {:?}", request);
+            };
+            assert_eq!(publish.topic, format!("obs/discovery/{}", announcement.id));
+            assert_eq!((publish.qos, publish.retain), (QoS::AtLeastOnce, true));
+            let payload: Value = serde_json::from_slice(&publish.payload).unwrap();
+            assert_eq!(payload, serde_json::to_value(announcement).unwrap());
+        }
+        assert_eq!(router.topic(DISCOVER_PATH), "obs/discover");
+    }
+}
@@ -0,0 +1,520 @@
+//! Exports of the frames for display rather than processing: lossless TIFF and
+//! PNG files, and small JPEG previews.
+//!
+//! Mono frames are written as 16 bit greyscale, 8 bit samples being scaled to
+//! the whole 16 bit range, and colour frames as 8 bit RGB. Raw frames of colour
+//! cameras are either kept as greyscale mosaics or debayered to RGB, with the
+//! bit depth of the frame. Previews are always in colour when possible, binned
+//! down to fit in a square of a given size and stretched with
+//! [`crate::stretch`] so that the faint parts of the frame are visible. Mosaics
+//! are binned before being debayered, only the small frame is interpolated.
+
+use crate::fits::{Image, PixelFormat};
+use crate::image::{self, BayerPattern, DebayerMethod};
+use crate::stretch::Stretch;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::Path;
+use tiff::encoder::colortype::{Gray16, RGB16, RGB8};
+use tiff::encoder::compression::DeflateLevel;
+use tiff::encoder::{Compression, Predictor, TiffEncoder};
+
+/// Samples written to the TIFF and PNG files
+enum Pixels {
+    Gray16(Vec<u16>),
+    Rgb8(Vec<u8>),
+    Rgb16(Vec<u16>),
+}
+
+/// Write `image` as a PNG file to `path`, creating the parent directories.
+/// Mosaics are debayered with `debayer` if set.
+pub fn write_png(path: &Path, image: &Image, debayer: Option<DebayerMethod>) -> io::Result<()> {
+    let pixels = pixels(image, debayer)?;
+    let out = BufWriter::new(create(path)?);
+
+    let mut encoder = png::Encoder::new(out, image.width as u32, image.height as u32);
+    // PNG stores the samples in big endian
+    let data = match pixels {
+        Pixels::Rgb8(data) => {
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.set_depth(png::BitDepth::Eight);
+            data
+        }
+        Pixels::Rgb16(data) => {
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.set_depth(png::BitDepth::Sixteen);
+            data.iter().flat_map(|v| v.to_be_bytes()).collect()
+        }
+        Pixels::Gray16(data) => {
+            encoder.set_color(png::ColorType::Grayscale);
+            encoder.set_depth(png::BitDepth::Sixteen);
+            data.iter().flat_map(|v| v.to_be_bytes()).collect()
+        }
+    };
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish().map_err(io::Error::from)
+}
+
+/// Write `image` as a deflate compressed TIFF file to `path`, creating the
+/// parent directories. Mosaics are debayered with `debayer` if set.
+pub fn write_tiff(path: &Path, image: &Image, debayer: Option<DebayerMethod>) -> io::Result<()> {
+    let pixels = pixels(image, debayer)?;
+    let out = BufWriter::new(create(path)?);
+
+    let mut encoder = TiffEncoder::new(out)
+        .map_err(tiff_error)?
+        .with_compression(Compression::Deflate(DeflateLevel::Balanced))
+        .with_predictor(Predictor::Horizontal);
+    let (width, height) = (image.width as u32, image.height as u32);
+    match pixels {
+        Pixels::Rgb8(data) => encoder.write_image::<RGB8>(width, height, &data),
+        Pixels::Rgb16(data) => encoder.write_image::<RGB16>(width, height, &data),
+        Pixels::Gray16(data) => encoder.write_image::<Gray16>(width, height, &data),
+    }
+    .map_err(tiff_error)
+}
+
+/// JPEG preview of `image`, binned to fit in `max_size` x `max_size`,
+/// debayered with `debayer` if it is a mosaic and stretched with `stretch`
+pub fn preview(
+    image: &Image,
+    max_size: usize,
+    quality: u8,
+    debayer: DebayerMethod,
+    stretch: &Stretch,
+) -> io::Result<Vec<u8>> {
+    image.check()?;
+    let factor = image.width.max(image.height).div_ceil(max_size).max(1);
+    let (width, height) = (image.width, image.height);
+
+    let (channels, max, width, height, binned) = match (image.format, image.bayer) {
+        (PixelFormat::Bgr24, _) => {
+            let rgb: Vec<u16> = image::bgr_to_rgb(image.data)
+                .into_iter()
+                .map(u16::from)
+                .collect();
+            let (width, height, binned) = bin(&rgb, width, height, 3, factor);
+            (3, 255.0, width, height, binned)
+        }
+        (_, None) => {
+            let (width, height, binned) = bin(&gray16(image), width, height, 1, factor);
+            (1, 65535.0, width, height, binned)
+        }
+        (_, Some(pattern)) => {
+            let (width, height, mosaic) =
+                bin_mosaic(&gray16(image), width, height, pattern, factor);
+            let data: Vec<u8> = mosaic.iter().flat_map(|v| v.to_le_bytes()).collect();
+            let binned = Image {
+                width,
+                height,
+                format: PixelFormat::Mono16,
+                bayer: Some(pattern),
+                data: &data,
+            };
+            let rgb = image::to_rgb(&binned, debayer)?.map_or_else(Vec::new, |rgb| rgb.data);
+            let samples = rgb.into_iter().map(f64::from).collect();
+            (3, 65535.0, width, height, samples)
+        }
+    };
+    let stretched = stretch.apply(&binned, channels, max);
+
+    let color = if channels == 3 {
+        jpeg_encoder::ColorType::Rgb
+    } else {
+        jpeg_encoder::ColorType::Luma
+    };
+    let mut jpeg = Vec::new();
+    jpeg_encoder::Encoder::new(&mut jpeg, quality)
+        .encode(&stretched, width as u16, height as u16, color)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(jpeg)
+}
+
+fn create(path: &Path) -> io::Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    File::create(path)
+}
+
+fn tiff_error(e: tiff::TiffError) -> io::Error {
+    match e {
+        tiff::TiffError::IoError(e) => e,
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+/// Colour conversion of `image` for the TIFF and PNG files
+fn pixels(image: &Image, debayer: Option<DebayerMethod>) -> io::Result<Pixels> {
+    image.check()?;
+    if let Some(method) = debayer
+        && let Some(rgb) = image::to_rgb(image, method)?
+    {
+        return Ok(if rgb.bit_depth == 8 {
+            Pixels::Rgb8(rgb.data.iter().map(|v| *v as u8).collect())
+        } else {
+            Pixels::Rgb16(rgb.data)
+        });
+    }
+
+    Ok(match image.format {
+        PixelFormat::Bgr24 => Pixels::Rgb8(image::bgr_to_rgb(image.data)),
+        PixelFormat::Mono8 | PixelFormat::Mono16 => Pixels::Gray16(gray16(image)),
+    })
+}
+
+/// Samples of a mono frame, 8 bit ones scaled to 16 bit
+fn gray16(image: &Image) -> Vec<u16> {
+    match image.format {
+        PixelFormat::Mono8 => image.data.iter().map(|v| u16::from(*v) * 257).collect(),
+        _ => image
+            .data
+            .chunks_exact(2)
+            .map(|p| u16::from_le_bytes([p[0], p[1]]))
+            .collect(),
+    }
+}
+
+/// Average the blocks of `factor` x `factor` pixels of interleaved samples,
+/// the incomplete blocks on the right and bottom edges are dropped
+fn bin(
+    samples: &[u16],
+    width: usize,
+    height: usize,
+    channels: usize,
+    factor: usize,
+) -> (usize, usize, Vec<f64>) {
+    let (out_width, out_height) = ((width / factor).max(1), (height / factor).max(1));
+    let mut binned = vec![0.0; out_width * out_height * channels];
+    let count = (factor.min(width) * factor.min(height)) as f64;
+
+    for y in 0..(out_height * factor).min(height) {
+        let row = &samples[y * width * channels..(y + 1) * width * channels];
+        for x in 0..(out_width * factor).min(width) {
+            let out = ((y / factor) * out_width + x / factor) * channels;
+            for c in 0..channels {
+                binned[out + c] += f64::from(row[x * channels + c]);
+            }
+        }
+    }
+    binned.iter_mut().for_each(|v| *v /= count);
+
+    (out_width, out_height, binned)
+}
+
+/// Bin a mosaic by `factor` keeping its colour filter array: every pixel
+/// averages the samples of its own colour in its block of `factor` x `factor`
+/// pixels, the incomplete blocks on the right and bottom edges are dropped
+fn bin_mosaic(
+    samples: &[u16],
+    width: usize,
+    height: usize,
+    pattern: BayerPattern,
+    factor: usize,
+) -> (usize, usize, Vec<u16>) {
+    if factor == 1 {
+        return (width, height, samples.to_vec());
+    }
+
+    let (out_width, out_height) = ((width / factor).max(1), (height / factor).max(1));
+    let mut sums = vec![(0_u64, 0_u64); out_width * out_height];
+    for y in 0..(out_height * factor).min(height) {
+        for x in 0..(out_width * factor).min(width) {
+            let (bx, by) = (x / factor, y / factor);
+            if pattern.channel(x, y) == pattern.channel(bx, by) {
+                let sum = &mut sums[by * out_width + bx];
+                sum.0 += u64::from(samples[y * width + x]);
+                sum.1 += 1;
+            }
+        }
+    }
+
+    let binned = sums
+        .iter()
+        .map(|(sum, count)| (*sum as f64 / (*count).max(1) as f64).round() as u16)
+        .collect();
+    (out_width, out_height, binned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PreviewConfig;
+    use crate::transfer;
+
+    /// Width and height of a baseline JPEG, from its start of frame
+    fn jpeg_size(jpeg: &[u8]) -> (usize, usize) {
+        assert_eq!(jpeg[..2], [0xFF, 0xD8]);
+        let sof = jpeg
+            .windows(2)
+            .position(|m| m == [0xFF, 0xC0])
+            .expect("start of frame");
+        let value = |at: usize| usize::from(u16::from_be_bytes([jpeg[at], jpeg[at + 1]]));
+        (value(sof + 7), value(sof + 5))
+    }
+
+    /// Colour type, bit depth and samples of a PNG file
+    type DecodedPng = (png::ColorType, png::BitDepth, Vec<u16>);
+
+    /// The PNG and TIFF files written from `image`, decoded
+    fn round_trip(
+        image: &Image,
+        debayer: Option<DebayerMethod>,
+    ) -> (DecodedPng, (tiff::ColorType, Vec<u16>)) {
+        let dir = std::env::temp_dir().join(format!("asi-rs-{}", uuid::Uuid::new_v4()));
+        let (png_path, tiff_path) = (dir.join("frame.png"), dir.join("frame.tiff"));
+        write_png(&png_path, image, debayer).unwrap();
+        write_tiff(&tiff_path, image, debayer).unwrap();
+
+        let file = io::BufReader::new(File::open(&png_path).unwrap());
+        let mut reader = png::Decoder::new(file).read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut data).unwrap();
+        assert_eq!(
+            (info.width, info.height),
+            (image.width as u32, image.height as u32)
+        );
+        let samples = match info.bit_depth {
+            png::BitDepth::Sixteen => data
+                .chunks_exact(2)
+                .map(|p| u16::from_be_bytes([p[0], p[1]]))
+                .collect(),
+            _ => data.iter().map(|v| u16::from(*v)).collect(),
+        };
+        let png = (info.color_type, info.bit_depth, samples);
+
+        let mut decoder = tiff::decoder::Decoder::new(File::open(&tiff_path).unwrap()).unwrap();
+        assert_eq!(
+            decoder.dimensions().unwrap(),
+            (image.width as u32, image.height as u32)
+        );
+        let color = decoder.colortype().unwrap();
+        let samples = match decoder.read_image().unwrap() {
+            tiff::decoder::DecodingResult::U8(data) => data.into_iter().map(u16::from).collect(),
+            tiff::decoder::DecodingResult::U16(data) => data,
+            other => panic!("unexpected TIFF samples {:?}", other),
+        };
+
+        fs::remove_dir_all(dir).unwrap();
+        (png, (color, samples))
+    }
+
+    #[test]
+    fn writes_mono_frames_in_16_bit() {
+        let expected = vec![0, 257, 128 * 257, 65535];
+        let mono8 = [0, 1, 128, 255];
+        let mono16: Vec<u8> = expected
+            .iter()
+            .flat_map(|v: &u16| v.to_le_bytes())
+            .collect();
+
+        for (format, data) in [
+            (PixelFormat::Mono8, &mono8[..]),
+            (PixelFormat::Mono16, &mono16[..]),
+        ] {
+            let image = Image {
+                width: 2,
+                height: 2,
+                format,
+                bayer: None,
+                data,
+            };
+            let (png, tiff) = round_trip(&image, None);
+            assert_eq!(
+                png,
+                (
+                    png::ColorType::Grayscale,
+                    png::BitDepth::Sixteen,
+                    expected.clone()
+                )
+            );
+            assert_eq!(tiff, (tiff::ColorType::Gray(16), expected.clone()));
+        }
+    }
+
+    #[test]
+    fn writes_colour_frames_in_rgb() {
+        let bgr = [10, 20, 30, 40, 50, 60];
+        let image = Image {
+            width: 2,
+            height: 1,
+            format: PixelFormat::Bgr24,
+            bayer: None,
+            data: &bgr,
+        };
+        let rgb = vec![30, 20, 10, 60, 50, 40];
+        let (png, tiff) = round_trip(&image, None);
+        assert_eq!(
+            png,
+            (png::ColorType::Rgb, png::BitDepth::Eight, rgb.clone())
+        );
+        assert_eq!(tiff, (tiff::ColorType::RGB(8), rgb));
+
+        // Mosaics are debayered with the bit depth of the frame, or kept as is
+        let (width, height) = (4, 4);
+        let mosaic8: Vec<u8> = (0..width * height).map(|i| i as u8 * 10).collect();
+        let mosaic16: Vec<u8> = (0..width * height)
+            .flat_map(|i| (i as u16 * 1000).to_le_bytes())
+            .collect();
+        for (format, data, depth, bits) in [
+            (PixelFormat::Mono8, &mosaic8, png::BitDepth::Eight, 8),
+            (PixelFormat::Mono16, &mosaic16, png::BitDepth::Sixteen, 16),
+        ] {
+            let image = Image {
+                width,
+                height,
+                format,
+                bayer: Some(BayerPattern::Rggb),
+                data,
+            };
+            let ((color, png_depth, samples), (tiff_color, tiff_samples)) =
+                round_trip(&image, Some(DebayerMethod::Bilinear));
+            assert_eq!((color, png_depth), (png::ColorType::Rgb, depth));
+            assert_eq!(tiff_color, tiff::ColorType::RGB(bits));
+            assert_eq!(samples.len(), width * height * 3);
+            assert_eq!(samples, tiff_samples);
+
+            let ((color, png_depth, _), (tiff_color, _)) = round_trip(&image, None);
+            assert_eq!(
+                (color, png_depth),
+                (png::ColorType::Grayscale, png::BitDepth::Sixteen)
+            );
+            assert_eq!(tiff_color, tiff::ColorType::Gray(16));
+        }
+    }
+
+    #[test]
+    fn previews_stay_under_max_bytes() {
+        // Colour noise at the default settings
+        let config = PreviewConfig::default();
+        let (width, height) = (config.max_size, config.max_size * 3 / 4);
+        let mut seed = 0x9e37_79b9_u32;
+        let bgr: Vec<u8> = (0..width * height * 3)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                seed as u8
+            })
+            .collect();
+        let image = Image {
+            width,
+            height,
+            format: PixelFormat::Bgr24,
+            bayer: None,
+            data: &bgr,
+        };
+
+        let stretch = config.auto_stretch();
+        let jpeg = preview(
+            &image,
+            config.max_size,
+            config.quality,
+            config.debayer,
+            &stretch,
+        )
+        .unwrap();
+        assert_eq!(jpeg_size(&jpeg), (width, height));
+        assert!(jpeg.len() <= config.max_bytes());
+    }
+
+    #[test]
+    fn bins_interleaved_samples() {
+        let samples = [1, 10, 3, 30, 5, 50, 7, 70, 9, 90, 11, 110];
+        // 3 x 2 pixels of 2 channels, the last column is dropped
+        let (width, height, binned) = bin(&samples, 3, 2, 2, 2);
+        assert_eq!((width, height), (1, 1));
+        assert_eq!(binned, [(1.0 + 3.0 + 7.0 + 9.0) / 4.0, 50.0]);
+    }
+
+    #[test]
+    fn bins_mosaic_keeping_the_pattern() {
+        let pattern = BayerPattern::Rggb;
+        let (width, height) = (12, 9);
+        let samples: Vec<u16> = (0..width * height)
+            .map(|i| [400, 200, 100][pattern.channel(i % width, i / width)])
+            .collect();
+
+        for factor in [2, 3] {
+            let (w, h, binned) = bin_mosaic(&samples, width, height, pattern, factor);
+            assert_eq!((w, h), (width / factor, height / factor));
+            for (i, v) in binned.iter().enumerate() {
+                assert_eq!(*v, [400, 200, 100][pattern.channel(i % w, i / w)]);
+            }
+        }
+    }
+
+    #[test]
+    fn previews_fit_in_max_size() {
+        let (width, height) = (300, 200);
+        let data: Vec<u8> = (0..width * height)
+            .flat_map(|i| ((i * 7 % 4096) as u16 * 16).to_le_bytes())
+            .collect();
+
+        for bayer in [None, Some(BayerPattern::Rggb)] {
+            let image = Image {
+                width,
+                height,
+                format: PixelFormat::Mono16,
+                bayer,
+                data: &data,
+            };
+            let jpeg =
+                preview(&image, 64, 80, DebayerMethod::Bilinear, &Stretch::default()).unwrap();
+            assert_eq!(jpeg_size(&jpeg), (60, 40));
+        }
+
+        let rgb = vec![128; 100 * 50 * 3];
+        let image = Image {
+            width: 100,
+            height: 50,
+            format: PixelFormat::Bgr24,
+            bayer: None,
+            data: &rgb,
+        };
+        let jpeg = preview(&image, 64, 80, DebayerMethod::Bilinear, &Stretch::default()).unwrap();
+        assert_eq!(jpeg_size(&jpeg), (50, 25));
+    }
+
+    #[test]
+    fn full_size_frame_preview_fits_in_a_packet() {
+        // Noise is the worst case of JPEG, at the largest size and quality
+        let config = PreviewConfig {
+            max_size: 2048,
+            quality: 100,
+            ..PreviewConfig::default()
+        };
+        let (width, height) = (config.max_size, config.max_size);
+        let mut seed = 0x2545_f491_u32;
+        let data: Vec<u8> = (0..width * height)
+            .flat_map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                (seed as u16).to_le_bytes()
+            })
+            .collect();
+        let image = Image {
+            width,
+            height,
+            format: PixelFormat::Mono16,
+            bayer: None,
+            data: &data,
+        };
+
+        let stretch = config.auto_stretch();
+        let jpeg = preview(
+            &image,
+            config.max_size,
+            config.quality,
+            config.debayer,
+            &stretch,
+        )
+        .unwrap();
+        assert_eq!(jpeg_size(&jpeg), (width, height));
+        assert!(jpeg.len() <= config.max_bytes());
+        assert!(jpeg.len() < transfer::packet_size(config.max_bytes()));
+    }
+}
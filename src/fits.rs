@@ -0,0 +1,506 @@
+//! Minimal FITS writer for the frames of the cameras.
+//!
+//! Only what the cameras produce is supported: a single primary HDU holding an
+//! 8 or 16 bit image, or a 3 planes cube for colour frames. 16 bit data is
+//! unsigned, stored as signed integers with `BZERO = 32768` as the standard
+//! requires. Rows are written in the order the camera delivers them, top to
+//! bottom, which is recorded in `ROWORDER`.
+
+use crate::image::BayerPattern;
+use chrono::{DateTime, Utc};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Size of a FITS block, headers and data are padded to it
+const BLOCK_SIZE: usize = 2880;
+const CARD_SIZE: usize = 80;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderValue {
+    Logical(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+impl HeaderValue {
+    /// Value as written in a header, without the fixed format padding
+    pub(crate) fn to_fits_string(&self) -> String {
+        match self {
+            HeaderValue::Logical(b) => (if *b { "T" } else { "F" }).to_string(),
+            HeaderValue::Int(i) => i.to_string(),
+            HeaderValue::Float(f) => {
+                // `Display` never uses the exponent notation, but drops the
+                // decimal point of round values
+                let s = f.to_string();
+                if s.contains('.') || !f.is_finite() {
+                    s
+                } else {
+                    s + ".0"
+                }
+            }
+            HeaderValue::Str(s) => format!("'{}'", s.replace('\'', "''")),
+        }
+    }
+
+    /// Fixed format representation, right justified in columns 11 to 30 for
+    /// everything but strings, which are at least 8 characters long
+    fn format(&self) -> String {
+        match self {
+            HeaderValue::Str(s) => format!("'{:<8}'", s.replace('\'', "''")),
+            _ => format!("{:>20}", self.to_fits_string()),
+        }
+    }
+}
+
+impl From<bool> for HeaderValue {
+    fn from(v: bool) -> Self {
+        HeaderValue::Logical(v)
+    }
+}
+
+impl From<i64> for HeaderValue {
+    fn from(v: i64) -> Self {
+        HeaderValue::Int(v)
+    }
+}
+
+impl From<i32> for HeaderValue {
+    fn from(v: i32) -> Self {
+        HeaderValue::Int(v as i64)
+    }
+}
+
+impl From<f64> for HeaderValue {
+    fn from(v: f64) -> Self {
+        HeaderValue::Float(v)
+    }
+}
+
+impl From<&str> for HeaderValue {
+    fn from(v: &str) -> Self {
+        HeaderValue::Str(v.to_string())
+    }
+}
+
+impl From<String> for HeaderValue {
+    fn from(v: String) -> Self {
+        HeaderValue::Str(v)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Card {
+    pub key: String,
+    pub value: HeaderValue,
+    pub comment: Option<String>,
+}
+
+impl Card {
+    /// The 80 characters records of the card. A string too long for a single
+    /// record is split on `CONTINUE` records following the long string
+    /// convention, every part but the last one ending with `&`. Only the
+    /// comment is cut if it doesn't fit.
+    fn to_records(&self) -> Vec<[u8; CARD_SIZE]> {
+        let mut prefix = format!("{:<8}= ", self.key);
+        let comment = match &self.comment {
+            Some(comment) => format!(" / {}", comment),
+            None => String::new(),
+        };
+
+        let value = self.value.format();
+        let HeaderValue::Str(s) = &self.value else {
+            return vec![record(&(prefix + &value + &comment))];
+        };
+        if prefix.chars().count() + value.chars().count() <= CARD_SIZE {
+            return vec![record(&(prefix + &value + &comment))];
+        }
+
+        let mut records = Vec::new();
+        let mut chars = s.chars().peekable();
+        loop {
+            // Room for the quotes and the continuation mark
+            let room = CARD_SIZE - prefix.chars().count() - 3;
+            let mut part = String::new();
+            let mut len = 0;
+            while let Some(&c) = chars.peek() {
+                // A quote is doubled, both must be in the same part
+                let width = if c == '\'' { 2 } else { 1 };
+                if len + width > room {
+                    break;
+                }
+                part.push(c);
+                if c == '\'' {
+                    part.push(c);
+                }
+                len += width;
+                chars.next();
+            }
+
+            if chars.peek().is_none() {
+                records.push(record(&format!("{}'{}'{}", prefix, part, comment)));
+                return records;
+            }
+            records.push(record(&format!("{}'{}&'", prefix, part)));
+            prefix = format!("{:<10}", "CONTINUE");
+        }
+    }
+}
+
+/// `line` as a header record, padded or cut to 80 characters
+fn record(line: &str) -> [u8; CARD_SIZE] {
+    let mut record = [b' '; CARD_SIZE];
+    // Only printable ASCII is allowed in a header
+    for (dst, c) in record.iter_mut().zip(line.chars()) {
+        *dst = if c.is_ascii_graphic() || c == ' ' {
+            c as u8
+        } else {
+            b'?'
+        };
+    }
+    record
+}
+
+/// The keywords describing a frame, the structural ones (`SIMPLE`, `BITPIX`,
+/// `NAXIS`...) are added by `write` and must not be set here.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Header {
+    cards: Vec<Card>,
+}
+
+impl Header {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set `key`, replacing its previous value if any
+    pub fn set(&mut self, key: &str, value: impl Into<HeaderValue>, comment: &str) {
+        let card = Card {
+            key: key.to_ascii_uppercase(),
+            value: value.into(),
+            comment: (!comment.is_empty()).then(|| comment.to_string()),
+        };
+
+        match self.cards.iter_mut().find(|c| c.key == card.key) {
+            Some(c) => *c = card,
+            None => self.cards.push(card),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&HeaderValue> {
+        self.cards.iter().find(|c| c.key == key).map(|c| &c.value)
+    }
+
+    pub fn cards(&self) -> &[Card] {
+        &self.cards
+    }
+}
+
+/// Layout of the pixels as delivered by the camera
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    Mono8,
+    /// Little endian 16 bit
+    Mono16,
+    /// Interleaved blue, green and red bytes
+    Bgr24,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Mono8 => 1,
+            PixelFormat::Mono16 => 2,
+            PixelFormat::Bgr24 => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Image<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
+    /// Colour filter array of the raw frames of colour cameras
+    pub bayer: Option<BayerPattern>,
+    pub data: &'a [u8],
+}
+
+impl Image<'_> {
+    pub(crate) fn check(&self) -> io::Result<()> {
+        let expected = self.width * self.height * self.format.bytes_per_pixel();
+        if self.data.len() != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{}x{} {:?} image needs {} bytes, got {}",
+                    self.width,
+                    self.height,
+                    self.format,
+                    expected,
+                    self.data.len()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The structural keywords of the primary HDU
+    fn structure(&self) -> Vec<Card> {
+        let card = |key: &str, value: HeaderValue, comment: &str| Card {
+            key: key.to_string(),
+            value,
+            comment: Some(comment.to_string()),
+        };
+
+        let bitpix = match self.format {
+            PixelFormat::Mono16 => 16,
+            PixelFormat::Mono8 | PixelFormat::Bgr24 => 8,
+        };
+        let planes = self.format == PixelFormat::Bgr24;
+
+        let mut cards = vec![
+            card("SIMPLE", true.into(), "conforms to the FITS standard"),
+            card("BITPIX", bitpix.into(), "bits per data value"),
+            card(
+                "NAXIS",
+                (if planes { 3 } else { 2 }).into(),
+                "number of axes",
+            ),
+            card("NAXIS1", (self.width as i64).into(), "width"),
+            card("NAXIS2", (self.height as i64).into(), "height"),
+        ];
+        if planes {
+            cards.push(card("NAXIS3", 3.into(), "red, green and blue planes"));
+        }
+        if bitpix == 16 {
+            cards.push(card("BZERO", 32768.into(), "unsigned 16 bit data"));
+            cards.push(card("BSCALE", 1.into(), "default scaling factor"));
+        }
+        cards.push(card("ROWORDER", "TOP-DOWN".into(), "order of the rows"));
+        cards
+    }
+
+    /// The samples, still little endian, one plane after the other in red,
+    /// green and blue order for colour frames
+    pub(crate) fn planar(&self) -> Vec<u8> {
+        match self.format {
+            PixelFormat::Mono8 | PixelFormat::Mono16 => self.data.to_vec(),
+            PixelFormat::Bgr24 => [2, 1, 0]
+                .iter()
+                .flat_map(|c| self.data.iter().skip(*c).step_by(3).copied())
+                .collect(),
+        }
+    }
+
+    /// The data in FITS order: big endian, one plane after the other
+    fn fits_data(&self) -> Vec<u8> {
+        match self.format {
+            // Flipping the sign bit is the same as subtracting BZERO
+            PixelFormat::Mono16 => self
+                .data
+                .chunks_exact(2)
+                .flat_map(|p| (u16::from_le_bytes([p[0], p[1]]) ^ 0x8000).to_be_bytes())
+                .collect(),
+            PixelFormat::Mono8 | PixelFormat::Bgr24 => self.planar(),
+        }
+    }
+}
+
+/// Write `image` with `header` to `path`, creating the parent directories
+pub fn write(path: &Path, header: &Header, image: &Image) -> io::Result<()> {
+    image.check()?;
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut out = BufWriter::new(File::create(path)?);
+
+    let mut written = 0;
+    for card in image.structure().iter().chain(header.cards()) {
+        for record in card.to_records() {
+            out.write_all(&record)?;
+            written += CARD_SIZE;
+        }
+    }
+    let mut end = [b' '; CARD_SIZE];
+    end[..3].copy_from_slice(b"END");
+    out.write_all(&end)?;
+    written += CARD_SIZE;
+    pad(&mut out, written, b' ')?;
+
+    let data = image.fits_data();
+    out.write_all(&data)?;
+    pad(&mut out, data.len(), 0)?;
+
+    out.flush()
+}
+
+fn pad(out: &mut impl Write, written: usize, byte: u8) -> io::Result<()> {
+    let missing = (BLOCK_SIZE - written % BLOCK_SIZE) % BLOCK_SIZE;
+    out.write_all(&vec![byte; missing])
+}
+
+/// `time` as expected by `DATE-OBS`, e.g. `2024-03-01T21:04:05.123`, in UTC
+pub fn date_obs(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%Y-%m-%dT%H:%M:%S%.3f")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card(key: &str, value: impl Into<HeaderValue>, comment: Option<&str>) -> Card {
+        Card {
+            key: key.to_string(),
+            value: value.into(),
+            comment: comment.map(str::to_string),
+        }
+    }
+
+    fn text(records: &[[u8; CARD_SIZE]]) -> Vec<String> {
+        records
+            .iter()
+            .map(|r| {
+                String::from_utf8(r.to_vec())
+                    .unwrap()
+                    .trim_end()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    /// Write `image` to a temporary file and read it back
+    fn write_read(header: &Header, image: &Image) -> Vec<u8> {
+        let dir = std::env::temp_dir().join(format!("asi-rs-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("frame.fits");
+        write(&path, header, image).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        bytes
+    }
+
+    /// Records of the header, up to `END`
+    fn header_records(bytes: &[u8]) -> Vec<String> {
+        bytes
+            .chunks(CARD_SIZE)
+            .map(|r| {
+                String::from_utf8(r.to_vec())
+                    .unwrap()
+                    .trim_end()
+                    .to_string()
+            })
+            .take_while(|r| r != "END")
+            .collect()
+    }
+
+    #[test]
+    fn formats_values() {
+        let records = text(&[
+            card("SIMPLE", true, None).to_records()[0],
+            card("EXPTIME", 30.0, Some("seconds")).to_records()[0],
+            card("OBJECT", "M 31", None).to_records()[0],
+            card("OBSERVER", "O'Neil", None).to_records()[0],
+        ]);
+        assert_eq!(
+            records,
+            [
+                "SIMPLE  =                    T",
+                "EXPTIME =                 30.0 / seconds",
+                "OBJECT  = 'M 31    '",
+                "OBSERVER= 'O''Neil '",
+            ]
+        );
+    }
+
+    #[test]
+    fn continues_long_strings() {
+        let value = format!("{}'{}", "a".repeat(66), "b".repeat(80));
+        let records = text(&card("OBJECT", value.as_str(), Some("target")).to_records());
+
+        // The doubled quote doesn't fit on the first record, it moves whole to
+        // the next one
+        assert_eq!(
+            records,
+            [
+                format!("OBJECT  = '{}&'", "a".repeat(66)),
+                format!("CONTINUE  '''{}&'", "b".repeat(65)),
+                format!("CONTINUE  '{}' / target", "b".repeat(15)),
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_unsigned_16_bit() {
+        let pixels: [u16; 6] = [0, 1, 32767, 32768, 40000, 65535];
+        let data: Vec<u8> = pixels.iter().flat_map(|p| p.to_le_bytes()).collect();
+        let image = Image {
+            width: 3,
+            height: 2,
+            format: PixelFormat::Mono16,
+            bayer: None,
+            data: &data,
+        };
+        let mut header = Header::new();
+        header.set("exptime", 1.5, "seconds");
+
+        let bytes = write_read(&header, &image);
+        assert_eq!(bytes.len(), 2 * BLOCK_SIZE);
+
+        let records = header_records(&bytes);
+        for expected in [
+            "BITPIX  =                   16 / bits per data value",
+            "BZERO   =                32768 / unsigned 16 bit data",
+            "EXPTIME =                  1.5 / seconds",
+        ] {
+            assert!(records.iter().any(|r| r == expected), "{}", expected);
+        }
+        assert!(bytes[records.len() * CARD_SIZE..BLOCK_SIZE].starts_with(b"END     "));
+        assert!(bytes[(records.len() + 1) * CARD_SIZE..BLOCK_SIZE]
+            .iter()
+            .all(|b| *b == b' '));
+
+        let read: Vec<u16> = bytes[BLOCK_SIZE..BLOCK_SIZE + 12]
+            .chunks_exact(2)
+            .map(|p| (i16::from_be_bytes([p[0], p[1]]) as i32 + 32768) as u16)
+            .collect();
+        assert_eq!(read, pixels);
+        assert!(bytes[BLOCK_SIZE + 12..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn writes_colour_planes() {
+        // Blue, green and red bytes of two pixels
+        let data = [1, 2, 3, 4, 5, 6];
+        let image = Image {
+            width: 2,
+            height: 1,
+            format: PixelFormat::Bgr24,
+            bayer: None,
+            data: &data,
+        };
+
+        let bytes = write_read(&Header::new(), &image);
+        assert_eq!(bytes.len(), 2 * BLOCK_SIZE);
+        assert!(header_records(&bytes)
+            .iter()
+            .any(|r| r.starts_with("NAXIS3  =                    3")));
+        assert_eq!(bytes[BLOCK_SIZE..BLOCK_SIZE + 6], [3, 6, 2, 5, 1, 4]);
+    }
+
+    #[test]
+    fn rejects_wrong_data_size() {
+        let image = Image {
+            width: 2,
+            height: 2,
+            format: PixelFormat::Mono8,
+            bayer: None,
+            data: &[0; 3],
+        };
+        assert!(image.check().is_err());
+    }
+}
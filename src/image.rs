@@ -0,0 +1,429 @@
+//! Colour handling of the frames.
+//!
+//! The SDK delivers `RGB24` frames with the blue sample first, and the raw
+//! frames of colour cameras as Bayer mosaics, one colour per pixel. This turns
+//! both into plain RGB images. Mosaics are demosaiced either bilinearly, fast
+//! but soft and prone to colour fringes on sharp edges, or with the edge aware
+//! Hamilton-Adams method, which interpolates along the edges rather than
+//! across them.
+
+use crate::fits::{Image, PixelFormat};
+use serde::Deserialize;
+use std::io;
+
+const RED: usize = 0;
+const GREEN: usize = 1;
+const BLUE: usize = 2;
+
+/// Layout of the 2x2 colour filter array, top left pixel first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BayerPattern {
+    Rggb,
+    Bggr,
+    Grbg,
+    Gbrg,
+}
+
+impl BayerPattern {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BayerPattern::Rggb => "RGGB",
+            BayerPattern::Bggr => "BGGR",
+            BayerPattern::Grbg => "GRBG",
+            BayerPattern::Gbrg => "GBRG",
+        }
+    }
+
+    /// The pattern seen once the frame is mirrored, left to right when
+    /// `horizontal` and top to bottom when `vertical`
+    pub fn flipped(self, horizontal: bool, vertical: bool) -> Self {
+        let mut pattern = self;
+        if horizontal {
+            pattern = match pattern {
+                BayerPattern::Rggb => BayerPattern::Grbg,
+                BayerPattern::Grbg => BayerPattern::Rggb,
+                BayerPattern::Bggr => BayerPattern::Gbrg,
+                BayerPattern::Gbrg => BayerPattern::Bggr,
+            };
+        }
+        if vertical {
+            pattern = match pattern {
+                BayerPattern::Rggb => BayerPattern::Gbrg,
+                BayerPattern::Gbrg => BayerPattern::Rggb,
+                BayerPattern::Bggr => BayerPattern::Grbg,
+                BayerPattern::Grbg => BayerPattern::Bggr,
+            };
+        }
+        pattern
+    }
+
+    /// Colour of the pixel at `x`, `y`
+    pub(crate) fn channel(&self, x: usize, y: usize) -> usize {
+        let cells = match self {
+            BayerPattern::Rggb => [[RED, GREEN], [GREEN, BLUE]],
+            BayerPattern::Bggr => [[BLUE, GREEN], [GREEN, RED]],
+            BayerPattern::Grbg => [[GREEN, RED], [BLUE, GREEN]],
+            BayerPattern::Gbrg => [[GREEN, BLUE], [RED, GREEN]],
+        };
+        cells[y % 2][x % 2]
+    }
+}
+
+/// How the missing colours of a mosaic are interpolated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DebayerMethod {
+    #[default]
+    Bilinear,
+    EdgeAware,
+}
+
+/// Image with interleaved red, green and blue samples
+#[derive(Debug, Clone)]
+pub struct RgbImage {
+    pub width: usize,
+    pub height: usize,
+    /// 8 for the frames with 8 bit samples, whose values stay below 256
+    pub bit_depth: u32,
+    pub data: Vec<u16>,
+}
+
+/// Swap the first and third samples of every pixel of an `RGB24` frame
+pub fn bgr_to_rgb(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(3)
+        .flat_map(|p| [p[2], p[1], p[0]])
+        .collect()
+}
+
+/// `image` in RGB, `None` for the frames of mono cameras
+pub fn to_rgb(image: &Image, method: DebayerMethod) -> io::Result<Option<RgbImage>> {
+    image.check()?;
+    let (bit_depth, samples): (u32, Vec<u16>) = match (image.format, image.bayer) {
+        (PixelFormat::Bgr24, _) => {
+            let data = bgr_to_rgb(image.data).into_iter().map(u16::from).collect();
+            return Ok(Some(RgbImage {
+                width: image.width,
+                height: image.height,
+                bit_depth: 8,
+                data,
+            }));
+        }
+        (_, None) => return Ok(None),
+        (PixelFormat::Mono8, Some(_)) => (8, image.data.iter().map(|v| u16::from(*v)).collect()),
+        (PixelFormat::Mono16, Some(_)) => (
+            16,
+            image
+                .data
+                .chunks_exact(2)
+                .map(|p| u16::from_le_bytes([p[0], p[1]]))
+                .collect(),
+        ),
+    };
+
+    let mosaic = Mosaic {
+        width: image.width,
+        height: image.height,
+        pattern: image.bayer.unwrap_or(BayerPattern::Rggb),
+        samples: samples.iter().map(|v| f32::from(*v)).collect(),
+    };
+    let planes = match method {
+        DebayerMethod::Bilinear => mosaic.bilinear(),
+        DebayerMethod::EdgeAware => mosaic.hamilton_adams(),
+    };
+
+    let max = if bit_depth == 8 { 255.0 } else { 65535.0 };
+    let data = (0..image.width * image.height)
+        .flat_map(|i| planes.iter().map(move |p| p[i]))
+        .map(|v| v.round().clamp(0.0, max) as u16)
+        .collect();
+    Ok(Some(RgbImage {
+        width: image.width,
+        height: image.height,
+        bit_depth,
+        data,
+    }))
+}
+
+struct Mosaic {
+    width: usize,
+    height: usize,
+    pattern: BayerPattern,
+    samples: Vec<f32>,
+}
+
+impl Mosaic {
+    /// Index of the pixel at `x`, `y`, mirrored on the edges: reflecting
+    /// around the border pixel keeps the colour of the mirrored pixel
+    fn index(&self, x: isize, y: isize) -> usize {
+        let mirror = |v: isize, len: usize| {
+            let len = len as isize;
+            let v = if v < 0 { -v } else { v };
+            let v = if v >= len { 2 * (len - 1) - v } else { v };
+            v.clamp(0, len - 1) as usize
+        };
+        mirror(y, self.height) * self.width + mirror(x, self.width)
+    }
+
+    fn at(&self, x: isize, y: isize) -> f32 {
+        self.samples[self.index(x, y)]
+    }
+
+    fn channel(&self, x: isize, y: isize) -> usize {
+        self.pattern.channel(x as usize, y as usize)
+    }
+
+    /// Every missing sample is the average of the nearest ones of the same
+    /// colour
+    fn bilinear(&self) -> [Vec<f32>; 3] {
+        let mut planes = [(); 3].map(|_| vec![0.0; self.samples.len()]);
+
+        for y in 0..self.height as isize {
+            for x in 0..self.width as isize {
+                let i = self.index(x, y);
+                let own = self.channel(x, y);
+                let cross =
+                    (self.at(x - 1, y) + self.at(x + 1, y) + self.at(x, y - 1) + self.at(x, y + 1))
+                        / 4.0;
+                let horizontal = (self.at(x - 1, y) + self.at(x + 1, y)) / 2.0;
+                let vertical = (self.at(x, y - 1) + self.at(x, y + 1)) / 2.0;
+                let diagonal = (self.at(x - 1, y - 1)
+                    + self.at(x + 1, y - 1)
+                    + self.at(x - 1, y + 1)
+                    + self.at(x + 1, y + 1))
+                    / 4.0;
+
+                for (c, plane) in planes.iter_mut().enumerate() {
+                    plane[i] = if c == own {
+                        self.samples[i]
+                    } else if c == GREEN {
+                        cross
+                    } else if own == GREEN {
+                        // Red and blue alternate between the rows
+                        if self.channel(x + 1, y) == c {
+                            horizontal
+                        } else {
+                            vertical
+                        }
+                    } else {
+                        diagonal
+                    };
+                }
+            }
+        }
+
+        planes
+    }
+
+    /// Hamilton-Adams: green is interpolated along the direction with the
+    /// smallest gradient, corrected by the curvature of the known colour, then
+    /// red and blue are interpolated as differences to green
+    fn hamilton_adams(&self) -> [Vec<f32>; 3] {
+        let len = self.samples.len();
+        let mut green = vec![0.0; len];
+
+        for y in 0..self.height as isize {
+            for x in 0..self.width as isize {
+                let i = self.index(x, y);
+                let c = self.samples[i];
+                if self.channel(x, y) == GREEN {
+                    green[i] = c;
+                    continue;
+                }
+
+                let (left, right) = (self.at(x - 1, y), self.at(x + 1, y));
+                let (up, down) = (self.at(x, y - 1), self.at(x, y + 1));
+                let curve_h = 2.0 * c - self.at(x - 2, y) - self.at(x + 2, y);
+                let curve_v = 2.0 * c - self.at(x, y - 2) - self.at(x, y + 2);
+                let gradient_h = (left - right).abs() + curve_h.abs();
+                let gradient_v = (up - down).abs() + curve_v.abs();
+                let along_h = (left + right) / 2.0 + curve_h / 4.0;
+                let along_v = (up + down) / 2.0 + curve_v / 4.0;
+
+                green[i] = if gradient_h < gradient_v {
+                    along_h
+                } else if gradient_v < gradient_h {
+                    along_v
+                } else {
+                    (along_h + along_v) / 2.0
+                };
+            }
+        }
+
+        let g = |x: isize, y: isize| green[self.index(x, y)];
+        // Difference to green of the neighbour at `x`, `y`
+        let diff = |x: isize, y: isize| self.at(x, y) - g(x, y);
+        let mut red = vec![0.0; len];
+        let mut blue = vec![0.0; len];
+
+        for y in 0..self.height as isize {
+            for x in 0..self.width as isize {
+                let i = self.index(x, y);
+                let own = self.channel(x, y);
+
+                for c in [RED, BLUE] {
+                    let value = if c == own {
+                        self.samples[i]
+                    } else if own == GREEN {
+                        if self.channel(x + 1, y) == c {
+                            g(x, y) + (diff(x - 1, y) + diff(x + 1, y)) / 2.0
+                        } else {
+                            g(x, y) + (diff(x, y - 1) + diff(x, y + 1)) / 2.0
+                        }
+                    } else {
+                        // The other colour sits on the diagonals, pick the
+                        // smoothest one
+                        let gradient_1 = (self.at(x - 1, y - 1) - self.at(x + 1, y + 1)).abs()
+                            + (2.0 * g(x, y) - g(x - 1, y - 1) - g(x + 1, y + 1)).abs();
+                        let gradient_2 = (self.at(x + 1, y - 1) - self.at(x - 1, y + 1)).abs()
+                            + (2.0 * g(x, y) - g(x + 1, y - 1) - g(x - 1, y + 1)).abs();
+                        let along_1 = (diff(x - 1, y - 1) + diff(x + 1, y + 1)) / 2.0;
+                        let along_2 = (diff(x + 1, y - 1) + diff(x - 1, y + 1)) / 2.0;
+
+                        g(x, y)
+                            + if gradient_1 < gradient_2 {
+                                along_1
+                            } else if gradient_2 < gradient_1 {
+                                along_2
+                            } else {
+                                (along_1 + along_2) / 2.0
+                            }
+                    };
+
+                    if c == RED {
+                        red[i] = value;
+                    } else {
+                        blue[i] = value;
+                    }
+                }
+            }
+        }
+
+        [red, green, blue]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mosaic of `width` x `height` where each colour has a constant value
+    fn uniform_mosaic(
+        width: usize,
+        height: usize,
+        pattern: BayerPattern,
+        rgb: [u16; 3],
+    ) -> Vec<u8> {
+        (0..width * height)
+            .flat_map(|i| rgb[pattern.channel(i % width, i / width)].to_le_bytes())
+            .collect()
+    }
+
+    fn mosaic_image(data: &[u8], width: usize, height: usize, pattern: BayerPattern) -> Image<'_> {
+        Image {
+            width,
+            height,
+            format: PixelFormat::Mono16,
+            bayer: Some(pattern),
+            data,
+        }
+    }
+
+    #[test]
+    fn swaps_blue_and_red() {
+        assert_eq!(bgr_to_rgb(&[1, 2, 3, 4, 5, 6]), [3, 2, 1, 6, 5, 4]);
+    }
+
+    #[test]
+    fn flipping_moves_the_pattern() {
+        let pattern = BayerPattern::Rggb;
+        assert_eq!(pattern.flipped(false, false), BayerPattern::Rggb);
+        assert_eq!(pattern.flipped(true, false), BayerPattern::Grbg);
+        assert_eq!(pattern.flipped(false, true), BayerPattern::Gbrg);
+        assert_eq!(pattern.flipped(true, true), BayerPattern::Bggr);
+
+        // The flipped pattern describes the mirrored mosaic
+        for flipped in [(true, false), (false, true), (true, true)] {
+            let mirrored = pattern.flipped(flipped.0, flipped.1);
+            for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let source = (
+                    if flipped.0 { 1 - x } else { x },
+                    if flipped.1 { 1 - y } else { y },
+                );
+                assert_eq!(mirrored.channel(x, y), pattern.channel(source.0, source.1));
+            }
+        }
+    }
+
+    #[test]
+    fn mono_frames_have_no_colour() {
+        let image = Image {
+            width: 2,
+            height: 1,
+            format: PixelFormat::Mono8,
+            bayer: None,
+            data: &[1, 2],
+        };
+        assert!(to_rgb(&image, DebayerMethod::Bilinear).unwrap().is_none());
+    }
+
+    #[test]
+    fn debayers_uniform_colour() {
+        let rgb = [40000, 20000, 10000];
+        for pattern in [
+            BayerPattern::Rggb,
+            BayerPattern::Bggr,
+            BayerPattern::Grbg,
+            BayerPattern::Gbrg,
+        ] {
+            let data = uniform_mosaic(8, 6, pattern, rgb);
+            for method in [DebayerMethod::Bilinear, DebayerMethod::EdgeAware] {
+                let image = to_rgb(&mosaic_image(&data, 8, 6, pattern), method)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!((image.width, image.height, image.bit_depth), (8, 6, 16));
+                assert!(
+                    image.data.chunks_exact(3).all(|p| p == rgb),
+                    "{:?} {:?}",
+                    pattern,
+                    method
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn interpolates_linear_gradient() {
+        // Every colour follows the same horizontal ramp
+        let (width, height) = (10, 6);
+        let data: Vec<u8> = (0..width * height)
+            .flat_map(|i| (1000 + 100 * (i % width) as u16).to_le_bytes())
+            .collect();
+        let image = mosaic_image(&data, width, height, BayerPattern::Rggb);
+
+        for method in [DebayerMethod::Bilinear, DebayerMethod::EdgeAware] {
+            let rgb = to_rgb(&image, method).unwrap().unwrap();
+            for y in 2..height - 2 {
+                for x in 2..width - 2 {
+                    let expected = 1000 + 100 * x as u16;
+                    let p = &rgb.data[(y * width + x) * 3..][..3];
+                    assert_eq!(p, [expected; 3], "{:?} at {}, {}", method, x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_8_bit_depth() {
+        let data = [200, 100, 100, 50];
+        let image = Image {
+            width: 2,
+            height: 2,
+            format: PixelFormat::Mono8,
+            bayer: Some(BayerPattern::Rggb),
+            data: &data,
+        };
+        let rgb = to_rgb(&image, DebayerMethod::Bilinear).unwrap().unwrap();
+        assert_eq!(rgb.bit_depth, 8);
+        assert_eq!(rgb.data[..3], [200, 100, 50]);
+    }
+}
@@ -1,4 +1,43 @@
+pub mod config;
+pub mod discovery;
+pub mod export;
+pub mod fits;
+pub mod image;
+pub mod naming;
+pub mod presence;
+pub mod reply;
+pub mod ser;
+pub mod stars;
+pub mod state;
+pub mod stats;
+pub mod stretch;
+pub mod topics;
+pub mod transfer;
+pub mod xisf;
+
 pub mod utils {
+    use uuid::Uuid;
+
+    /// Namespace of the device ids, see `device_uuid`
+    const DEVICE_NAMESPACE: Uuid = Uuid::from_u128(0x5a1f_0c2e_8d3b_4e61_9b7a_2c4d_6e8f_a0b1);
+
+    /// Id of a device derived from its model and a hardware identifier (serial
+    /// number or ID stored in the device flash), so that it doesn't change across
+    /// restarts and retained messages keep pointing at the right device.
+    pub fn device_uuid(model: &str, hw_id: &str) -> Uuid {
+        Uuid::new_v5(&DEVICE_NAMESPACE, format!("{}/{}", model, hw_id).as_bytes())
+    }
+
+    /// Hexadecimal representation of a serial number, `None` if the device
+    /// didn't report any
+    pub fn serial_to_string(serial: &[u8]) -> Option<String> {
+        if serial.iter().all(|b| *b == 0) {
+            return None;
+        }
+
+        Some(serial.iter().map(|b| format!("{:02X}", b)).collect())
+    }
+
     pub fn asi_name_to_string(name_array: &[i8]) -> String {
         let mut to_u8: Vec<u8> = vec![];
 
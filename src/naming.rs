@@ -0,0 +1,384 @@
+//! Naming of the saved files.
+//!
+//! The path of a file, relative to the output directory and without its
+//! extension, comes from a template such as
+//! `{date}/{target}/{camera}_{frame_type}_{exposure}s_{seq:04}`, every `/`
+//! creating a directory. The tokens are:
+//!
+//! - `{camera}`, `{target}`, `{filter}` and `{frame_type}`
+//! - `{exposure}` in seconds, `{gain}` and `{temp}`, the sensor temperature in
+//!   degrees
+//! - `{date}`, the date of the night (`2024-03-01`): it changes at local noon,
+//!   so that a whole night ends up in the same directory
+//! - `{time}`, the local time (`210405`)
+//! - `{seq}`, the lowest number giving a file that doesn't exist yet
+//!
+//! `{seq}` and `{gain}` can be padded with zeros, e.g. `{seq:04}`. Missing
+//! values, like the target of a calibration frame, are left empty. Values are
+//! sanitized so that they can't create directories on their own. Whatever the
+//! template, an existing file is never overwritten: if needed a counter is
+//! appended to the name.
+
+use chrono::{DateTime, Local, TimeDelta};
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+const DEFAULT_TEMPLATE: &str = "{date}/{camera}_{frame_type}_{exposure}s_{seq:04}";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateError(String);
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Camera,
+    Target,
+    Filter,
+    FrameType,
+    Exposure,
+    Gain,
+    Temp,
+    Date,
+    Time,
+    Seq,
+}
+
+impl Token {
+    const ALL: [Token; 10] = [
+        Token::Camera,
+        Token::Target,
+        Token::Filter,
+        Token::FrameType,
+        Token::Exposure,
+        Token::Gain,
+        Token::Temp,
+        Token::Date,
+        Token::Time,
+        Token::Seq,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Token::Camera => "camera",
+            Token::Target => "target",
+            Token::Filter => "filter",
+            Token::FrameType => "frame_type",
+            Token::Exposure => "exposure",
+            Token::Gain => "gain",
+            Token::Temp => "temp",
+            Token::Date => "date",
+            Token::Time => "time",
+            Token::Seq => "seq",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Token { token: Token, width: usize },
+}
+
+/// What a file is named after
+#[derive(Debug, Clone)]
+pub struct NameContext<'a> {
+    pub camera: &'a str,
+    pub target: Option<&'a str>,
+    pub filter: Option<&'a str>,
+    pub frame_type: &'a str,
+    /// Exposure length in seconds
+    pub exposure: f64,
+    pub gain: Option<i64>,
+    /// Sensor temperature in degrees
+    pub temperature: Option<f64>,
+    /// When the frame was taken
+    pub time: SystemTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        DEFAULT_TEMPLATE.parse().expect("valid default template")
+    }
+}
+
+impl std::str::FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| TemplateError(format!("unclosed `{{` in `{}`", s)))?;
+            parts.push(parse_token(&rest[start + 1..start + end])?);
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+
+        let text: String = parts
+            .iter()
+            .filter_map(|p| match p {
+                Part::Text(t) => Some(t.as_str()),
+                Part::Token { .. } => None,
+            })
+            .collect();
+        if text.contains('}') {
+            return Err(TemplateError(format!("unopened `}}` in `{}`", s)));
+        }
+        if parts.is_empty() {
+            return Err(TemplateError("the template is empty".to_string()));
+        }
+        // The files must stay in the output directory
+        let path = Path::new(s);
+        if path.has_root()
+            || path
+                .components()
+                .any(|c| matches!(c, Component::ParentDir | Component::Prefix(_)))
+        {
+            return Err(TemplateError(format!(
+                "`{}` must be relative to the output directory",
+                s
+            )));
+        }
+
+        Ok(Self { parts })
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = TemplateError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+fn parse_token(spec: &str) -> Result<Part, TemplateError> {
+    let (name, width) = match spec.split_once(':') {
+        Some((name, width)) => (name, Some(width)),
+        None => (spec, None),
+    };
+    let token = Token::ALL
+        .into_iter()
+        .find(|t| t.name() == name)
+        .ok_or_else(|| TemplateError(format!("unknown token `{{{}}}`", spec)))?;
+
+    let width = match width {
+        None => 0,
+        Some(_) if !matches!(token, Token::Seq | Token::Gain) => {
+            return Err(TemplateError(format!(
+                "only `{{seq}}` and `{{gain}}` can be padded, got `{{{}}}`",
+                spec
+            )));
+        }
+        Some(w) => w
+            .parse()
+            .map_err(|_| TemplateError(format!("invalid width in `{{{}}}`", spec)))?,
+    };
+
+    Ok(Part::Token { token, width })
+}
+
+impl Template {
+    /// A path in `directory` for a file described by `context`, that doesn't
+    /// exist yet
+    pub fn path(&self, directory: &Path, context: &NameContext, extension: &str) -> PathBuf {
+        let with_seq = self.parts.iter().any(|p| match p {
+            Part::Token { token, .. } => *token == Token::Seq,
+            Part::Text(_) => false,
+        });
+
+        let mut seq = 1;
+        let name = loop {
+            let name = self.render(context, seq);
+            if !with_seq || !file(directory, &name, extension).exists() {
+                break name;
+            }
+            seq += 1;
+        };
+
+        let mut path = file(directory, &name, extension);
+        let mut n = 1;
+        while path.exists() {
+            path = file(directory, &format!("{}_{}", name, n), extension);
+            n += 1;
+        }
+        path
+    }
+
+    fn render(&self, context: &NameContext, seq: u32) -> String {
+        let local = DateTime::<Local>::from(context.time);
+
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Token { token, width } => {
+                    let value = match token {
+                        Token::Camera => context.camera.to_string(),
+                        Token::Target => context.target.unwrap_or_default().to_string(),
+                        Token::Filter => context.filter.unwrap_or_default().to_string(),
+                        Token::FrameType => context.frame_type.to_string(),
+                        Token::Exposure => context.exposure.to_string(),
+                        Token::Gain => match context.gain {
+                            Some(g) => format!("{:0width$}", g, width = *width),
+                            None => String::new(),
+                        },
+                        Token::Temp => match context.temperature {
+                            Some(t) => format!("{:.0}", t),
+                            None => String::new(),
+                        },
+                        // The night starts and ends at noon
+                        Token::Date => (local - TimeDelta::hours(12))
+                            .format("%Y-%m-%d")
+                            .to_string(),
+                        Token::Time => local.format("%H%M%S").to_string(),
+                        Token::Seq => format!("{:0width$}", seq, width = *width),
+                    };
+                    sanitize(&value)
+                }
+            })
+            .collect()
+    }
+}
+
+fn file(directory: &Path, name: &str, extension: &str) -> PathBuf {
+    directory.join(format!("{}.{}", name, extension))
+}
+
+/// Keep the characters that are safe in a file name on every platform
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>()
+        // A lone `.` or `..` would move out of the directory
+        .trim_start_matches('.')
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::fs;
+
+    /// `hour` o'clock, local time, on the given day of March 2024
+    fn local(day: u32, hour: u32) -> SystemTime {
+        Local
+            .with_ymd_and_hms(2024, 3, day, hour, 4, 5)
+            .single()
+            .unwrap()
+            .into()
+    }
+
+    fn context(time: SystemTime) -> NameContext<'static> {
+        NameContext {
+            camera: "ZWO ASI2600MM Pro",
+            target: Some("M 31/Andromeda"),
+            filter: None,
+            frame_type: "light",
+            exposure: 30.0,
+            gain: Some(100),
+            temperature: Some(-9.8),
+            time,
+        }
+    }
+
+    fn render(template: &str, time: SystemTime) -> String {
+        template
+            .parse::<Template>()
+            .unwrap()
+            .render(&context(time), 7)
+    }
+
+    #[test]
+    fn renders_tokens() {
+        assert_eq!(
+            render(
+                "{camera}/{target}_{filter}_{frame_type}_{exposure}s_g{gain:04}_{temp}C_{time}_{seq:03}",
+                local(1, 21)
+            ),
+            "ZWO_ASI2600MM_Pro/M_31_Andromeda__light_30s_g0100_-10C_210405_007"
+        );
+    }
+
+    #[test]
+    fn night_date_changes_at_noon() {
+        assert_eq!(render("{date}", local(1, 21)), "2024-03-01");
+        assert_eq!(render("{date}", local(2, 3)), "2024-03-01");
+        assert_eq!(render("{date}", local(2, 11)), "2024-03-01");
+        assert_eq!(render("{date}", local(2, 12)), "2024-03-02");
+    }
+
+    #[test]
+    fn sanitizes_values() {
+        assert_eq!(sanitize("../etc"), "_etc");
+        assert_eq!(sanitize(".."), "");
+        assert_eq!(sanitize("Hα"), "H_");
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for template in [
+            "",
+            "{seq",
+            "seq}",
+            "{unknown}",
+            "{camera:04}",
+            "{seq:x}",
+            "/data/{seq}",
+            "../{seq}",
+        ] {
+            assert!(template.parse::<Template>().is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn never_overwrites_files() {
+        let dir = std::env::temp_dir().join(format!("asi-rs-{}", uuid::Uuid::new_v4()));
+        let context = context(local(1, 21));
+
+        let seq: Template = "{frame_type}_{seq}".parse().unwrap();
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("light_1.fits"), []).unwrap();
+        assert_eq!(seq.path(&dir, &context, "fits"), dir.join("light_2.fits"));
+
+        let fixed: Template = "{target}".parse().unwrap();
+        fs::write(dir.join("M_31_Andromeda.fits"), []).unwrap();
+        assert_eq!(
+            fixed.path(&dir, &context, "fits"),
+            dir.join("M_31_Andromeda_1.fits")
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
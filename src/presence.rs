@@ -0,0 +1,176 @@
+//! Online/offline status of the devices.
+//!
+//! Each device owns a small MQTT connection whose last will publishes
+//! `offline` on `<prefix>/devices/<id>/status`, so that a crashed daemon or a
+//! lost network flips every device to offline. The status is retained, a
+//! client connecting at any time gets it straight away.
+
+use crate::config::MqttConfig;
+use crate::reply::{ErrorCode, Reply, Status};
+use log::{debug, error, warn};
+use rumqttc::Event::{Incoming, Outgoing};
+use rumqttc::{AsyncClient, LastWill, Packet, QoS};
+use serde::Serialize;
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Topic action where the status of a device is published
+pub const STATUS_ACTION: &str = "status";
+
+/// How long to wait for the `offline` status to be delivered on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceStatus {
+    Online,
+    Offline,
+    /// The daemon is running but the device doesn't answer anymore
+    Error,
+}
+
+impl DeviceStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceStatus::Online => "online",
+            DeviceStatus::Offline => "offline",
+            DeviceStatus::Error => "error",
+        }
+    }
+}
+
+impl DeviceStatus {
+    /// The status implied by the outcome of a command, if any
+    pub fn after(reply: &Reply) -> Option<Self> {
+        match (reply.status, &reply.error) {
+            (Status::Failed, Some(e)) if e.code == ErrorCode::Unavailable => {
+                Some(DeviceStatus::Error)
+            }
+            (Status::Completed, _) => Some(DeviceStatus::Online),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DeviceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Changes the status of a device, it can be cloned and moved to other tasks
+#[derive(Debug, Clone)]
+pub struct StatusSender(watch::Sender<DeviceStatus>);
+
+impl StatusSender {
+    /// Publish `status` if it differs from the current one
+    pub fn set(&self, status: DeviceStatus) {
+        self.0.send_if_modified(|current| {
+            let changed = *current != status;
+            *current = status;
+            changed
+        });
+    }
+}
+
+pub struct Presence {
+    status: StatusSender,
+    task: JoinHandle<()>,
+}
+
+impl Presence {
+    /// Open the presence connection of `device_id` and announce it online.
+    /// `daemon` is used to build a unique MQTT client id.
+    pub fn spawn(mqtt: &MqttConfig, daemon: &str, device_id: impl fmt::Display) -> Self {
+        let topic = mqtt.router().device_action(&device_id, STATUS_ACTION);
+
+        let mut options = mqtt.options(&format!("{}-{}", daemon, device_id));
+        options.set_last_will(LastWill::new(
+            &topic,
+            DeviceStatus::Offline.as_str(),
+            QoS::AtLeastOnce,
+            true,
+        ));
+
+        let (client, eventloop) = AsyncClient::new(options, 10);
+        let (status, rx) = watch::channel(DeviceStatus::Online);
+        let task = tokio::spawn(run(client, eventloop, topic, rx));
+
+        Self {
+            status: StatusSender(status),
+            task,
+        }
+    }
+
+    pub fn sender(&self) -> StatusSender {
+        self.status.clone()
+    }
+
+    /// Publish `offline` and close the connection, the last will is not
+    /// triggered by a clean disconnection.
+    pub async fn shutdown(self) {
+        self.status.set(DeviceStatus::Offline);
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, self.task)
+            .await
+            .is_err()
+        {
+            warn!("Timeout while publishing the offline status");
+        }
+    }
+}
+
+async fn run(
+    client: AsyncClient,
+    mut eventloop: rumqttc::EventLoop,
+    topic: String,
+    mut rx: watch::Receiver<DeviceStatus>,
+) {
+    loop {
+        tokio::select! {
+            changed = rx.changed() => {
+                let status = *rx.borrow_and_update();
+                if changed.is_err() || status == DeviceStatus::Offline {
+                    break;
+                }
+                publish(&client, &topic, status).await;
+            }
+            event = eventloop.poll() => match event {
+                // (Re)connected, the broker may have published the last will
+                Ok(Incoming(Packet::ConnAck(_))) => {
+                    let status = *rx.borrow();
+                    publish(&client, &topic, status).await;
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    error!("Presence connection error on {}: {}", topic, e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            },
+        }
+    }
+
+    publish(&client, &topic, DeviceStatus::Offline).await;
+    if let Err(e) = client.disconnect().await {
+        error!("Unable to disconnect the presence of {}: {}", topic, e);
+        return;
+    }
+
+    // Drive the connection until the disconnection has been sent
+    loop {
+        match eventloop.poll().await {
+            Ok(Outgoing(rumqttc::Outgoing::Disconnect)) | Err(_) => break,
+            Ok(event) => debug!("Presence event: {:?}", event),
+        }
+    }
+}
+
+async fn publish(client: &AsyncClient, topic: &str, status: DeviceStatus) {
+    if let Err(e) = client
+        .publish(topic, QoS::AtLeastOnce, true, status.as_str())
+        .await
+    {
+        error!("Unable to publish status on {}: {}", topic, e);
+    }
+}
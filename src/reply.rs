@@ -0,0 +1,206 @@
+//! Replies sent back to the clients for every command.
+//!
+//! Replies are published on `<prefix>/devices/<id>/reply` and carry the
+//! `request_id` chosen by the client, so that it can match them with the
+//! command it sent. A command is either `rejected` straight away or
+//! `accepted`, in which case it is later followed by exactly one reply with
+//! status `completed` or `failed`.
+
+use libasi::camera::AsiError;
+use libasi::efw::EfwError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Topic action where the replies of a device are published
+pub const REPLY_ACTION: &str = "reply";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// The command is valid and is being run
+    Accepted,
+    /// The command has not been run, see the error
+    Rejected,
+    Completed,
+    /// The command was accepted but the device failed to run it
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The payload couldn't be parsed
+    InvalidPayload,
+    /// The payload is well formed but a value is not accepted by the device
+    InvalidValue,
+    /// The device can't run the command in its current state
+    Busy,
+    /// The device doesn't support the command
+    NotSupported,
+    /// The device didn't answer in time
+    Timeout,
+    /// The device has been closed or disconnected
+    Unavailable,
+    /// The command was interrupted by another one
+    Aborted,
+    /// Any other error reported by the SDK
+    DeviceError,
+    /// The frame couldn't be delivered to the broker
+    TransferFailed,
+    /// The frame couldn't be written to disk
+    SaveFailed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReplyError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Name of the error returned by the SDK, if that's where it comes from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sdk_error: Option<&'static str>,
+}
+
+impl ReplyError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            sdk_error: None,
+        }
+    }
+
+    /// An `InvalidValue` error pointing at `field`
+    pub fn invalid(field: &str, reason: impl fmt::Display) -> Self {
+        Self::new(ErrorCode::InvalidValue, format!("{}: {}", field, reason))
+    }
+}
+
+impl fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ReplyError {}
+
+impl From<AsiError> for ReplyError {
+    fn from(e: AsiError) -> Self {
+        let code = match e {
+            AsiError::Timeout => ErrorCode::Timeout,
+            AsiError::CameraClosed | AsiError::CameraRemoved => ErrorCode::Unavailable,
+            AsiError::VideoModeActive
+            | AsiError::ExposureInProgress
+            | AsiError::InvalidSequence
+            | AsiError::InvalidMode => ErrorCode::Busy,
+            AsiError::InvalidControlType
+            | AsiError::InvalidSize
+            | AsiError::InvalidImgType
+            | AsiError::OutOfBoundary => ErrorCode::InvalidValue,
+            _ => ErrorCode::DeviceError,
+        };
+
+        Self {
+            code,
+            message: e.to_string(),
+            sdk_error: Some(e.name()),
+        }
+    }
+}
+
+impl From<EfwError> for ReplyError {
+    fn from(e: EfwError) -> Self {
+        let code = match e {
+            EfwError::Removed | EfwError::Closed => ErrorCode::Unavailable,
+            EfwError::Moving => ErrorCode::Busy,
+            EfwError::InvalidValue => ErrorCode::InvalidValue,
+            EfwError::NotSupported => ErrorCode::NotSupported,
+            _ => ErrorCode::DeviceError,
+        };
+
+        Self {
+            code,
+            message: e.to_string(),
+            sdk_error: Some(e.name()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Reply {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub action: String,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ReplyError>,
+}
+
+impl Reply {
+    fn new(
+        action: &str,
+        request_id: Option<String>,
+        status: Status,
+        error: Option<ReplyError>,
+    ) -> Self {
+        Self {
+            request_id,
+            action: action.to_string(),
+            status,
+            error,
+        }
+    }
+
+    pub fn accepted(action: &str, request_id: Option<String>) -> Self {
+        Self::new(action, request_id, Status::Accepted, None)
+    }
+
+    pub fn rejected(action: &str, request_id: Option<String>, error: ReplyError) -> Self {
+        Self::new(action, request_id, Status::Rejected, Some(error))
+    }
+
+    pub fn completed(action: &str, request_id: Option<String>) -> Self {
+        Self::new(action, request_id, Status::Completed, None)
+    }
+
+    pub fn failed(action: &str, request_id: Option<String>, error: ReplyError) -> Self {
+        Self::new(action, request_id, Status::Failed, Some(error))
+    }
+
+    /// `completed` or `failed` depending on `result`
+    pub fn outcome(
+        action: &str,
+        request_id: Option<String>,
+        result: Result<(), ReplyError>,
+    ) -> Self {
+        match result {
+            Ok(()) => Self::completed(action, request_id),
+            Err(e) => Self::failed(action, request_id, e),
+        }
+    }
+}
+
+/// Best effort extraction of the `request_id` of a payload that couldn't be
+/// parsed, so that the error can still be matched by the client.
+pub fn request_id(payload: &[u8]) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(payload).ok()?;
+    value.get("request_id")?.as_str().map(str::to_string)
+}
+
+/// Payload of the commands that carry nothing but the request id, an empty
+/// payload is accepted as well.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimpleRequest {
+    pub request_id: Option<String>,
+}
+
+impl SimpleRequest {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ReplyError> {
+        if payload.iter().all(u8::is_ascii_whitespace) {
+            return Ok(Self::default());
+        }
+
+        serde_json::from_slice(payload)
+            .map_err(|e| ReplyError::new(ErrorCode::InvalidPayload, e.to_string()))
+    }
+}
@@ -0,0 +1,253 @@
+//! SER v3 writer, the format of choice of planetary imaging.
+//!
+//! A SER file is a fixed 178 bytes header followed by the raw frames, all of
+//! the same size, and a trailer holding the UTC timestamp of every frame. The
+//! number of frames is only known at the end, it is written in the header by
+//! `finish`. 16 bit samples are stored little endian, as delivered by the
+//! camera, and flagged so in the header.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const FILE_ID: &[u8; 14] = b"LUCAM-RECORDER";
+const HEADER_SIZE: usize = 178;
+/// Offset of the `FrameCount` field in the header
+const FRAME_COUNT_OFFSET: u64 = 38;
+/// Size of the `Observer`, `Instrument` and `Telescope` fields
+const TEXT_SIZE: usize = 40;
+
+/// Ticks, of 100 ns, between 0001-01-01 and 1970-01-01, SER timestamps are
+/// .NET `DateTime` values
+const UNIX_EPOCH_TICKS: i64 = 621_355_968_000_000_000;
+
+/// `ColorID` of the header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorId {
+    Mono = 0,
+    BayerRggb = 8,
+    BayerGrbg = 9,
+    BayerGbrg = 10,
+    BayerBggr = 11,
+    Rgb = 100,
+    Bgr = 101,
+}
+
+impl ColorId {
+    /// Colour id of raw frames with the given colour filter array pattern,
+    /// e.g. `RGGB`
+    pub fn bayer(pattern: &str) -> Option<Self> {
+        match pattern {
+            "RGGB" => Some(ColorId::BayerRggb),
+            "GRBG" => Some(ColorId::BayerGrbg),
+            "GBRG" => Some(ColorId::BayerGbrg),
+            "BGGR" => Some(ColorId::BayerBggr),
+            _ => None,
+        }
+    }
+
+    fn planes(&self) -> usize {
+        match self {
+            ColorId::Rgb | ColorId::Bgr => 3,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SerHeader {
+    pub color: ColorId,
+    pub width: usize,
+    pub height: usize,
+    /// Bits per sample, up to 8 the samples take one byte, two otherwise
+    pub bit_depth: u32,
+    pub observer: String,
+    pub instrument: String,
+    pub telescope: String,
+}
+
+impl SerHeader {
+    /// Size in bytes of a frame
+    pub fn frame_size(&self) -> usize {
+        let bytes = if self.bit_depth > 8 { 2 } else { 1 };
+        self.width * self.height * self.color.planes() * bytes
+    }
+
+    fn to_bytes(&self, frames: u32, started: SystemTime) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(FILE_ID);
+        // LuID, unused
+        header.extend_from_slice(&0_i32.to_le_bytes());
+        header.extend_from_slice(&(self.color as i32).to_le_bytes());
+        // LittleEndian
+        header.extend_from_slice(&1_i32.to_le_bytes());
+        header.extend_from_slice(&(self.width as i32).to_le_bytes());
+        header.extend_from_slice(&(self.height as i32).to_le_bytes());
+        header.extend_from_slice(&(self.bit_depth as i32).to_le_bytes());
+        header.extend_from_slice(&(frames as i32).to_le_bytes());
+        for text in [&self.observer, &self.instrument, &self.telescope] {
+            header.extend_from_slice(&text_field(text));
+        }
+        // The local time zone is unknown, both dates are in UTC
+        header.extend_from_slice(&ticks(started).to_le_bytes());
+        header.extend_from_slice(&ticks(started).to_le_bytes());
+
+        header
+    }
+}
+
+/// ASCII only, truncated or padded with zeros
+fn text_field(text: &str) -> [u8; TEXT_SIZE] {
+    let mut field = [0; TEXT_SIZE];
+    for (dst, c) in field.iter_mut().zip(text.chars().filter(char::is_ascii)) {
+        *dst = c as u8;
+    }
+    field
+}
+
+/// `time` as .NET ticks
+fn ticks(time: SystemTime) -> i64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    UNIX_EPOCH_TICKS + (since_epoch.as_nanos() / 100) as i64
+}
+
+pub struct SerWriter {
+    out: BufWriter<File>,
+    header: SerHeader,
+    /// UTC time of every frame written so far, in ticks
+    timestamps: Vec<i64>,
+}
+
+impl SerWriter {
+    /// Create the file at `path`, and its parent directories, for a recording
+    /// starting at `started`
+    pub fn create(path: &Path, header: SerHeader, started: SystemTime) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(&header.to_bytes(0, started))?;
+
+        Ok(Self {
+            out,
+            header,
+            timestamps: Vec::new(),
+        })
+    }
+
+    /// Append a frame received at `time`
+    pub fn add_frame(&mut self, data: &[u8], time: SystemTime) -> io::Result<()> {
+        if data.len() != self.header.frame_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "frames must be {} bytes, got {}",
+                    self.header.frame_size(),
+                    data.len()
+                ),
+            ));
+        }
+
+        self.out.write_all(data)?;
+        self.timestamps.push(ticks(time));
+        Ok(())
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.timestamps.len() as u32
+    }
+
+    /// Write the timestamps trailer and the final number of frames, returns
+    /// the number of frames
+    pub fn finish(mut self) -> io::Result<u32> {
+        for t in &self.timestamps {
+            self.out.write_all(&t.to_le_bytes())?;
+        }
+
+        let frames = self.frames();
+        self.out.seek(SeekFrom::Start(FRAME_COUNT_OFFSET))?;
+        self.out.write_all(&(frames as i32).to_le_bytes())?;
+        self.out.flush()?;
+
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn header(color: ColorId, bit_depth: u32) -> SerHeader {
+        SerHeader {
+            color,
+            width: 4,
+            height: 2,
+            bit_depth,
+            observer: "Jane Doe".into(),
+            instrument: "ZWO ASI462MC".into(),
+            telescope: "C11".into(),
+        }
+    }
+
+    fn i32_at(bytes: &[u8], offset: usize) -> i32 {
+        i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn i64_at(bytes: &[u8], offset: usize) -> i64 {
+        i64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn frame_size_follows_depth_and_planes() {
+        assert_eq!(header(ColorId::Mono, 8).frame_size(), 8);
+        assert_eq!(header(ColorId::BayerRggb, 12).frame_size(), 16);
+        assert_eq!(header(ColorId::Bgr, 8).frame_size(), 24);
+    }
+
+    #[test]
+    fn converts_to_net_ticks() {
+        assert_eq!(ticks(UNIX_EPOCH), UNIX_EPOCH_TICKS);
+        assert_eq!(
+            ticks(UNIX_EPOCH + Duration::from_micros(1)),
+            UNIX_EPOCH_TICKS + 10
+        );
+    }
+
+    #[test]
+    fn writes_frames_and_trailer() {
+        let dir = std::env::temp_dir().join(format!("asi-rs-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("capture.ser");
+        let started = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let mut writer = SerWriter::create(&path, header(ColorId::BayerRggb, 12), started).unwrap();
+        for i in 0..3u8 {
+            let time = started + Duration::from_millis(10 * i as u64);
+            writer.add_frame(&[i; 16], time).unwrap();
+        }
+        assert!(writer.add_frame(&[0; 8], started).is_err());
+        assert_eq!(writer.finish().unwrap(), 3);
+
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(bytes.len(), HEADER_SIZE + 3 * 16 + 3 * 8);
+        assert_eq!(&bytes[..14], FILE_ID);
+        assert_eq!(i32_at(&bytes, 18), ColorId::BayerRggb as i32);
+        assert_eq!(i32_at(&bytes, 22), 1);
+        assert_eq!(i32_at(&bytes, 26), 4);
+        assert_eq!(i32_at(&bytes, 30), 2);
+        assert_eq!(i32_at(&bytes, 34), 12);
+        assert_eq!(i32_at(&bytes, FRAME_COUNT_OFFSET as usize), 3);
+        assert_eq!(&bytes[42..50], b"Jane Doe");
+        assert!(bytes[50..82].iter().all(|b| *b == 0));
+        assert_eq!(i64_at(&bytes, 162), ticks(started));
+
+        assert!(bytes[HEADER_SIZE + 16..HEADER_SIZE + 32]
+            .iter()
+            .all(|b| *b == 1));
+        let trailer = HEADER_SIZE + 3 * 16;
+        assert_eq!(i64_at(&bytes, trailer + 16), ticks(started) + 20 * 10_000);
+    }
+}
@@ -0,0 +1,661 @@
+//! Star detection and measurement, to check the focus and reject the bad
+//! frames.
+//!
+//! The background and its noise are estimated on tiles of the frame, from
+//! their median and median absolute deviation, and interpolated between the
+//! tiles. Pixels above the background by more than `threshold` times the noise
+//! are grouped in 8-connected blobs. A blob having several peaks separated by
+//! a dip deeper than the detection threshold is split between them, so that
+//! close pairs are measured as two stars.
+//!
+//! Each star is measured on the background subtracted pixels of a circular
+//! aperture around it:
+//!
+//! - the centroid, weighted by the flux
+//! - the HFR, the flux weighted mean distance to the centroid
+//! - the FWHM and the eccentricity, from the second moments, assuming a
+//!   Gaussian profile
+//!
+//! Saturated stars, stars touching the edges and blobs too small to be stars
+//! (hot pixels) or too large (nebulae, trails) are left out. Mosaics of colour
+//! cameras are binned 2x2 first so that the filters don't look like structure,
+//! the measures are always in pixels of the frame.
+
+use crate::fits::{Image, PixelFormat};
+use crate::stats;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+
+/// Side of the tiles the background is estimated on
+const TILE_SIZE: usize = 64;
+
+/// Bounds of the number of pixels above the threshold of a star
+const MIN_AREA: usize = 3;
+const MAX_AREA: usize = 5000;
+
+/// Below this FWHM, in pixels, a blob is a cluster of hot pixels
+const MIN_FWHM: f64 = 0.8;
+
+/// Ratio between the FWHM and the standard deviation of a Gaussian
+const FWHM_PER_SIGMA: f64 = 2.354_820_045;
+
+/// Ratio between the standard deviation and the median absolute deviation of
+/// a normal distribution
+const SIGMA_PER_MAD: f32 = 1.4826;
+
+/// Label of the pixels belonging to nothing
+const NONE: usize = usize::MAX;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Star {
+    /// Centroid, from the top left corner of the frame
+    pub x: f64,
+    pub y: f64,
+    /// Sum of the background subtracted pixels
+    pub flux: f64,
+    /// Highest background subtracted pixel
+    pub peak: f64,
+    pub hfr: f64,
+    pub fwhm: f64,
+    /// 0 for a round star, close to 1 for an elongated one
+    pub eccentricity: f64,
+}
+
+/// Median measures of the stars of a frame, `None` without any star
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StarSummary {
+    pub count: usize,
+    pub hfr: Option<f64>,
+    pub fwhm: Option<f64>,
+    pub eccentricity: Option<f64>,
+}
+
+impl StarSummary {
+    pub fn new(stars: &[Star]) -> Self {
+        let median_of = |measure: fn(&Star) -> f64| {
+            let mut values: Vec<f64> = stars.iter().map(measure).collect();
+            median(&mut values)
+        };
+
+        Self {
+            count: stars.len(),
+            hfr: median_of(|s| s.hfr),
+            fwhm: median_of(|s| s.fwhm),
+            eccentricity: median_of(|s| s.eccentricity),
+        }
+    }
+}
+
+/// Stars of `image`, taken by a sensor with `sensor_bits` bits per pixel,
+/// detected `threshold` times the noise above the background
+pub fn detect(image: &Image, sensor_bits: u32, threshold: f64) -> io::Result<Vec<Star>> {
+    image.check()?;
+    let plane = Plane::new(image, sensor_bits);
+    let background = Background::new(&plane);
+    let threshold = threshold as f32;
+
+    // Background subtracted value of every pixel, and whether it is above
+    // the threshold
+    let mut residual = vec![0.0; plane.data.len()];
+    let mut above = vec![false; plane.data.len()];
+    for y in 0..plane.height {
+        for x in 0..plane.width {
+            let i = y * plane.width + x;
+            let (level, noise) = background.at(x, y);
+            residual[i] = plane.data[i] - level;
+            above[i] = residual[i] > threshold * noise;
+        }
+    }
+
+    let mut blobs = Vec::new();
+    let mut seen = vec![false; above.len()];
+    for start in 0..above.len() {
+        if !above[start] || seen[start] {
+            continue;
+        }
+        seen[start] = true;
+
+        let mut blob = vec![start];
+        let mut next = 0;
+        while next < blob.len() {
+            for n in plane.neighbours(blob[next]) {
+                if above[n] && !seen[n] {
+                    seen[n] = true;
+                    blob.push(n);
+                }
+            }
+            next += 1;
+        }
+
+        if (MIN_AREA..=MAX_AREA).contains(&blob.len()) && !blob.iter().any(|i| plane.on_edge(*i)) {
+            blobs.push(blob);
+        }
+    }
+
+    // Blob of every pixel above the threshold, the apertures must not
+    // measure the neighbouring stars
+    let mut owner = vec![NONE; plane.data.len()];
+    let mut stars_pixels = Vec::new();
+    for blob in &blobs {
+        let peak = blob.iter().map(|i| residual[*i]).fold(f32::MIN, f32::max);
+        let center = blob
+            .iter()
+            .find(|i| residual[**i] == peak)
+            .copied()
+            .unwrap_or(blob[0]);
+        let (_, noise) = background.at(center % plane.width, center / plane.width);
+
+        for pixels in deblend(&plane, blob, &residual, threshold * noise) {
+            for i in &pixels {
+                owner[*i] = stars_pixels.len();
+            }
+            stars_pixels.push(pixels);
+        }
+    }
+
+    let mut stars: Vec<Star> = stars_pixels
+        .iter()
+        .enumerate()
+        .filter(|(_, pixels)| !pixels.iter().any(|i| plane.clipped[*i]))
+        .filter_map(|(id, pixels)| measure(&plane, &background, pixels, id, &owner, &residual))
+        .filter(|s| s.fwhm >= MIN_FWHM)
+        .collect();
+
+    // A pixel of a binned plane is centered between the pixels of the frame
+    // it is made of
+    let scale = plane.scale as f64;
+    for star in &mut stars {
+        star.x = star.x * scale + (scale - 1.0) / 2.0;
+        star.y = star.y * scale + (scale - 1.0) / 2.0;
+        star.hfr *= scale;
+        star.fwhm *= scale;
+    }
+    Ok(stars)
+}
+
+/// Luminance of a frame
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+    /// Pixels of the frame per pixel of the plane, along each axis
+    scale: usize,
+    /// Whether each pixel is made of at least one saturated sample
+    clipped: Vec<bool>,
+}
+
+impl Plane {
+    fn new(image: &Image, sensor_bits: u32) -> Self {
+        let saturation = stats::saturation(image.format, sensor_bits) as f32;
+        let samples: Vec<f32> = match image.format {
+            PixelFormat::Mono8 | PixelFormat::Bgr24 => {
+                image.data.iter().map(|v| f32::from(*v)).collect()
+            }
+            PixelFormat::Mono16 => image
+                .data
+                .chunks_exact(2)
+                .map(|p| f32::from(u16::from_le_bytes([p[0], p[1]])))
+                .collect(),
+        };
+
+        // Offsets of the samples making each pixel of the plane, from the
+        // first one
+        let (width, height, scale, offsets) = match (image.format, image.bayer) {
+            (PixelFormat::Bgr24, _) => (image.width, image.height, 1, vec![0, 1, 2]),
+            (_, Some(_)) => {
+                let w = image.width;
+                (w / 2, image.height / 2, 2, vec![0, 1, w, w + 1])
+            }
+            (_, None) => (image.width, image.height, 1, vec![0]),
+        };
+
+        let mut data = Vec::with_capacity(width * height);
+        let mut clipped = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let first = match (image.format, scale) {
+                    (PixelFormat::Bgr24, _) => (y * width + x) * 3,
+                    (_, 2) => 2 * y * image.width + 2 * x,
+                    _ => y * width + x,
+                };
+                let values = offsets.iter().map(|offset| samples[first + offset]);
+                data.push(values.clone().sum());
+                clipped.push(values.into_iter().any(|v| v >= saturation));
+            }
+        }
+
+        Self {
+            width,
+            height,
+            data,
+            scale,
+            clipped,
+        }
+    }
+
+    fn neighbours(&self, i: usize) -> impl Iterator<Item = usize> + '_ {
+        let (x, y) = ((i % self.width) as isize, (i / self.width) as isize);
+        (-1..=1)
+            .flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+            .filter(move |(nx, ny)| {
+                (*nx, *ny) != (x, y)
+                    && (0..self.width as isize).contains(nx)
+                    && (0..self.height as isize).contains(ny)
+            })
+            .map(|(nx, ny)| ny as usize * self.width + nx as usize)
+    }
+
+    fn on_edge(&self, i: usize) -> bool {
+        let (x, y) = (i % self.width, i / self.width);
+        x == 0 || y == 0 || x + 1 == self.width || y + 1 == self.height
+    }
+}
+
+/// Level and noise of the background, measured on tiles
+struct Background {
+    columns: usize,
+    rows: usize,
+    level: Vec<f32>,
+    noise: Vec<f32>,
+}
+
+impl Background {
+    fn new(plane: &Plane) -> Self {
+        let columns = plane.width.div_ceil(TILE_SIZE).max(1);
+        let rows = plane.height.div_ceil(TILE_SIZE).max(1);
+        let mut level = Vec::with_capacity(columns * rows);
+        let mut noise = Vec::with_capacity(columns * rows);
+
+        let mut values = Vec::with_capacity(TILE_SIZE * TILE_SIZE);
+        for row in 0..rows {
+            for column in 0..columns {
+                values.clear();
+                for y in row * TILE_SIZE..((row + 1) * TILE_SIZE).min(plane.height) {
+                    let line = &plane.data[y * plane.width..(y + 1) * plane.width];
+                    values.extend_from_slice(
+                        &line[column * TILE_SIZE..((column + 1) * TILE_SIZE).min(plane.width)],
+                    );
+                }
+
+                let median = median_f32(&mut values);
+                let mut deviations: Vec<f32> = values.iter().map(|v| (v - median).abs()).collect();
+                // Keep a floor so that a flat synthetic or clipped background
+                // doesn't make every pixel a detection
+                let sigma = (median_f32(&mut deviations) * SIGMA_PER_MAD).max(0.5);
+
+                // The median sticks to the steps of the sensors with less
+                // than 16 bits, the mean of the values close to it doesn't
+                let (sum, count) = values
+                    .iter()
+                    .filter(|v| (*v - median).abs() <= 3.0 * sigma)
+                    .fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+                level.push(if count > 0 {
+                    sum / count as f32
+                } else {
+                    median
+                });
+                noise.push(sigma);
+            }
+        }
+
+        Self {
+            columns,
+            rows,
+            level,
+            noise,
+        }
+    }
+
+    /// Level and noise at `x`, `y`, bilinearly interpolated between the
+    /// centers of the tiles
+    fn at(&self, x: usize, y: usize) -> (f32, f32) {
+        let position = |v: usize, count: usize| {
+            let f = ((v as f32 + 0.5) / TILE_SIZE as f32 - 0.5).clamp(0.0, (count - 1) as f32);
+            let i = f.floor() as usize;
+            (i, (i + 1).min(count - 1), f - i as f32)
+        };
+        let (x0, x1, tx) = position(x, self.columns);
+        let (y0, y1, ty) = position(y, self.rows);
+
+        let interpolate = |grid: &[f32]| {
+            let top = grid[y0 * self.columns + x0] * (1.0 - tx) + grid[y0 * self.columns + x1] * tx;
+            let bottom =
+                grid[y1 * self.columns + x0] * (1.0 - tx) + grid[y1 * self.columns + x1] * tx;
+            top * (1.0 - ty) + bottom * ty
+        };
+        (interpolate(&self.level), interpolate(&self.noise))
+    }
+}
+
+/// Split `blob` between its significant peaks. Every pixel climbs to the
+/// brightest of its neighbours until it reaches a peak, then the peaks less
+/// than `prominence` above the dip separating them from a brighter peak are
+/// merged into it.
+fn deblend(plane: &Plane, blob: &[usize], residual: &[f32], prominence: f32) -> Vec<Vec<usize>> {
+    // Position in `blob` of the pixels of its bounding box
+    let (mut x0, mut y0, mut x1, mut y1) = (usize::MAX, usize::MAX, 0, 0);
+    for &i in blob {
+        let (x, y) = (i % plane.width, i / plane.width);
+        (x0, y0, x1, y1) = (x0.min(x), y0.min(y), x1.max(x), y1.max(y));
+    }
+    let box_width = x1 - x0 + 1;
+    let mut labels = vec![NONE; box_width * (y1 - y0 + 1)];
+    for (n, &i) in blob.iter().enumerate() {
+        labels[(i / plane.width - y0) * box_width + i % plane.width - x0] = n;
+    }
+    let index = |i: usize| {
+        let (x, y) = (i % plane.width, i / plane.width);
+        if x < x0 || x > x1 || y < y0 || y > y1 {
+            return None;
+        }
+        Some(labels[(y - y0) * box_width + x - x0]).filter(|n| *n != NONE)
+    };
+    // Ties are broken on the position so that climbing always ends
+    let key = |i: usize| (residual[i], i);
+
+    let mut parent: Vec<usize> = blob
+        .iter()
+        .enumerate()
+        .map(|(n, &i)| {
+            plane
+                .neighbours(i)
+                .filter_map(|j| index(j).map(|m| (j, m)))
+                .max_by(|a, b| {
+                    key(a.0)
+                        .partial_cmp(&key(b.0))
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                })
+                .filter(|(j, _)| key(*j) > key(i))
+                .map_or(n, |(_, m)| m)
+        })
+        .collect();
+    for n in 0..blob.len() {
+        let mut root = n;
+        while parent[root] != root {
+            root = parent[root];
+        }
+        parent[n] = root;
+    }
+
+    let mut peaks: Vec<usize> = (0..blob.len()).filter(|n| parent[*n] == *n).collect();
+    if peaks.len() == 1 {
+        return vec![blob.to_vec()];
+    }
+
+    // Highest dip between every pair of neighbouring peaks
+    let mut dips: HashMap<(usize, usize), f32> = HashMap::new();
+    for (n, &i) in blob.iter().enumerate() {
+        for m in plane.neighbours(i).filter_map(index) {
+            let (a, b) = (parent[n], parent[m]);
+            if a < b {
+                let dip = residual[i].min(residual[blob[m]]);
+                let entry = dips.entry((a, b)).or_insert(dip);
+                *entry = entry.max(dip);
+            }
+        }
+    }
+
+    // Merge from the faintest peak, the group of a peak is named after its
+    // brightest one
+    let value = |n: usize| residual[blob[n]];
+    peaks.sort_by(|a, b| value(*a).total_cmp(&value(*b)));
+    let mut group: Vec<usize> = (0..blob.len()).collect();
+    let find = |group: &[usize], mut p: usize| {
+        while group[p] != p {
+            p = group[p];
+        }
+        p
+    };
+
+    for &peak in &peaks {
+        let own = find(&group, peak);
+        let deepest = dips
+            .iter()
+            .filter_map(|(&(a, b), &dip)| {
+                let (ga, gb) = (find(&group, a), find(&group, b));
+                match (ga == own, gb == own) {
+                    (true, false) => Some((gb, dip)),
+                    (false, true) => Some((ga, dip)),
+                    _ => None,
+                }
+            })
+            .filter(|(other, _)| value(*other) > value(own))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+
+        if let Some((other, dip)) = deepest
+            && value(own) - dip < prominence
+        {
+            group[own] = other;
+        }
+    }
+
+    let mut split: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (n, &i) in blob.iter().enumerate() {
+        split.entry(find(&group, parent[n])).or_default().push(i);
+    }
+    split.into_values().collect()
+}
+
+/// Measure the star `id` made of `pixels`, `None` if it has no flux
+fn measure(
+    plane: &Plane,
+    background: &Background,
+    pixels: &[usize],
+    id: usize,
+    owner: &[usize],
+    residual: &[f32],
+) -> Option<Star> {
+    let (mut flux, mut sx, mut sy) = (0.0, 0.0, 0.0);
+    for &i in pixels {
+        let v = residual[i] as f64;
+        flux += v;
+        sx += v * (i % plane.width) as f64;
+        sy += v * (i / plane.width) as f64;
+    }
+    if flux <= 0.0 {
+        return None;
+    }
+    let (cx, cy) = (sx / flux, sy / flux);
+
+    // The aperture reaches well into the wings of the star
+    let radius = 2.0 * (pixels.len() as f64 / std::f64::consts::PI).sqrt() + 2.0;
+    let (x0, x1) = (
+        (cx - radius).floor().max(0.0) as usize,
+        ((cx + radius).ceil() as usize).min(plane.width - 1),
+    );
+    let (y0, y1) = (
+        (cy - radius).floor().max(0.0) as usize,
+        ((cy + radius).ceil() as usize).min(plane.height - 1),
+    );
+
+    let mut samples = Vec::new();
+    for y in y0..=y1 {
+        for x in x0..=x1 {
+            let i = y * plane.width + x;
+            let (dx, dy) = (x as f64 - cx, y as f64 - cy);
+            if dx * dx + dy * dy > radius * radius || (owner[i] != NONE && owner[i] != id) {
+                continue;
+            }
+            let (level, _) = background.at(x, y);
+            // Negative values are kept, clipping them would leave the noise
+            // of the background in the measures
+            let v = (plane.data[i] - level) as f64;
+            samples.push((dx, dy, v));
+        }
+    }
+
+    let flux: f64 = samples.iter().map(|s| s.2).sum();
+    if flux <= 0.0 {
+        return None;
+    }
+    let peak = samples.iter().map(|s| s.2).fold(0.0, f64::max);
+    // Centroid of the aperture, refined from the one of the blob
+    let (mx, my) = samples
+        .iter()
+        .fold((0.0, 0.0), |(x, y), s| (x + s.0 * s.2, y + s.1 * s.2));
+    let (mx, my) = (mx / flux, my / flux);
+
+    let (mut hfr, mut xx, mut yy, mut xy) = (0.0, 0.0, 0.0, 0.0);
+    for (dx, dy, v) in &samples {
+        let (dx, dy) = (dx - mx, dy - my);
+        hfr += v * (dx * dx + dy * dy).sqrt();
+        xx += v * dx * dx;
+        yy += v * dy * dy;
+        xy += v * dx * dy;
+    }
+    let (xx, yy, xy) = (xx / flux, yy / flux, xy / flux);
+    if hfr <= 0.0 || xx <= 0.0 || yy <= 0.0 {
+        return None;
+    }
+
+    // Axes of the ellipse, from the eigenvalues of the covariance matrix
+    let spread = (((xx - yy) / 2.0).powi(2) + xy * xy).sqrt();
+    let major = (xx + yy) / 2.0 + spread;
+    let minor = ((xx + yy) / 2.0 - spread).max(0.0);
+    let eccentricity = if major > 0.0 {
+        (1.0 - minor / major).sqrt()
+    } else {
+        0.0
+    };
+
+    Some(Star {
+        x: cx + mx,
+        y: cy + my,
+        flux,
+        peak,
+        hfr: hfr / flux,
+        fwhm: FWHM_PER_SIGMA * ((xx + yy) / 2.0).sqrt(),
+        eccentricity,
+    })
+}
+
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    Some(if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    })
+}
+
+fn median_f32(values: &mut [f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mid = values.len() / 2;
+    *values.select_nth_unstable_by(mid, f32::total_cmp).1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 200;
+    const HEIGHT: usize = 160;
+    const BACKGROUND: f64 = 1000.0;
+
+    /// Background with a little deterministic noise
+    fn sky() -> Vec<f64> {
+        let mut state = 12345_u32;
+        (0..WIDTH * HEIGHT)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                BACKGROUND + ((state >> 16) % 21) as f64 - 10.0
+            })
+            .collect()
+    }
+
+    /// Add a Gaussian star of standard deviation `sigma` centered on `x`, `y`
+    fn add_star(pixels: &mut [f64], x: f64, y: f64, amplitude: f64, sigma: f64) {
+        for (i, p) in pixels.iter_mut().enumerate() {
+            let (dx, dy) = ((i % WIDTH) as f64 - x, (i / WIDTH) as f64 - y);
+            *p += amplitude * (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp();
+        }
+    }
+
+    fn detect_in(pixels: &[f64]) -> Vec<Star> {
+        let data: Vec<u8> = pixels
+            .iter()
+            .flat_map(|p| (p.round().min(65535.0) as u16).to_le_bytes())
+            .collect();
+        let image = Image {
+            width: WIDTH,
+            height: HEIGHT,
+            format: PixelFormat::Mono16,
+            bayer: None,
+            data: &data,
+        };
+        detect(&image, 16, 5.0).unwrap()
+    }
+
+    #[test]
+    fn measures_gaussian_star() {
+        let sigma = 2.0;
+        let mut pixels = sky();
+        add_star(&mut pixels, 80.3, 70.6, 20000.0, sigma);
+
+        let stars = detect_in(&pixels);
+        assert_eq!(stars.len(), 1);
+        let star = &stars[0];
+        assert!((star.x - 80.3).abs() < 0.05, "x {}", star.x);
+        assert!((star.y - 70.6).abs() < 0.05, "y {}", star.y);
+
+        // The mean distance to the center of a 2D Gaussian is sigma √(π/2)
+        let hfr = sigma * (std::f64::consts::PI / 2.0).sqrt();
+        let fwhm = FWHM_PER_SIGMA * sigma;
+        assert!((star.hfr - hfr).abs() / hfr < 0.02, "HFR {}", star.hfr);
+        assert!((star.fwhm - fwhm).abs() / fwhm < 0.02, "FWHM {}", star.fwhm);
+        assert!(
+            star.eccentricity < 0.2,
+            "eccentricity {}",
+            star.eccentricity
+        );
+
+        let flux = 20000.0 * 2.0 * std::f64::consts::PI * sigma * sigma;
+        assert!((star.flux - flux).abs() / flux < 0.02, "flux {}", star.flux);
+    }
+
+    #[test]
+    fn splits_close_pair() {
+        let mut pixels = sky();
+        add_star(&mut pixels, 60.0, 80.0, 15000.0, 1.5);
+        add_star(&mut pixels, 67.0, 80.0, 10000.0, 1.5);
+
+        let mut stars = detect_in(&pixels);
+        stars.sort_by(|a, b| a.x.total_cmp(&b.x));
+        assert_eq!(stars.len(), 2);
+        assert!((stars[0].x - 60.0).abs() < 0.3, "x {}", stars[0].x);
+        assert!((stars[1].x - 67.0).abs() < 0.3, "x {}", stars[1].x);
+    }
+
+    #[test]
+    fn ignores_hot_and_saturated_pixels() {
+        let mut pixels = sky();
+        pixels[50 * WIDTH + 50] = 30000.0;
+        add_star(&mut pixels, 120.0, 40.0, 80000.0, 2.0);
+        add_star(&mut pixels, 150.0, 120.0, 20000.0, 2.0);
+
+        let stars = detect_in(&pixels);
+        assert_eq!(stars.len(), 1);
+        assert!((stars[0].x - 150.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn summarizes_stars() {
+        let mut pixels = sky();
+        for (i, sigma) in [1.5, 2.0, 2.5].into_iter().enumerate() {
+            add_star(&mut pixels, 40.0 + 50.0 * i as f64, 80.0, 20000.0, sigma);
+        }
+
+        let summary = StarSummary::new(&detect_in(&pixels));
+        assert_eq!(summary.count, 3);
+        let fwhm = summary.fwhm.unwrap();
+        assert!((fwhm - FWHM_PER_SIGMA * 2.0).abs() < 0.1, "FWHM {}", fwhm);
+        assert!(StarSummary::new(&[]).hfr.is_none());
+    }
+}
@@ -0,0 +1,157 @@
+//! Change tracking of the device state.
+//!
+//! The full state of a device is published, retained, on
+//! `<prefix>/devices/<id>` only every now and then: in between only the
+//! properties that changed since the last publication go out on
+//! `<prefix>/devices/<id>/changes`. A client applies the changes on top of the
+//! last snapshot, or asks for a fresh one on `<prefix>/devices/<id>/snapshot`.
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::time::{Duration, Instant};
+
+/// Topic action where the changed properties are published
+pub const CHANGES_ACTION: &str = "changes";
+
+/// Topic action used by the clients to request a full snapshot
+pub const SNAPSHOT_ACTION: &str = "snapshot";
+
+/// What should be published after a poll
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateUpdate {
+    /// Serialized full state
+    Snapshot(String),
+    /// Serialized object holding only the properties that changed, a property
+    /// that disappeared is set to `null`
+    Changes(String),
+}
+
+#[derive(Debug)]
+pub struct StateTracker {
+    /// State as it was last published
+    last: Map<String, Value>,
+    snapshot_interval: Duration,
+    last_snapshot: Option<Instant>,
+}
+
+impl StateTracker {
+    pub fn new(snapshot_interval: Duration) -> Self {
+        Self {
+            last: Map::new(),
+            snapshot_interval,
+            last_snapshot: None,
+        }
+    }
+
+    /// Compare `state` with the last published one. A snapshot is returned if
+    /// it is due, otherwise the changed properties if there is any.
+    pub fn update<T: Serialize>(&mut self, state: &T) -> serde_json::Result<Option<StateUpdate>> {
+        let due = self
+            .last_snapshot
+            .is_none_or(|t| t.elapsed() >= self.snapshot_interval);
+        if due {
+            return self.snapshot(state).map(Some);
+        }
+
+        let current = to_map(state)?;
+        let mut changes: Map<String, Value> = current
+            .iter()
+            .filter(|(k, v)| self.last.get(*k) != Some(*v))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        for k in self.last.keys().filter(|k| !current.contains_key(*k)) {
+            changes.insert(k.clone(), Value::Null);
+        }
+        self.last = current;
+
+        if changes.is_empty() {
+            return Ok(None);
+        }
+        serde_json::to_string(&changes).map(|c| Some(StateUpdate::Changes(c)))
+    }
+
+    /// Full snapshot of `state`, regardless of when the last one was published
+    pub fn snapshot<T: Serialize>(&mut self, state: &T) -> serde_json::Result<StateUpdate> {
+        let current = to_map(state)?;
+        let serialized = serde_json::to_string(&current)?;
+        self.last = current;
+        self.last_snapshot = Some(Instant::now());
+
+        Ok(StateUpdate::Snapshot(serialized))
+    }
+}
+
+fn to_map<T: Serialize>(state: &T) -> serde_json::Result<Map<String, Value>> {
+    match serde_json::to_value(state)? {
+        Value::Object(map) => Ok(map),
+        _ => Err(serde::ser::Error::custom("the state must be a JSON object")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn tracker() -> StateTracker {
+        StateTracker::new(Duration::from_secs(3600))
+    }
+
+    #[test]
+    fn first_update_is_a_snapshot() {
+        let mut tracker = tracker();
+        let update = tracker.update(&json!({"slot": 1})).unwrap();
+        assert_eq!(update, Some(StateUpdate::Snapshot(r#"{"slot":1}"#.into())));
+    }
+
+    #[test]
+    fn unchanged_state_publishes_nothing() {
+        let mut tracker = tracker();
+        tracker.update(&json!({"slot": 1})).unwrap();
+        assert_eq!(tracker.update(&json!({"slot": 1})).unwrap(), None);
+    }
+
+    #[test]
+    fn changes_carry_only_the_changed_properties() {
+        let mut tracker = tracker();
+        tracker
+            .update(&json!({"slot": 1, "moving": false, "name": "EFW"}))
+            .unwrap();
+
+        let update = tracker
+            .update(&json!({"slot": 2, "moving": false}))
+            .unwrap();
+        let Some(StateUpdate::Changes(changes)) = update else {
+            panic!("expected changes, got {:?}", update);
+        };
+        let changes: Value = serde_json::from_str(&changes).unwrap();
+        assert_eq!(changes, json!({"name": null, "slot": 2}));
+        assert!(changes.get("moving").is_none());
+    }
+
+    #[test]
+    fn snapshot_is_due_after_the_interval() {
+        let mut tracker = StateTracker::new(Duration::ZERO);
+        tracker.update(&json!({"slot": 1})).unwrap();
+        assert_eq!(
+            tracker.update(&json!({"slot": 1})).unwrap(),
+            Some(StateUpdate::Snapshot(r#"{"slot":1}"#.into()))
+        );
+    }
+
+    #[test]
+    fn snapshot_on_request() {
+        let mut tracker = tracker();
+        tracker.update(&json!({"slot": 1})).unwrap();
+        assert_eq!(
+            tracker.snapshot(&json!({"slot": 2})).unwrap(),
+            StateUpdate::Snapshot(r#"{"slot":2}"#.into())
+        );
+        assert_eq!(tracker.update(&json!({"slot": 2})).unwrap(), None);
+    }
+
+    #[test]
+    fn state_must_be_an_object() {
+        assert!(tracker().update(&json!([1, 2])).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+//! Statistics of the frames, to judge an exposure without looking at it.
+//!
+//! Every colour is measured on its own: the three channels of `RGB24` frames,
+//! and the pixels under each filter of the mosaics of colour cameras, the two
+//! greens together. The statistics are exact, they come from the count of
+//! every possible value.
+
+use crate::fits::{Image, PixelFormat};
+use serde::Serialize;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Gray,
+    Red,
+    Green,
+    Blue,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelStats {
+    pub channel: Channel,
+    pub min: u16,
+    pub max: u16,
+    pub mean: f64,
+    pub median: f64,
+    pub std_dev: f64,
+    /// Pixels at the saturation level of the sensor or above
+    pub saturated: u64,
+    /// Pixel count of evenly sized ranges of values, from 0 to the largest
+    /// value of the sample format
+    pub histogram: Vec<u64>,
+}
+
+/// Statistics of every channel of `image`, whose samples come from a sensor
+/// with `sensor_bits` bits per pixel, split in `bins` histogram bins
+pub fn compute(image: &Image, sensor_bits: u32, bins: usize) -> io::Result<Vec<ChannelStats>> {
+    image.check()?;
+    let levels = match image.format {
+        PixelFormat::Mono16 => 1 << 16,
+        PixelFormat::Mono8 | PixelFormat::Bgr24 => 1 << 8,
+    };
+    let channels = match (image.format, image.bayer) {
+        (PixelFormat::Bgr24, _) | (_, Some(_)) => vec![Channel::Red, Channel::Green, Channel::Blue],
+        (_, None) => vec![Channel::Gray],
+    };
+
+    // Index in `channels` of the pixel `i` of a mono frame
+    let channel = |i: usize| match image.bayer {
+        Some(pattern) => pattern.channel(i % image.width, i / image.width),
+        None => 0,
+    };
+    let mut counts = vec![vec![0_u64; levels]; channels.len()];
+    match image.format {
+        PixelFormat::Bgr24 => {
+            for p in image.data.chunks_exact(3) {
+                counts[0][p[2] as usize] += 1;
+                counts[1][p[1] as usize] += 1;
+                counts[2][p[0] as usize] += 1;
+            }
+        }
+        PixelFormat::Mono8 => {
+            for (i, v) in image.data.iter().enumerate() {
+                counts[channel(i)][*v as usize] += 1;
+            }
+        }
+        PixelFormat::Mono16 => {
+            for (i, p) in image.data.chunks_exact(2).enumerate() {
+                counts[channel(i)][u16::from_le_bytes([p[0], p[1]]) as usize] += 1;
+            }
+        }
+    }
+
+    let saturation = saturation(image.format, sensor_bits);
+    Ok(channels
+        .into_iter()
+        .zip(&counts)
+        .map(|(channel, counts)| channel_stats(channel, counts, saturation, bins))
+        .collect())
+}
+
+/// Lowest saturated value: the SDK aligns the samples of the sensors with less
+/// than 16 bits on the most significant bit
+pub(crate) fn saturation(format: PixelFormat, sensor_bits: u32) -> usize {
+    match format {
+        PixelFormat::Mono16 => {
+            let bits = sensor_bits.clamp(1, 16);
+            ((1 << bits) - 1) << (16 - bits)
+        }
+        PixelFormat::Mono8 | PixelFormat::Bgr24 => u8::MAX as usize,
+    }
+}
+
+fn channel_stats(channel: Channel, counts: &[u64], saturation: usize, bins: usize) -> ChannelStats {
+    let pixels: u64 = counts.iter().sum();
+    let (mut sum, mut squares) = (0.0, 0.0);
+    for (v, n) in counts.iter().enumerate().filter(|(_, n)| **n > 0) {
+        let (v, n) = (v as f64, *n as f64);
+        sum += v * n;
+        squares += v * v * n;
+    }
+    let mean = if pixels > 0 { sum / pixels as f64 } else { 0.0 };
+    let variance = if pixels > 0 {
+        (squares / pixels as f64 - mean * mean).max(0.0)
+    } else {
+        0.0
+    };
+
+    let mut histogram = vec![0; bins];
+    for (v, n) in counts.iter().enumerate() {
+        histogram[v * bins / counts.len()] += n;
+    }
+
+    ChannelStats {
+        channel,
+        min: counts.iter().position(|n| *n > 0).unwrap_or(0) as u16,
+        max: counts.iter().rposition(|n| *n > 0).unwrap_or(0) as u16,
+        mean,
+        median: median(counts, pixels),
+        std_dev: variance.sqrt(),
+        saturated: counts[saturation.min(counts.len() - 1)..].iter().sum(),
+        histogram,
+    }
+}
+
+/// Middle value of the `pixels` counted in `counts`, the mean of the two
+/// middle ones for an even count
+fn median(counts: &[u64], pixels: u64) -> f64 {
+    if pixels == 0 {
+        return 0.0;
+    }
+
+    // Value of the `rank`th pixel, from 0
+    let nth = |rank: u64| {
+        let mut seen = 0;
+        counts
+            .iter()
+            .position(|n| {
+                seen += n;
+                seen > rank
+            })
+            .unwrap_or(0) as f64
+    };
+    if pixels % 2 == 1 {
+        nth(pixels / 2)
+    } else {
+        (nth(pixels / 2 - 1) + nth(pixels / 2)) / 2.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::BayerPattern;
+
+    fn mono16(pixels: &[u16]) -> Vec<u8> {
+        pixels.iter().flat_map(|p| p.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn measures_mono_frame() {
+        let data = mono16(&[100, 200, 300, 400, 65520, 65535]);
+        let image = Image {
+            width: 3,
+            height: 2,
+            format: PixelFormat::Mono16,
+            bayer: None,
+            data: &data,
+        };
+
+        let stats = compute(&image, 12, 256).unwrap();
+        assert_eq!(stats.len(), 1);
+        let gray = &stats[0];
+        assert_eq!(gray.channel, Channel::Gray);
+        assert_eq!((gray.min, gray.max), (100, 65535));
+        assert_eq!(gray.median, 350.0);
+        let mean = (100 + 200 + 300 + 400 + 65520 + 65535) as f64 / 6.0;
+        assert!((gray.mean - mean).abs() < 1e-9);
+        // 12 bit samples are shifted to the top of the 16 bits
+        assert_eq!(gray.saturated, 2);
+        assert_eq!(gray.histogram.len(), 256);
+        assert_eq!(gray.histogram[0], 2);
+        assert_eq!(gray.histogram[1], 2);
+        assert_eq!(gray.histogram[255], 2);
+        assert_eq!(gray.histogram.iter().sum::<u64>(), 6);
+    }
+
+    #[test]
+    fn computes_standard_deviation() {
+        let data = [2, 4, 4, 4, 5, 5, 7, 9];
+        let image = Image {
+            width: 4,
+            height: 2,
+            format: PixelFormat::Mono8,
+            bayer: None,
+            data: &data,
+        };
+
+        let gray = &compute(&image, 8, 256).unwrap()[0];
+        assert_eq!(gray.mean, 5.0);
+        assert_eq!(gray.std_dev, 2.0);
+        assert_eq!(gray.median, 4.5);
+        assert_eq!(gray.saturated, 0);
+    }
+
+    #[test]
+    fn splits_mosaic_channels() {
+        // RGGB: red 10, greens 20 and 30, blue 40
+        let data = [10, 20, 10, 20, 30, 40, 30, 40];
+        let image = Image {
+            width: 4,
+            height: 2,
+            format: PixelFormat::Mono8,
+            bayer: Some(BayerPattern::Rggb),
+            data: &data,
+        };
+
+        let stats = compute(&image, 8, 256).unwrap();
+        let channels: Vec<_> = stats.iter().map(|s| (s.channel, s.mean)).collect();
+        assert_eq!(
+            channels,
+            [
+                (Channel::Red, 10.0),
+                (Channel::Green, 25.0),
+                (Channel::Blue, 40.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_bgr_channels() {
+        let data = [1, 2, 3, 255, 2, 3];
+        let image = Image {
+            width: 2,
+            height: 1,
+            format: PixelFormat::Bgr24,
+            bayer: None,
+            data: &data,
+        };
+
+        let stats = compute(&image, 8, 256).unwrap();
+        assert_eq!(stats[0].channel, Channel::Red);
+        assert_eq!(stats[0].mean, 3.0);
+        assert_eq!(stats[2].channel, Channel::Blue);
+        assert_eq!(
+            (stats[2].min, stats[2].max, stats[2].saturated),
+            (1, 255, 1)
+        );
+    }
+
+    #[test]
+    fn saturation_follows_sensor_depth() {
+        assert_eq!(saturation(PixelFormat::Mono16, 16), 65535);
+        assert_eq!(saturation(PixelFormat::Mono16, 12), 65520);
+        assert_eq!(saturation(PixelFormat::Mono8, 12), 255);
+    }
+}
@@ -0,0 +1,348 @@
+//! Automatic stretches making linear frames visible on a screen.
+//!
+//! Astronomical frames are linear: the sky background and most of the objects
+//! sit in the darkest few percents of the range and look black. The stretch
+//! follows the screen transfer function of PixInsight: the shadows are clipped
+//! a few times the noise below the median, measured with the median absolute
+//! deviation, and a midtones transfer function brings the background to a
+//! given brightness. Frames whose median is in the upper half, e.g. negatives,
+//! get their highlights clipped instead.
+//!
+//! The curve may instead be an inverse hyperbolic sine, which keeps more
+//! contrast in the bright parts, stars and galaxy cores, for the same
+//! background. The channels of colour frames are either stretched together,
+//! keeping the colour balance of the frame, or each on its own, which
+//! neutralizes the background of the raw frames of colour cameras.
+
+use serde::Deserialize;
+
+/// Ratio between the standard deviation and the median absolute deviation of
+/// a normal distribution
+const SIGMA_PER_MAD: f64 = 1.4826;
+
+/// Bounds of the asinh stretch factor searched for
+const MIN_ASINH_FACTOR: f64 = 1e-3;
+const MAX_ASINH_FACTOR: f64 = 1e6;
+
+/// Shape of the stretch curve
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StretchMethod {
+    /// Midtones transfer function, as the screen transfer function of
+    /// PixInsight
+    #[default]
+    Mtf,
+    Asinh,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stretch {
+    pub method: StretchMethod,
+    /// Whether all the channels are stretched the same way
+    pub linked: bool,
+    /// Where the shadows are clipped, in noise standard deviations from the
+    /// median, negative below it
+    pub shadows_clip: f64,
+    /// Brightness of the background once stretched, from 0 to 1
+    pub target_background: f64,
+}
+
+impl Default for Stretch {
+    fn default() -> Self {
+        Self {
+            method: StretchMethod::Mtf,
+            linked: true,
+            shadows_clip: -2.8,
+            target_background: 0.25,
+        }
+    }
+}
+
+/// Stretch of a channel, the values are normalized from 0 to 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Levels {
+    /// Values at or below it become black
+    pub shadows: f64,
+    /// Values at or above it become white
+    pub highlights: f64,
+    /// Level of the background between the shadows and the highlights, it
+    /// becomes the target background
+    pub background: f64,
+}
+
+impl Levels {
+    /// Levels of a channel from the median and the median absolute deviation
+    /// of its normalized values
+    fn new(median: f64, mad: f64, shadows_clip: f64) -> Self {
+        let noise = mad * SIGMA_PER_MAD;
+        let (shadows, highlights) = if median <= 0.5 {
+            ((median + shadows_clip * noise).clamp(0.0, 1.0), 1.0)
+        } else {
+            (0.0, (median - shadows_clip * noise).clamp(0.0, 1.0))
+        };
+        let range = (highlights - shadows).max(f64::EPSILON);
+
+        Self {
+            shadows,
+            highlights,
+            background: ((median - shadows) / range).clamp(0.0, 1.0),
+        }
+    }
+
+    /// Average of the levels of every channel
+    fn linked(levels: &[Levels]) -> Self {
+        let n = levels.len().max(1) as f64;
+        let mean = |value: fn(&Levels) -> f64| levels.iter().map(value).sum::<f64>() / n;
+
+        Self {
+            shadows: mean(|l| l.shadows),
+            highlights: mean(|l| l.highlights),
+            background: mean(|l| l.background),
+        }
+    }
+}
+
+impl Stretch {
+    /// Levels of every channel of the interleaved `samples`, valued from 0 to
+    /// `max`, the same for all of them when linked
+    pub fn levels(&self, samples: &[f64], channels: usize, max: f64) -> Vec<Levels> {
+        let channels = channels.max(1);
+        let levels: Vec<Levels> = (0..channels)
+            .map(|c| {
+                let mut values: Vec<f64> = samples
+                    .iter()
+                    .skip(c)
+                    .step_by(channels)
+                    .map(|v| v / max)
+                    .collect();
+                let median = median(&mut values);
+                values.iter_mut().for_each(|v| *v = (*v - median).abs());
+                Levels::new(median, self::median(&mut values), self.shadows_clip)
+            })
+            .collect();
+
+        if self.linked {
+            vec![Levels::linked(&levels); channels]
+        } else {
+            levels
+        }
+    }
+
+    /// Stretch the interleaved `samples`, valued from 0 to `max`, to 8 bits
+    pub fn apply(&self, samples: &[f64], channels: usize, max: f64) -> Vec<u8> {
+        let curves: Vec<Curve> = self
+            .levels(samples, channels, max)
+            .into_iter()
+            .map(|levels| Curve::new(self.method, levels, self.target_background))
+            .collect();
+
+        samples
+            .iter()
+            .zip(curves.iter().cycle())
+            .map(|(v, curve)| (curve.value(v / max) * 255.0).round() as u8)
+            .collect()
+    }
+}
+
+/// Curve bringing the background of a channel to the target
+struct Curve {
+    method: StretchMethod,
+    levels: Levels,
+    /// Midtones balance of the MTF, or stretch factor of the asinh
+    parameter: f64,
+}
+
+impl Curve {
+    fn new(method: StretchMethod, levels: Levels, target: f64) -> Self {
+        let background = levels.background;
+        // Only the frames with their highlights clipped have a background in
+        // the upper half, it stays as bright as the target is dark
+        let target = if background > 0.5 {
+            1.0 - target
+        } else {
+            target
+        };
+        let parameter = match method {
+            // The MTF is its own inverse along the balance, a black or white
+            // background can't be moved and is left linear
+            StretchMethod::Mtf if background > 0.0 && background < 1.0 => mtf(target, background),
+            StretchMethod::Mtf => 0.5,
+            StretchMethod::Asinh => asinh_factor(background, target),
+        };
+
+        Self {
+            method,
+            levels,
+            parameter,
+        }
+    }
+
+    /// Stretched value, from 0 to 1, of the normalized value `x`
+    fn value(&self, x: f64) -> f64 {
+        let Levels {
+            shadows,
+            highlights,
+            ..
+        } = self.levels;
+        let x = ((x - shadows) / (highlights - shadows).max(f64::EPSILON)).clamp(0.0, 1.0);
+        match self.method {
+            StretchMethod::Mtf => mtf(self.parameter, x),
+            StretchMethod::Asinh => (self.parameter * x).asinh() / self.parameter.asinh(),
+        }
+    }
+}
+
+/// Midtones transfer function of balance `m`: 0, `m` and 1 become 0, 0.5 and
+/// 1
+fn mtf(m: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        0.0
+    } else if x >= 1.0 {
+        1.0
+    } else if x == m {
+        0.5
+    } else {
+        (m - 1.0) * x / ((2.0 * m - 1.0) * x - m)
+    }
+}
+
+/// Factor of the asinh curve bringing `background` to `target`, found by
+/// bisection as the curve brightens with the factor
+fn asinh_factor(background: f64, target: f64) -> f64 {
+    let curve = |factor: f64| (factor * background).asinh() / factor.asinh();
+    if background <= 0.0 || curve(MIN_ASINH_FACTOR) >= target {
+        return MIN_ASINH_FACTOR;
+    }
+    if curve(MAX_ASINH_FACTOR) <= target {
+        return MAX_ASINH_FACTOR;
+    }
+
+    // Bisect on the logarithm, the factor spans several orders of magnitude
+    let (mut low, mut high) = (MIN_ASINH_FACTOR.ln(), MAX_ASINH_FACTOR.ln());
+    for _ in 0..60 {
+        let mid = (low + high) / 2.0;
+        if curve(mid.exp()) < target {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    ((low + high) / 2.0).exp()
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mid = values.len() / 2;
+    *values.select_nth_unstable_by(mid, f64::total_cmp).1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn mtf_maps_balance_to_half() {
+        for m in [0.01, 0.25, 0.5, 0.9] {
+            assert_eq!(mtf(m, 0.0), 0.0);
+            assert_eq!(mtf(m, 1.0), 1.0);
+            assert!(close(mtf(m, m), 0.5));
+        }
+        // A balance of one half is the identity
+        assert!(close(mtf(0.5, 0.3), 0.3));
+    }
+
+    #[test]
+    fn mtf_is_its_own_inverse() {
+        let (background, target) = (0.02, 0.25);
+        assert!(close(mtf(mtf(target, background), background), target));
+    }
+
+    #[test]
+    fn levels_clip_shadows_below_median() {
+        let levels = Levels::new(0.1, 0.01, -2.8);
+        assert!(close(levels.shadows, 0.1 - 2.8 * 0.01 * SIGMA_PER_MAD));
+        assert_eq!(levels.highlights, 1.0);
+        assert!(close(
+            levels.background,
+            (0.1 - levels.shadows) / (1.0 - levels.shadows)
+        ));
+
+        // Negatives get their highlights clipped instead
+        let levels = Levels::new(0.9, 0.01, -2.8);
+        assert_eq!(levels.shadows, 0.0);
+        assert!(close(levels.highlights, 0.9 + 2.8 * 0.01 * SIGMA_PER_MAD));
+    }
+
+    /// Median of the stretched 8 bit values
+    fn stretched_median(stretch: &Stretch, samples: &[f64], channels: usize) -> Vec<u8> {
+        let out = stretch.apply(samples, channels, 65535.0);
+        (0..channels)
+            .map(|c| {
+                let mut values: Vec<u8> = out.iter().skip(c).step_by(channels).copied().collect();
+                values.sort();
+                values[values.len() / 2]
+            })
+            .collect()
+    }
+
+    /// A dark background with some noise, and a few bright pixels
+    fn frame() -> Vec<f64> {
+        (0..1000)
+            .map(|i| match i % 100 {
+                0 => 60000.0,
+                _ => 1000.0 + (i * 37 % 41) as f64,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn brings_background_to_target() {
+        for method in [StretchMethod::Mtf, StretchMethod::Asinh] {
+            let stretch = Stretch {
+                method,
+                ..Stretch::default()
+            };
+            let median = stretched_median(&stretch, &frame(), 1)[0];
+            assert!(
+                (median as f64 - 0.25 * 255.0).abs() <= 1.0,
+                "{:?}: {}",
+                method,
+                median
+            );
+        }
+    }
+
+    #[test]
+    fn unlinked_channels_neutralize_background() {
+        // Red background twice as bright as the green and blue ones
+        let samples: Vec<f64> = frame().into_iter().flat_map(|v| [v * 2.0, v, v]).collect();
+
+        let unlinked = Stretch {
+            linked: false,
+            ..Stretch::default()
+        };
+        let medians = stretched_median(&unlinked, &samples, 3);
+        assert!(
+            medians.iter().all(|m| (*m as f64 - 63.75).abs() <= 1.0),
+            "{:?}",
+            medians
+        );
+
+        let medians = stretched_median(&Stretch::default(), &samples, 3);
+        assert!(medians[0] > medians[1] + 10, "{:?}", medians);
+    }
+
+    #[test]
+    fn handles_flat_frames() {
+        let out = Stretch::default().apply(&[0.0; 16], 1, 65535.0);
+        assert!(out.iter().all(|v| *v == 0));
+        let out = Stretch::default().apply(&[65535.0; 16], 1, 65535.0);
+        assert!(out.iter().all(|v| *v == 255));
+    }
+}
@@ -0,0 +1,218 @@
+//! MQTT topics used by the daemons.
+//!
+//! Every device lives under `<prefix>/devices/<id>`, where the state is
+//! published, and receives commands on `<prefix>/devices/<id>/<action>`.
+//! The prefix is optional and lets several rigs share the same broker.
+//!
+//! Devices are announced on `<prefix>/discovery/<id>`, see `discovery`.
+
+use log::warn;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq)]
+pub enum TopicError {
+    /// The topic doesn't live under the configured prefix
+    OutsidePrefix,
+    /// The topic is not in the form `devices/<id>/<action>`
+    Malformed,
+    /// The action is not known by the handler
+    UnknownAction(String),
+}
+
+impl fmt::Display for TopicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopicError::OutsidePrefix => write!(f, "topic outside of the configured prefix"),
+            TopicError::Malformed => write!(f, "expected `devices/<id>/<action>`"),
+            TopicError::UnknownAction(a) => write!(f, "unknown action `{}`", a),
+        }
+    }
+}
+
+impl std::error::Error for TopicError {}
+
+/// A command topic split in its parts
+#[derive(Debug, PartialEq)]
+pub struct DeviceTopic<'a> {
+    pub device_id: &'a str,
+    pub action: &'a str,
+}
+
+/// Receives the commands routed by a `TopicRouter`
+pub trait Handler {
+    /// The actions this handler understands, topics with an action that
+    /// can't be parsed are ignored.
+    type Action: FromStr;
+
+    fn handle(&mut self, device_id: &str, action: Self::Action, payload: &[u8]);
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TopicRouter {
+    prefix: String,
+}
+
+impl TopicRouter {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+        }
+    }
+
+    /// Prepend the prefix to `path`
+    pub fn topic(&self, path: &str) -> String {
+        if self.prefix.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}/{}", self.prefix, path)
+        }
+    }
+
+    /// Topic where the state of the device is published
+    pub fn device(&self, id: impl fmt::Display) -> String {
+        self.topic(&format!("devices/{}", id))
+    }
+
+    /// Topic of `action` for the device
+    pub fn device_action(&self, id: impl fmt::Display, action: &str) -> String {
+        self.topic(&format!("devices/{}/{}", id, action))
+    }
+
+    /// Topic where the announcement of the device is published
+    pub fn discovery(&self, id: impl fmt::Display) -> String {
+        self.topic(&format!("discovery/{}", id))
+    }
+
+    /// Split a command topic in device id and action
+    pub fn parse<'a>(&self, topic: &'a str) -> Result<DeviceTopic<'a>, TopicError> {
+        let path = if self.prefix.is_empty() {
+            topic
+        } else {
+            topic
+                .strip_prefix(self.prefix.as_str())
+                .and_then(|t| t.strip_prefix('/'))
+                .ok_or(TopicError::OutsidePrefix)?
+        };
+
+        let mut levels = path.split('/');
+        match (levels.next(), levels.next(), levels.next(), levels.next()) {
+            (Some("devices"), Some(device_id), Some(action), None)
+                if !device_id.is_empty() && !action.is_empty() =>
+            {
+                Ok(DeviceTopic { device_id, action })
+            }
+            _ => Err(TopicError::Malformed),
+        }
+    }
+
+    /// Parse `topic` and hand the command to `handler`. Topics that can't be
+    /// routed are logged and ignored, returns whether the command was handled.
+    pub fn dispatch<H: Handler>(&self, handler: &mut H, topic: &str, payload: &[u8]) -> bool {
+        let parsed = self.parse(topic).and_then(|t| {
+            t.action
+                .parse::<H::Action>()
+                .map(|action| (t.device_id, action))
+                .map_err(|_| TopicError::UnknownAction(t.action.to_string()))
+        });
+
+        match parsed {
+            Ok((device_id, action)) => {
+                handler.handle(device_id, action, payload);
+                true
+            }
+            Err(e) => {
+                warn!("Ignoring message on `{}`: {}", topic, e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Action {
+        Expose,
+    }
+
+    impl FromStr for Action {
+        type Err = ();
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "expose" => Ok(Action::Expose),
+                _ => Err(()),
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorder(Vec<(String, Action, Vec<u8>)>);
+
+    impl Handler for Recorder {
+        type Action = Action;
+
+        fn handle(&mut self, device_id: &str, action: Action, payload: &[u8]) {
+            self.0
+                .push((device_id.to_string(), action, payload.to_vec()));
+        }
+    }
+
+    #[test]
+    fn builds_topics_under_prefix() {
+        let router = TopicRouter::new("observatory/east");
+        assert_eq!(router.device(42), "observatory/east/devices/42");
+        assert_eq!(
+            router.device_action(42, "expose"),
+            "observatory/east/devices/42/expose"
+        );
+        assert_eq!(router.discovery(42), "observatory/east/discovery/42");
+        assert_eq!(TopicRouter::default().topic("discover"), "discover");
+    }
+
+    #[test]
+    fn parses_command_topics() {
+        let router = TopicRouter::new("obs");
+        assert_eq!(
+            router.parse("obs/devices/42/expose"),
+            Ok(DeviceTopic {
+                device_id: "42",
+                action: "expose"
+            })
+        );
+        assert_eq!(
+            router.parse("other/devices/42/expose"),
+            Err(TopicError::OutsidePrefix)
+        );
+        assert_eq!(
+            router.parse("observatory/devices/42/expose"),
+            Err(TopicError::OutsidePrefix)
+        );
+        for topic in [
+            "obs/devices/42",
+            "obs/devices//expose",
+            "obs/devices/42/",
+            "obs/devices/42/frame/1",
+            "obs/discovery/42/expose",
+        ] {
+            assert_eq!(router.parse(topic), Err(TopicError::Malformed), "{}", topic);
+        }
+    }
+
+    #[test]
+    fn dispatches_known_actions() {
+        let router = TopicRouter::default();
+        let mut handler = Recorder::default();
+
+        assert!(router.dispatch(&mut handler, "devices/42/expose", b"{}"));
+        assert!(!router.dispatch(&mut handler, "devices/42/explode", b"{}"));
+        assert!(!router.dispatch(&mut handler, "devices/42", b"{}"));
+        assert_eq!(
+            handler.0,
+            vec![("42".to_string(), Action::Expose, b"{}".to_vec())]
+        );
+    }
+}
@@ -0,0 +1,779 @@
+//! Binary transfer of the frames over MQTT.
+//!
+//! A frame is too big to be sent as a single message, it is split in chunks
+//! published under `<prefix>/devices/<id>/frame/<transfer_id>/`:
+//!
+//! - `header`: JSON `FrameHeader`, with the size, the number of chunks, the
+//!   CRC32 of the whole frame and the metadata of the frame
+//! - `0`, `1`, ...: the raw bytes of each chunk, all of them `chunk_size` long
+//!   except the last one
+//! - `done`: JSON `Completion`, published once every chunk has been sent
+//!
+//! Every message is QoS 1 and the transfer waits for the broker to
+//! acknowledge it: the `PubAck`s read by the event loop go through an
+//! `AckTracker`, the messages still unacknowledged after the timeout are
+//! published again, and the frame is reported as failed if some are missing
+//! after the last retry. The last frames are kept in memory, a client
+//! that missed some chunks can ask for them on
+//! `<prefix>/devices/<id>/resend` with a `ResendRequest`.
+//! `Assembler` puts the chunks back together and verifies the frame.
+
+use crate::reply::{ErrorCode, ReplyError};
+use crate::topics::TopicRouter;
+use log::{debug, error, warn};
+use rumqttc::{AsyncClient, QoS, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::{timeout_at, Instant};
+use uuid::Uuid;
+
+/// Topic action under which the frames are published
+pub const FRAME_ACTION: &str = "frame";
+
+/// Topic action used by the clients to ask for missing chunks
+pub const RESEND_ACTION: &str = "resend";
+
+/// Room left in an MQTT packet for the topic and the headers of a chunk
+const PACKET_OVERHEAD: usize = 1024;
+
+/// Requests the relay client can hold before publishing waits, as for the
+/// client of the connection
+const RELAY_CAPACITY: usize = 10;
+
+#[derive(Debug, PartialEq)]
+pub enum TransferError {
+    /// The chunk index is not part of the transfer
+    OutOfRange(u32),
+    /// The chunk doesn't have the size announced by the header
+    ChunkSize {
+        index: u32,
+        expected: usize,
+        actual: usize,
+    },
+    /// Some chunks were not received
+    Missing(Vec<u32>),
+    /// The reassembled frame doesn't match the checksum of the header
+    Checksum { expected: u32, actual: u32 },
+    /// The message on this topic couldn't be published in time
+    Timeout(String),
+    /// This many messages were never acknowledged by the broker
+    Unacknowledged(usize),
+    /// The frame couldn't be published
+    Mqtt(String),
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::OutOfRange(i) => write!(f, "chunk {} out of range", i),
+            TransferError::ChunkSize {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "chunk {} is {} bytes long, expected {}",
+                index, actual, expected
+            ),
+            TransferError::Missing(chunks) => write!(f, "{} chunks missing", chunks.len()),
+            TransferError::Checksum { expected, actual } => write!(
+                f,
+                "checksum mismatch: expected {:08x}, got {:08x}",
+                expected, actual
+            ),
+            TransferError::Timeout(topic) => write!(f, "timeout while publishing {}", topic),
+            TransferError::Unacknowledged(count) => {
+                write!(f, "{} messages not acknowledged by the broker", count)
+            }
+            TransferError::Mqtt(e) => write!(f, "unable to publish the frame: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+/// Published on `frame/<transfer_id>/header` before any chunk
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrameHeader {
+    pub transfer_id: Uuid,
+    /// The `request_id` of the exposure that produced the frame
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Size of the frame in bytes
+    pub size: usize,
+    pub chunk_size: usize,
+    pub chunks: u32,
+    pub crc32: u32,
+    /// Describe the frame, e.g. its dimensions and pixel format
+    #[serde(flatten)]
+    pub metadata: Map<String, Value>,
+}
+
+impl FrameHeader {
+    /// Expected size of the chunk `index`
+    pub fn chunk_len(&self, index: u32) -> Option<usize> {
+        if index >= self.chunks {
+            return None;
+        }
+
+        let start = index as usize * self.chunk_size;
+        Some(self.chunk_size.min(self.size - start))
+    }
+}
+
+/// Published on `frame/<transfer_id>/done` after the last chunk
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Completion {
+    pub transfer_id: Uuid,
+    pub chunks: u32,
+    pub crc32: u32,
+}
+
+/// Payload of `devices/{id}/resend`, an empty `chunks` means the whole frame
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResendRequest {
+    pub request_id: Option<String>,
+    pub transfer_id: Uuid,
+    #[serde(default)]
+    pub chunks: Vec<u32>,
+}
+
+impl ResendRequest {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ReplyError> {
+        serde_json::from_slice(payload)
+            .map_err(|e| ReplyError::new(ErrorCode::InvalidPayload, e.to_string()))
+    }
+}
+
+/// A frame ready to be sent
+#[derive(Debug)]
+pub struct Frame {
+    pub header: FrameHeader,
+    data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(
+        data: Vec<u8>,
+        chunk_size: usize,
+        request_id: Option<String>,
+        metadata: Map<String, Value>,
+    ) -> Self {
+        let header = FrameHeader {
+            transfer_id: Uuid::new_v4(),
+            request_id,
+            size: data.len(),
+            chunk_size,
+            chunks: data.len().div_ceil(chunk_size) as u32,
+            crc32: crc32fast::hash(&data),
+            metadata,
+        };
+
+        Self { header, data }
+    }
+
+    pub fn chunk(&self, index: u32) -> Option<&[u8]> {
+        let len = self.header.chunk_len(index)?;
+        let start = index as usize * self.header.chunk_size;
+        Some(&self.data[start..start + len])
+    }
+
+    pub fn completion(&self) -> Completion {
+        Completion {
+            transfer_id: self.header.transfer_id,
+            chunks: self.header.chunks,
+            crc32: self.header.crc32,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Topic of `part` (`header`, `done` or a chunk index) of a transfer
+pub fn topic(
+    router: &TopicRouter,
+    device_id: impl fmt::Display,
+    transfer_id: Uuid,
+    part: impl fmt::Display,
+) -> String {
+    router.device_action(
+        device_id,
+        &format!("{}/{}/{}", FRAME_ACTION, transfer_id, part),
+    )
+}
+
+/// Largest MQTT packet needed to send chunks of `chunk_size` bytes
+pub fn packet_size(chunk_size: usize) -> usize {
+    chunk_size + PACKET_OVERHEAD
+}
+
+/// How long the broker may take to acknowledge the messages of a frame, and
+/// how many times the messages it didn't acknowledge are published again
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+/// Match the `PubAck`s read by the event loop with the messages of the
+/// transfers. It can be cloned and shared between threads.
+///
+/// rumqttc only tells the packet id of a message once it is written on the
+/// connection, as an `Outgoing::Publish` event. The messages published with
+/// the client returned by `relay` are queued in order before reaching the
+/// event loop, and get their packet id from the next `outgoing` call.
+#[derive(Debug, Clone, Default)]
+pub struct AckTracker {
+    acks: Arc<Mutex<Acks>>,
+}
+
+#[derive(Debug, Default)]
+struct Acks {
+    /// Waiters of the transfers, by topic, until their message is relayed
+    expected: HashMap<String, oneshot::Sender<()>>,
+    /// QoS 1 messages relayed to the event loop, still without a packet id
+    queued: VecDeque<Option<oneshot::Sender<()>>>,
+    /// Messages written on the connection, by packet id
+    inflight: HashMap<u16, Option<oneshot::Sender<()>>>,
+}
+
+impl AckTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A client whose QoS 1 messages are tracked, everything published with
+    /// it is forwarded to `client` in order. Must be called from within the
+    /// tokio runtime.
+    pub fn relay(&self, client: AsyncClient) -> AsyncClient {
+        let (tx, rx) = flume::bounded(RELAY_CAPACITY);
+        tokio::spawn(forward(rx, client, self.clone()));
+        AsyncClient::from_senders(tx)
+    }
+
+    /// To be called on every `Outgoing::Publish` event of the connection
+    pub fn outgoing(&self, pkid: u16) {
+        let Ok(mut acks) = self.acks.lock() else {
+            return;
+        };
+
+        // QoS 0 messages have no packet id, the messages sent again after a
+        // reconnection keep theirs
+        if pkid == 0 || acks.inflight.contains_key(&pkid) {
+            return;
+        }
+        match acks.queued.pop_front() {
+            Some(waiter) => {
+                acks.inflight.insert(pkid, waiter);
+            }
+            None => warn!("Packet {} was not published through the relay", pkid),
+        }
+    }
+
+    /// To be called on every `PubAck` received by the connection
+    pub fn acked(&self, pkid: u16) {
+        let waiter = match self.acks.lock() {
+            Ok(mut acks) => acks.inflight.remove(&pkid).flatten(),
+            Err(_) => None,
+        };
+        if let Some(waiter) = waiter {
+            let _ = waiter.send(());
+        }
+    }
+
+    /// Wait for the acknowledgment of the next message published on `topic`
+    fn expect(&self, topic: &str) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        if let Ok(mut acks) = self.acks.lock() {
+            acks.expected.insert(topic.to_string(), tx);
+        }
+        rx
+    }
+
+    /// Drop the waiter of a message that was never published
+    fn forget(&self, topic: &str) {
+        if let Ok(mut acks) = self.acks.lock() {
+            acks.expected.remove(topic);
+        }
+    }
+
+    fn relayed(&self, topic: &str) {
+        if let Ok(mut acks) = self.acks.lock() {
+            let waiter = acks.expected.remove(topic);
+            acks.queued.push_back(waiter);
+        }
+    }
+}
+
+/// Forward the requests of the relay client to the connection
+async fn forward(requests: flume::Receiver<Request>, client: AsyncClient, acks: AckTracker) {
+    while let Ok(request) = requests.recv_async().await {
+        let forwarded = match request {
+            Request::Publish(publish) => {
+                if publish.qos != QoS::AtMostOnce {
+                    acks.relayed(&publish.topic);
+                }
+                client
+                    .publish_bytes(publish.topic, publish.qos, publish.retain, publish.payload)
+                    .await
+            }
+            Request::Subscribe(subscribe) => client.subscribe_many(subscribe.filters).await,
+            Request::Unsubscribe(unsubscribe) => {
+                let mut result = Ok(());
+                for topic in unsubscribe.topics {
+                    result = result.and(client.unsubscribe(topic).await);
+                }
+                result
+            }
+            Request::Disconnect(_) => client.disconnect().await,
+            request => {
+                debug!("Request not relayed: {:?}", request);
+                Ok(())
+            }
+        };
+
+        if let Err(e) = forwarded {
+            error!("Unable to relay an MQTT request: {}", e);
+        }
+    }
+}
+
+/// Publish the whole `frame`: header, chunks and completion. Every message
+/// is QoS 1, each part is acknowledged by the broker before the next one is
+/// published.
+pub async fn send(
+    client: &AsyncClient,
+    acks: &AckTracker,
+    router: &TopicRouter,
+    device_id: Uuid,
+    frame: &Frame,
+    retry: RetryPolicy,
+) -> Result<(), TransferError> {
+    let id = frame.header.transfer_id;
+
+    let header =
+        serde_json::to_vec(&frame.header).map_err(|e| TransferError::Mqtt(e.to_string()))?;
+    let parts = vec![(topic(router, device_id, id, "header"), header.as_slice())];
+    deliver(client, acks, parts, retry).await?;
+
+    let chunks: Vec<u32> = (0..frame.header.chunks).collect();
+    send_chunks(client, acks, router, device_id, frame, &chunks, retry).await
+}
+
+/// Publish again the `chunks` of `frame`, all of them if `chunks` is empty,
+/// followed by the completion message
+pub async fn resend(
+    client: &AsyncClient,
+    acks: &AckTracker,
+    router: &TopicRouter,
+    device_id: Uuid,
+    frame: &Frame,
+    chunks: &[u32],
+    retry: RetryPolicy,
+) -> Result<(), TransferError> {
+    if chunks.is_empty() {
+        let all: Vec<u32> = (0..frame.header.chunks).collect();
+        return send_chunks(client, acks, router, device_id, frame, &all, retry).await;
+    }
+
+    send_chunks(client, acks, router, device_id, frame, chunks, retry).await
+}
+
+async fn send_chunks(
+    client: &AsyncClient,
+    acks: &AckTracker,
+    router: &TopicRouter,
+    device_id: Uuid,
+    frame: &Frame,
+    chunks: &[u32],
+    retry: RetryPolicy,
+) -> Result<(), TransferError> {
+    let id = frame.header.transfer_id;
+
+    let mut parts = Vec::with_capacity(chunks.len());
+    for &index in chunks {
+        let chunk = frame.chunk(index).ok_or(TransferError::OutOfRange(index))?;
+        parts.push((topic(router, device_id, id, index), chunk));
+    }
+    debug!("Publishing {} chunks of {}", parts.len(), id);
+    deliver(client, acks, parts, retry).await?;
+
+    let done =
+        serde_json::to_vec(&frame.completion()).map_err(|e| TransferError::Mqtt(e.to_string()))?;
+    let parts = vec![(topic(router, device_id, id, "done"), done.as_slice())];
+    deliver(client, acks, parts, retry).await
+}
+
+/// Publish `parts`, topic and payload, and wait for their `PubAck`. The
+/// messages not acknowledged within the timeout of `retry` are published
+/// again, the transfer fails if some are still missing after the last retry.
+async fn deliver(
+    client: &AsyncClient,
+    acks: &AckTracker,
+    mut parts: Vec<(String, &[u8])>,
+    retry: RetryPolicy,
+) -> Result<(), TransferError> {
+    for attempt in 0..=retry.retries {
+        if attempt > 0 {
+            warn!(
+                "{} messages not acknowledged, retry {}/{}",
+                parts.len(),
+                attempt,
+                retry.retries
+            );
+        }
+
+        let mut pending = Vec::with_capacity(parts.len());
+        for (topic, payload) in parts {
+            let acked = acks.expect(&topic);
+            if let Err(e) = publish(client, &topic, payload, retry).await {
+                acks.forget(&topic);
+                return Err(e);
+            }
+            pending.push((topic, payload, acked));
+        }
+
+        let deadline = Instant::now() + retry.timeout;
+        parts = Vec::new();
+        for (topic, payload, acked) in pending {
+            if !matches!(timeout_at(deadline, acked).await, Ok(Ok(()))) {
+                parts.push((topic, payload));
+            }
+        }
+        if parts.is_empty() {
+            return Ok(());
+        }
+    }
+
+    Err(TransferError::Unacknowledged(parts.len()))
+}
+
+/// Hand a message to the connection. A message that can't be queued in time,
+/// because the connection is stalled, is retried; the connection being gone
+/// is reported straight away.
+async fn publish(
+    client: &AsyncClient,
+    topic: &str,
+    payload: &[u8],
+    retry: RetryPolicy,
+) -> Result<(), TransferError> {
+    let mut attempt = 0;
+    loop {
+        let publish = client.publish(topic, QoS::AtLeastOnce, false, payload.to_vec());
+        match tokio::time::timeout(retry.timeout, publish).await {
+            Ok(result) => return result.map_err(|e| TransferError::Mqtt(e.to_string())),
+            Err(_) if attempt < retry.retries => {
+                attempt += 1;
+                warn!(
+                    "Timeout publishing {}, retry {}/{}",
+                    topic, attempt, retry.retries
+                );
+            }
+            Err(_) => return Err(TransferError::Timeout(topic.to_string())),
+        }
+    }
+}
+
+/// The last frames sent by a device, kept to answer the resend requests. It
+/// can be cloned and shared between threads.
+#[derive(Debug, Clone)]
+pub struct FrameStore {
+    frames: Arc<Mutex<VecDeque<Arc<Frame>>>>,
+    capacity: usize,
+}
+
+impl FrameStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            frames: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Keep `frame`, dropping the oldest one if the store is full
+    pub fn insert(&self, frame: Arc<Frame>) {
+        let Ok(mut frames) = self.frames.lock() else {
+            error!(
+                "Frame store poisoned, {} not kept",
+                frame.header.transfer_id
+            );
+            return;
+        };
+
+        if self.capacity == 0 {
+            return;
+        }
+        while frames.len() >= self.capacity {
+            frames.pop_front();
+        }
+        frames.push_back(frame);
+    }
+
+    pub fn get(&self, transfer_id: Uuid) -> Option<Arc<Frame>> {
+        let frames = self.frames.lock().ok()?;
+        frames
+            .iter()
+            .find(|f| f.header.transfer_id == transfer_id)
+            .cloned()
+    }
+}
+
+/// Put a frame back together on the receiving side
+#[derive(Debug)]
+pub struct Assembler {
+    header: FrameHeader,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+impl Assembler {
+    pub fn new(header: FrameHeader) -> Self {
+        let chunks = vec![None; header.chunks as usize];
+        Self { header, chunks }
+    }
+
+    pub fn header(&self) -> &FrameHeader {
+        &self.header
+    }
+
+    /// Store a chunk, a chunk received twice replaces the previous one
+    pub fn insert(&mut self, index: u32, data: Vec<u8>) -> Result<(), TransferError> {
+        let expected = self
+            .header
+            .chunk_len(index)
+            .ok_or(TransferError::OutOfRange(index))?;
+        if data.len() != expected {
+            return Err(TransferError::ChunkSize {
+                index,
+                expected,
+                actual: data.len(),
+            });
+        }
+
+        self.chunks[index as usize] = Some(data);
+        Ok(())
+    }
+
+    /// Indexes of the chunks not received yet, to be sent in a `ResendRequest`
+    pub fn missing(&self) -> Vec<u32> {
+        self.chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.is_none())
+            .map(|(i, _)| i as u32)
+            .collect()
+    }
+
+    /// The whole frame, once every chunk has been received and the checksum
+    /// verified
+    pub fn finish(self) -> Result<Vec<u8>, TransferError> {
+        let missing = self.missing();
+        if !missing.is_empty() {
+            return Err(TransferError::Missing(missing));
+        }
+
+        let mut data = Vec::with_capacity(self.header.size);
+        for chunk in self.chunks.into_iter().flatten() {
+            data.extend_from_slice(&chunk);
+        }
+
+        let actual = crc32fast::hash(&data);
+        if actual != self.header.crc32 {
+            return Err(TransferError::Checksum {
+                expected: self.header.crc32,
+                actual,
+            });
+        }
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame() -> Frame {
+        let data: Vec<u8> = (0..2500u32).map(|i| (i * 7 % 251) as u8).collect();
+        Frame::new(data, 1000, Some("42".into()), Map::new())
+    }
+
+    #[test]
+    fn splits_frame_in_chunks() {
+        let frame = frame();
+        assert_eq!(frame.header.chunks, 3);
+        assert_eq!(frame.header.chunk_len(0), Some(1000));
+        assert_eq!(frame.header.chunk_len(2), Some(500));
+        assert_eq!(frame.header.chunk_len(3), None);
+        assert_eq!(frame.chunk(2).unwrap(), &frame.data()[2000..]);
+    }
+
+    #[test]
+    fn reassembles_chunks_out_of_order() {
+        let frame = frame();
+        let mut assembler = Assembler::new(frame.header.clone());
+        for index in [2, 0, 1] {
+            assembler
+                .insert(index, frame.chunk(index).unwrap().to_vec())
+                .unwrap();
+        }
+
+        assert_eq!(assembler.finish().unwrap(), frame.data());
+    }
+
+    #[test]
+    fn reports_missing_chunks() {
+        let frame = frame();
+        let mut assembler = Assembler::new(frame.header.clone());
+        assembler
+            .insert(1, frame.chunk(1).unwrap().to_vec())
+            .unwrap();
+
+        assert_eq!(assembler.missing(), vec![0, 2]);
+        assert_eq!(assembler.finish(), Err(TransferError::Missing(vec![0, 2])));
+    }
+
+    #[test]
+    fn detects_corrupted_chunk() {
+        let frame = frame();
+        let mut assembler = Assembler::new(frame.header.clone());
+        for index in 0..3 {
+            let mut chunk = frame.chunk(index).unwrap().to_vec();
+            if index == 1 {
+                chunk[10] ^= 0xFF;
+            }
+            assembler.insert(index, chunk).unwrap();
+        }
+
+        assert!(matches!(
+            assembler.finish(),
+            Err(TransferError::Checksum { expected, .. }) if expected == frame.header.crc32
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_chunks() {
+        let frame = frame();
+        let mut assembler = Assembler::new(frame.header.clone());
+
+        assert_eq!(
+            assembler.insert(3, vec![0; 500]),
+            Err(TransferError::OutOfRange(3))
+        );
+        assert_eq!(
+            assembler.insert(2, vec![0; 1000]),
+            Err(TransferError::ChunkSize {
+                index: 2,
+                expected: 500,
+                actual: 1000
+            })
+        );
+    }
+
+    #[test]
+    fn store_drops_oldest_frame() {
+        let store = FrameStore::new(2);
+        let frames: Vec<Arc<Frame>> = (0..3).map(|_| Arc::new(frame())).collect();
+        frames.iter().for_each(|f| store.insert(f.clone()));
+
+        assert!(store.get(frames[0].header.transfer_id).is_none());
+        assert!(store.get(frames[2].header.transfer_id).is_some());
+    }
+
+    /// Stand in for the event loop and the broker: every message published
+    /// through the relay gets a packet id and is acknowledged, unless `lost`
+    /// says the broker never received it. `lost` is given the topic and how
+    /// many times it was published before.
+    fn connection(
+        acks: &AckTracker,
+        lost: fn(&str, usize) -> bool,
+    ) -> (AsyncClient, Arc<Mutex<Vec<String>>>) {
+        let (tx, rx) = flume::bounded(RELAY_CAPACITY);
+        let client = acks.relay(AsyncClient::from_senders(tx));
+        let published = Arc::new(Mutex::new(Vec::new()));
+
+        let (acks, log) = (acks.clone(), published.clone());
+        tokio::spawn(async move {
+            let mut pkid = 0;
+            while let Ok(Request::Publish(publish)) = rx.recv_async().await {
+                pkid += 1;
+                acks.outgoing(pkid);
+                let mut log = log.lock().unwrap();
+                let before = log.iter().filter(|t| **t == publish.topic).count();
+                log.push(publish.topic.clone());
+                if !lost(&publish.topic, before) {
+                    acks.acked(pkid);
+                }
+            }
+        });
+
+        (client, published)
+    }
+
+    fn retry() -> RetryPolicy {
+        RetryPolicy {
+            timeout: Duration::from_millis(100),
+            retries: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn resends_unacknowledged_chunks() {
+        let (router, device) = (TopicRouter::new("obs"), Uuid::new_v4());
+        let frame = frame();
+        let acks = AckTracker::new();
+        let (client, published) =
+            connection(&acks, |topic, before| topic.ends_with("/1") && before == 0);
+
+        send(&client, &acks, &router, device, &frame, retry())
+            .await
+            .unwrap();
+
+        let id = frame.header.transfer_id;
+        let expected: Vec<String> = ["header", "0", "1", "2", "1", "done"]
+            .iter()
+            .map(|part| topic(&router, device, id, part))
+            .collect();
+        assert_eq!(*published.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn fails_when_chunks_are_never_acknowledged() {
+        let (router, device) = (TopicRouter::new("obs"), Uuid::new_v4());
+        let frame = frame();
+        let acks = AckTracker::new();
+        let (client, published) = connection(&acks, |topic, _| topic.ends_with("/1"));
+
+        let result = send(&client, &acks, &router, device, &frame, retry()).await;
+
+        assert_eq!(result, Err(TransferError::Unacknowledged(1)));
+        let published = published.lock().unwrap();
+        let chunk = topic(&router, device, frame.header.transfer_id, 1);
+        assert_eq!(published.iter().filter(|t| **t == chunk).count(), 3);
+        assert!(!published.iter().any(|t| t.ends_with("/done")));
+    }
+
+    #[test]
+    fn replayed_messages_keep_their_waiter() {
+        let acks = AckTracker::new();
+        let mut acked = acks.expect("frame/0");
+        acks.relayed("frame/0");
+        acks.relayed("other");
+
+        acks.outgoing(1);
+        // Sent again after a reconnection, with the same packet id
+        acks.outgoing(1);
+        acks.outgoing(2);
+        acks.acked(2);
+        assert!(acked.try_recv().is_err());
+        acks.acked(1);
+        assert_eq!(acked.try_recv(), Ok(()));
+    }
+}
@@ -0,0 +1,348 @@
+//! Minimal XISF 1.0 writer, for the users processing their frames in
+//! PixInsight.
+//!
+//! Files are monolithic: the XML header is followed by a single attached data
+//! block holding the image, optionally compressed. The keywords of the FITS
+//! header are embedded as `FITSKeyword` elements and the ones having an XISF
+//! equivalent are also written as properties, so that both describe the frame
+//! the same way.
+
+use crate::fits::{Header, HeaderValue, Image, PixelFormat};
+use flate2::write::ZlibEncoder;
+use flate2::Compression as ZlibLevel;
+use serde::Deserialize;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+const SIGNATURE: &[u8; 8] = b"XISF0100";
+
+/// The data block starts on a multiple of this, as PixInsight does
+const BLOCK_ALIGNMENT: usize = 4096;
+
+/// Compression of the data block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zlib,
+    Lz4,
+}
+
+/// XISF properties matching FITS keywords, with their type
+const PROPERTIES: [(&str, &str, &str); 18] = [
+    ("EXPTIME", "Instrument:ExposureTime", "Float32"),
+    ("INSTRUME", "Instrument:Camera:Name", "String"),
+    ("XBINNING", "Instrument:Camera:XBinning", "Int32"),
+    ("YBINNING", "Instrument:Camera:YBinning", "Int32"),
+    ("CCD-TEMP", "Instrument:Sensor:Temperature", "Float32"),
+    ("SET-TEMP", "Instrument:Sensor:TargetTemperature", "Float32"),
+    ("XPIXSZ", "Instrument:Sensor:XPixelSize", "Float32"),
+    ("YPIXSZ", "Instrument:Sensor:YPixelSize", "Float32"),
+    ("DATE-OBS", "Observation:Time:Start", "TimePoint"),
+    ("OBJECT", "Observation:Object:Name", "String"),
+    ("FILTER", "Instrument:Filter:Name", "String"),
+    ("TELESCOP", "Instrument:Telescope:Name", "String"),
+    ("OBSERVER", "Observer:Name", "String"),
+    ("SITELAT", "Observation:Location:Latitude", "Float64"),
+    ("SITELONG", "Observation:Location:Longitude", "Float64"),
+    ("SITEELEV", "Observation:Location:Elevation", "Float64"),
+    ("RA", "Observation:Center:RA", "Float64"),
+    ("DEC", "Observation:Center:Dec", "Float64"),
+];
+
+/// Write `image` with the keywords of `header` to `path`, creating the parent
+/// directories
+pub fn write(
+    path: &Path,
+    header: &Header,
+    image: &Image,
+    compression: Compression,
+) -> io::Result<()> {
+    image.check()?;
+    let (data, codec) = encode(image, compression)?;
+
+    // The position of the data block is written in the XML, whose length
+    // moves the data block: stop as soon as it doesn't move anymore
+    let mut position = BLOCK_ALIGNMENT;
+    let xml = loop {
+        let xml = xml_header(header, image, position, data.len(), codec.as_deref());
+        let needed = align(SIGNATURE.len() + 8 + xml.len());
+        if needed <= position {
+            break xml;
+        }
+        position = needed;
+    };
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut out = BufWriter::new(File::create(path)?);
+
+    out.write_all(SIGNATURE)?;
+    out.write_all(&(xml.len() as u32).to_le_bytes())?;
+    out.write_all(&[0; 4])?;
+    out.write_all(xml.as_bytes())?;
+    let written = SIGNATURE.len() + 8 + xml.len();
+    out.write_all(&vec![0; position - written])?;
+    out.write_all(&data)?;
+
+    out.flush()
+}
+
+fn align(n: usize) -> usize {
+    n.div_ceil(BLOCK_ALIGNMENT) * BLOCK_ALIGNMENT
+}
+
+/// The data block and the value of the `compression` attribute if any
+fn encode(image: &Image, compression: Compression) -> io::Result<(Vec<u8>, Option<String>)> {
+    let raw = image.planar();
+    let codec = match compression {
+        Compression::None => return Ok((raw, None)),
+        Compression::Zlib => "zlib",
+        Compression::Lz4 => "lz4",
+    };
+
+    // Shuffling groups the most significant bytes of the 16 bit samples
+    // together, which compresses much better
+    let size = raw.len();
+    let (input, attribute) = match image.format {
+        PixelFormat::Mono16 => (shuffle(&raw, 2), format!("{}+sh:{}:2", codec, size)),
+        PixelFormat::Mono8 | PixelFormat::Bgr24 => (raw, format!("{}:{}", codec, size)),
+    };
+
+    let data = if compression == Compression::Zlib {
+        let mut encoder = ZlibEncoder::new(Vec::new(), ZlibLevel::default());
+        encoder.write_all(&input)?;
+        encoder.finish()?
+    } else {
+        lz4_flex::block::compress(&input)
+    };
+    Ok((data, Some(attribute)))
+}
+
+fn shuffle(data: &[u8], item_size: usize) -> Vec<u8> {
+    (0..item_size)
+        .flat_map(|b| data.iter().skip(b).step_by(item_size).copied())
+        .collect()
+}
+
+fn xml_header(
+    header: &Header,
+    image: &Image,
+    position: usize,
+    size: usize,
+    compression: Option<&str>,
+) -> String {
+    let (channels, color_space) = match image.format {
+        PixelFormat::Bgr24 => (3, "RGB"),
+        PixelFormat::Mono8 | PixelFormat::Mono16 => (1, "Gray"),
+    };
+    let (sample_format, bounds) = match image.format {
+        PixelFormat::Mono16 => ("UInt16", "0:65535"),
+        PixelFormat::Mono8 | PixelFormat::Bgr24 => ("UInt8", "0:255"),
+    };
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(concat!(
+        "<xisf version=\"1.0\" xmlns=\"http://www.pixinsight.com/xisf\" ",
+        "xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" ",
+        "xsi:schemaLocation=\"http://www.pixinsight.com/xisf ",
+        "http://pixinsight.com/xisf/xisf-1.0.xsd\">\n"
+    ));
+
+    let _ = write!(
+        xml,
+        "<Image geometry=\"{}:{}:{}\" sampleFormat=\"{}\" bounds=\"{}\" colorSpace=\"{}\" location=\"attachment:{}:{}\"",
+        image.width, image.height, channels, sample_format, bounds, color_space, position, size
+    );
+    if let Some(compression) = compression {
+        let _ = write!(xml, " compression=\"{}\"", compression);
+    }
+    xml.push_str(">\n");
+
+    if let Some(pattern) = image.bayer {
+        let _ = writeln!(
+            xml,
+            "<ColorFilterArray pattern=\"{}\" width=\"2\" height=\"2\"/>",
+            pattern.as_str()
+        );
+    }
+
+    for (key, id, kind) in PROPERTIES {
+        if let Some(value) = header.get(key) {
+            xml.push_str(&property(id, kind, value));
+        }
+    }
+
+    for card in header.cards() {
+        let _ = writeln!(
+            xml,
+            "<FITSKeyword name=\"{}\" value=\"{}\" comment=\"{}\"/>",
+            escape(&card.key),
+            escape(&card.value.to_fits_string()),
+            escape(card.comment.as_deref().unwrap_or_default())
+        );
+    }
+    xml.push_str("</Image>\n");
+
+    xml.push_str("<Metadata>\n");
+    let created = HeaderValue::Str(crate::fits::date_obs(SystemTime::now()));
+    xml.push_str(&property("XISF:CreationTime", "TimePoint", &created));
+    let creator = HeaderValue::Str(format!("asi-rs {}", env!("CARGO_PKG_VERSION")));
+    xml.push_str(&property("XISF:CreatorApplication", "String", &creator));
+    xml.push_str("</Metadata>\n</xisf>");
+
+    xml
+}
+
+fn property(id: &str, kind: &str, value: &HeaderValue) -> String {
+    let value = match value {
+        HeaderValue::Logical(b) => b.to_string(),
+        HeaderValue::Int(i) => i.to_string(),
+        HeaderValue::Float(f) => f.to_string(),
+        HeaderValue::Str(s) => s.clone(),
+    };
+
+    match kind {
+        // Strings are stored as the content of the element
+        "String" => format!(
+            "<Property id=\"{}\" type=\"String\">{}</Property>\n",
+            id,
+            escape(&value)
+        ),
+        // The times of the FITS headers are in UTC
+        "TimePoint" => format!(
+            "<Property id=\"{}\" type=\"TimePoint\" value=\"{}Z\"/>\n",
+            id,
+            escape(&value)
+        ),
+        _ => format!(
+            "<Property id=\"{}\" type=\"{}\" value=\"{}\"/>\n",
+            id,
+            kind,
+            escape(&value)
+        ),
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    const PIXELS: [u16; 6] = [0, 1, 256, 4095, 40000, 65535];
+
+    fn data() -> Vec<u8> {
+        PIXELS.iter().flat_map(|p| p.to_le_bytes()).collect()
+    }
+
+    fn image(data: &[u8]) -> Image<'_> {
+        Image {
+            width: 3,
+            height: 2,
+            format: PixelFormat::Mono16,
+            bayer: None,
+            data,
+        }
+    }
+
+    /// Write `image` to a temporary file, return its XML header and the
+    /// attached block
+    fn write_read(header: &Header, image: &Image, compression: Compression) -> (String, Vec<u8>) {
+        let dir = std::env::temp_dir().join(format!("asi-rs-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("frame.xisf");
+        write(&path, header, image, compression).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(&bytes[..8], SIGNATURE);
+        let length = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+        let xml = String::from_utf8(bytes[16..16 + length].to_vec()).unwrap();
+
+        let location = xml.split("location=\"attachment:").nth(1).unwrap();
+        let mut location = location.split('"').next().unwrap().split(':');
+        let position: usize = location.next().unwrap().parse().unwrap();
+        let size: usize = location.next().unwrap().parse().unwrap();
+        assert_eq!(position % BLOCK_ALIGNMENT, 0);
+        assert_eq!(bytes.len(), position + size);
+
+        (xml, bytes[position..].to_vec())
+    }
+
+    #[test]
+    fn writes_uncompressed_frame() {
+        let data = data();
+        let mut header = Header::new();
+        header.set("EXPTIME", 30.0, "seconds");
+        header.set("OBJECT", "M 31 <Andromeda>", "");
+
+        let (xml, block) = write_read(&header, &image(&data), Compression::None);
+        assert!(xml.contains("geometry=\"3:2:1\" sampleFormat=\"UInt16\""));
+        assert!(!xml.contains("compression="));
+        assert!(xml
+            .contains("<Property id=\"Instrument:ExposureTime\" type=\"Float32\" value=\"30\"/>"));
+        assert!(xml.contains(
+            "<Property id=\"Observation:Object:Name\" type=\"String\">M 31 &lt;Andromeda&gt;</Property>"
+        ));
+        assert!(xml.contains("<FITSKeyword name=\"EXPTIME\" value=\"30.0\" comment=\"seconds\"/>"));
+        assert_eq!(block, data);
+    }
+
+    #[test]
+    fn writes_zlib_shuffled_frame() {
+        let data = data();
+        let (xml, block) = write_read(&Header::new(), &image(&data), Compression::Zlib);
+        assert!(xml.contains("compression=\"zlib+sh:12:2\""));
+
+        let mut shuffled = Vec::new();
+        ZlibDecoder::new(&block[..])
+            .read_to_end(&mut shuffled)
+            .unwrap();
+        assert_eq!(shuffled, shuffle(&data, 2));
+    }
+
+    #[test]
+    fn writes_lz4_frame() {
+        let data = data();
+        let (xml, block) = write_read(&Header::new(), &image(&data), Compression::Lz4);
+        assert!(xml.contains("compression=\"lz4+sh:12:2\""));
+        assert_eq!(
+            lz4_flex::block::decompress(&block, data.len()).unwrap(),
+            shuffle(&data, 2)
+        );
+    }
+
+    #[test]
+    fn shuffles_bytes_by_significance() {
+        assert_eq!(shuffle(&[1, 2, 3, 4, 5, 6], 2), [1, 3, 5, 2, 4, 6]);
+    }
+
+    #[test]
+    fn writes_colour_planes() {
+        let data = [1, 2, 3, 4, 5, 6];
+        let image = Image {
+            width: 2,
+            height: 1,
+            format: PixelFormat::Bgr24,
+            bayer: None,
+            data: &data,
+        };
+
+        let (xml, block) = write_read(&Header::new(), &image, Compression::None);
+        assert!(xml.contains("geometry=\"2:1:3\" sampleFormat=\"UInt8\""));
+        assert!(xml.contains("colorSpace=\"RGB\""));
+        assert_eq!(block, [3, 6, 2, 5, 1, 4]);
+    }
+}